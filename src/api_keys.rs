@@ -0,0 +1,581 @@
+use opentelemetry::KeyValue;
+use serde::Deserialize;
+use std::{
+    collections::{HashMap, HashSet},
+    convert::Infallible,
+    io::{Error, ErrorKind},
+    net::IpAddr,
+    sync::{Arc, Mutex, RwLock},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+use tracing::{error, info, warn};
+use warp::{Filter, Reply, http::StatusCode, reply};
+
+use crate::{
+    config::Config,
+    metrics::METRICS,
+    rate_limit::{self, BucketKey, IpNet, RateLimiter},
+};
+
+const MINUTE: Duration = Duration::from_secs(60);
+const DAY_SECS: u64 = 86_400;
+
+/// An API key and its quotas, as given in the keys file. Quotas left out are unlimited.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct ApiKey {
+    /// Name reported in logs and metrics instead of the key itself
+    pub name: String,
+    pub key: String,
+    #[serde(default = "enabled")]
+    pub enabled: bool,
+    pub requests_per_minute: Option<u32>,
+    /// Points looked up per UTC day
+    pub points_per_day: Option<u64>,
+    /// Points of a single batch request
+    pub max_batch_size: Option<usize>,
+}
+
+fn enabled() -> bool {
+    true
+}
+
+/// Usage of a key in the current minute and UTC day.
+#[derive(Debug)]
+struct Usage {
+    minute_start: Instant,
+    minute_requests: u32,
+    day: u64,
+    day_points: u64,
+}
+
+impl Default for Usage {
+    fn default() -> Self {
+        Self {
+            minute_start: Instant::now(),
+            minute_requests: 0,
+            day: utc_day(),
+            day_points: 0,
+        }
+    }
+}
+
+/// Reasons to turn a request away.
+#[derive(Debug, PartialEq)]
+pub enum AuthError {
+    /// No key or an unknown one
+    Unauthorized,
+    /// A known key that may not make this request
+    Forbidden(String),
+    /// A quota of the key is used up
+    TooManyRequests { message: String, retry_after: u64 },
+}
+
+impl AuthError {
+    fn outcome(&self) -> &'static str {
+        match self {
+            AuthError::Unauthorized => "unauthorized",
+            AuthError::Forbidden(_) => "forbidden",
+            AuthError::TooManyRequests { .. } => "quota_exceeded",
+        }
+    }
+}
+
+impl Reply for AuthError {
+    fn into_response(self) -> reply::Response {
+        match self {
+            AuthError::Unauthorized => reply::with_header(
+                reply::with_status("Invalid or missing API key", StatusCode::UNAUTHORIZED),
+                "www-authenticate",
+                "Bearer",
+            )
+            .into_response(),
+            AuthError::Forbidden(message) => {
+                reply::with_status(message, StatusCode::FORBIDDEN).into_response()
+            }
+            AuthError::TooManyRequests {
+                message,
+                retry_after,
+            } => reply::with_header(
+                reply::with_status(message, StatusCode::TOO_MANY_REQUESTS),
+                "retry-after",
+                retry_after.to_string(),
+            )
+            .into_response(),
+        }
+    }
+}
+
+/// API keys from the file at `API_KEYS_PATH`, reloaded when it changes. Without
+/// the file every request is allowed.
+#[derive(Default)]
+pub struct ApiKeys {
+    path: Option<String>,
+    contents: Mutex<Vec<u8>>, // Contents of the file the current keys were parsed from
+    keys: RwLock<HashMap<String, ApiKey>>,
+    usage: Mutex<HashMap<String, Usage>>,
+}
+
+impl ApiKeys {
+    pub fn from_config(config: &Config) -> Result<Self, Error> {
+        match &config.api_keys_path {
+            Some(path) => Self::from_file(path),
+            None => Ok(Self::default()),
+        }
+    }
+
+    fn from_file(path: &str) -> Result<Self, Error> {
+        let api_keys = Self {
+            path: Some(path.to_string()),
+            ..Self::default()
+        };
+        api_keys.reload()?;
+        Ok(api_keys)
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.path.is_some()
+    }
+
+    /// Reads the keys file again if it changed. Returns true if it did. The usage of
+    /// keys that are still present is kept.
+    pub fn reload(&self) -> Result<bool, Error> {
+        let Some(path) = &self.path else {
+            return Ok(false);
+        };
+        let contents = std::fs::read(path)
+            .map_err(|e| Error::new(e.kind(), format!("Failed to read '{}': {}", path, e)))?;
+        let mut loaded = self.contents.lock().unwrap_or_else(|e| e.into_inner());
+        if *loaded == contents {
+            return Ok(false);
+        }
+        let keys = parse_keys(&contents).map_err(|e| {
+            Error::new(e.kind(), format!("Invalid API keys file '{}': {}", path, e))
+        })?;
+        self.usage
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .retain(|key, _| keys.contains_key(key));
+        *self.keys.write().unwrap_or_else(|e| e.into_inner()) = keys;
+        *loaded = contents;
+        Ok(true)
+    }
+
+    /// Checks the keys file for changes every `interval`. An invalid file is logged
+    /// and the previous keys stay in use.
+    pub fn watch(self: &Arc<Self>, interval: Duration) {
+        if !self.is_enabled() {
+            return;
+        }
+        let api_keys = self.clone();
+        tokio::spawn(async move {
+            let mut ticks = tokio::time::interval(interval);
+            ticks.tick().await;
+            loop {
+                ticks.tick().await;
+                match api_keys.reload() {
+                    Ok(true) => info!(
+                        keys = api_keys
+                            .keys
+                            .read()
+                            .unwrap_or_else(|e| e.into_inner())
+                            .len(),
+                        "Reloaded API keys"
+                    ),
+                    Ok(false) => {}
+                    Err(e) => error!(error = %e, "Failed to reload API keys"),
+                }
+            }
+        });
+    }
+
+    /// Returns the name of a known key.
+    pub fn name(&self, key: Option<&str>) -> Option<String> {
+        let keys = self.keys.read().unwrap_or_else(|e| e.into_inner());
+        Some(keys.get(key?)?.name.clone())
+    }
+
+    /// Checks that the key of a request is known and enabled, without counting the
+    /// request against the key's quotas.
+    pub fn authenticate(&self, key: Option<&str>) -> Result<(), AuthError> {
+        if !self.is_enabled() {
+            return Ok(());
+        }
+        let keys = self.keys.read().unwrap_or_else(|e| e.into_inner());
+        let api_key = key.and_then(|key| keys.get(key));
+        let error = match api_key {
+            None => AuthError::Unauthorized,
+            Some(api_key) if !api_key.enabled => {
+                AuthError::Forbidden(String::from("API key is disabled"))
+            }
+            Some(_) => return Ok(()),
+        };
+        let mut labels = vec![KeyValue::new("outcome", error.outcome())];
+        if let Some(api_key) = api_key {
+            warn!(key = %api_key.name, outcome = error.outcome(), "API key request refused");
+            labels.push(KeyValue::new("key", api_key.name.clone()));
+        }
+        METRICS.api_key_requests.add(1, &labels);
+        Err(error)
+    }
+
+    /// Checks the key of a request looking up `points` points and counts it
+    /// against the key's quotas.
+    pub fn authorize(&self, key: Option<&str>, points: usize) -> Result<(), AuthError> {
+        if !self.is_enabled() {
+            return Ok(());
+        }
+        let keys = self.keys.read().unwrap_or_else(|e| e.into_inner());
+        let api_key = match key.and_then(|key| keys.get(key)) {
+            Some(api_key) => api_key,
+            None => {
+                METRICS
+                    .api_key_requests
+                    .add(1, &[KeyValue::new("outcome", "unauthorized")]);
+                return Err(AuthError::Unauthorized);
+            }
+        };
+        let name = KeyValue::new("key", api_key.name.clone());
+        let result = self.consume(api_key, points);
+        let outcome = match &result {
+            Ok(()) => {
+                METRICS
+                    .api_key_points
+                    .add(points as u64, std::slice::from_ref(&name));
+                "ok"
+            }
+            Err(e) => {
+                warn!(key = %api_key.name, outcome = e.outcome(), "API key request refused");
+                e.outcome()
+            }
+        };
+        METRICS
+            .api_key_requests
+            .add(1, &[name, KeyValue::new("outcome", outcome)]);
+        result
+    }
+
+    fn consume(&self, api_key: &ApiKey, points: usize) -> Result<(), AuthError> {
+        if !api_key.enabled {
+            return Err(AuthError::Forbidden(String::from("API key is disabled")));
+        }
+        if let Some(max) = api_key.max_batch_size
+            && points > max
+        {
+            return Err(AuthError::Forbidden(format!(
+                "Batch of {} points exceeds the limit of {} points of the API key",
+                points, max
+            )));
+        }
+
+        let mut usage = self.usage.lock().unwrap_or_else(|e| e.into_inner());
+        let usage = usage.entry(api_key.key.clone()).or_default();
+        let now = Instant::now();
+        if now.duration_since(usage.minute_start) >= MINUTE {
+            usage.minute_start = now;
+            usage.minute_requests = 0;
+        }
+        let unix_now = unix_secs();
+        let day = unix_now / DAY_SECS;
+        if usage.day != day {
+            usage.day = day;
+            usage.day_points = 0;
+        }
+
+        if let Some(limit) = api_key.requests_per_minute
+            && usage.minute_requests >= limit
+        {
+            let reset = MINUTE.saturating_sub(now.duration_since(usage.minute_start));
+            return Err(AuthError::TooManyRequests {
+                message: format!("Quota of {} requests per minute exceeded", limit),
+                retry_after: reset.as_secs_f64().ceil() as u64,
+            });
+        }
+        if let Some(limit) = api_key.points_per_day
+            && usage.day_points + points as u64 > limit
+        {
+            return Err(AuthError::TooManyRequests {
+                message: format!(
+                    "Quota of {} points per day exceeded, {} points left",
+                    limit,
+                    limit.saturating_sub(usage.day_points)
+                ),
+                retry_after: ((day + 1) * DAY_SECS).saturating_sub(unix_now).max(1),
+            });
+        }
+
+        usage.minute_requests += 1;
+        usage.day_points += points as u64;
+        Ok(())
+    }
+}
+
+fn parse_keys(contents: &[u8]) -> Result<HashMap<String, ApiKey>, Error> {
+    let keys: Vec<ApiKey> = serde_json::from_slice(contents)
+        .map_err(|e| Error::new(ErrorKind::InvalidData, e.to_string()))?;
+    let mut names = HashSet::new();
+    let mut by_key = HashMap::new();
+    for api_key in keys {
+        if api_key.key.is_empty() {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("API key '{}' is empty", api_key.name),
+            ));
+        }
+        if !names.insert(api_key.name.clone()) {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("API key name '{}' is used twice", api_key.name),
+            ));
+        }
+        let name = api_key.name.clone();
+        if by_key.insert(api_key.key.clone(), api_key).is_some() {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("API key '{}' duplicates another key", name),
+            ));
+        }
+    }
+    Ok(by_key)
+}
+
+fn unix_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+fn utc_day() -> u64 {
+    unix_secs() / DAY_SECS
+}
+
+#[derive(Deserialize)]
+struct ApiKeyQuery {
+    api_key: Option<String>,
+}
+
+/// The sender of a request, as far as quotas and rate limits are concerned.
+pub struct Caller {
+    api_key: Option<String>,
+    client_ip: Option<IpAddr>,
+    api_keys: Arc<ApiKeys>,
+    rate_limiter: Arc<RateLimiter>,
+}
+
+impl Caller {
+    /// Rejects unknown and disabled API keys before the request is validated, see
+    /// [`ApiKeys::authenticate`].
+    pub fn authenticate(&self) -> Result<(), AuthError> {
+        self.api_keys.authenticate(self.api_key.as_deref())
+    }
+
+    /// Charges the caller for a request looking up `points` points. Callers with a
+    /// known API key are rate limited by their key, all others by their address.
+    /// Requests refused by the API key quotas take no tokens.
+    pub fn authorize(&self, points: usize) -> Result<(), AuthError> {
+        self.api_keys.authorize(self.api_key.as_deref(), points)?;
+        let bucket = match self.api_keys.name(self.api_key.as_deref()) {
+            Some(name) => Some(BucketKey::ApiKey(name)),
+            None => self.client_ip.map(BucketKey::Ip),
+        };
+        if let Some(bucket) = bucket {
+            self.rate_limiter.check(bucket, points)?;
+        }
+        Ok(())
+    }
+}
+
+/// Extracts the caller of a request. The API key is given as bearer token,
+/// `X-API-Key` header or `api_key` query parameter.
+pub fn caller(
+    api_keys: Arc<ApiKeys>,
+    rate_limiter: Arc<RateLimiter>,
+    trusted_proxies: Vec<IpNet>,
+) -> impl Filter<Extract = (Caller,), Error = Infallible> + Clone {
+    api_key()
+        .and(rate_limit::client(trusted_proxies))
+        .map(move |api_key, client_ip| Caller {
+            api_key,
+            client_ip,
+            api_keys: api_keys.clone(),
+            rate_limiter: rate_limiter.clone(),
+        })
+}
+
+fn api_key() -> impl Filter<Extract = (Option<String>,), Error = Infallible> + Clone {
+    warp::header::optional::<String>("authorization")
+        .and(warp::header::optional::<String>("x-api-key"))
+        .and(
+            warp::query::<ApiKeyQuery>()
+                .map(|query: ApiKeyQuery| query.api_key)
+                .or(warp::any().map(|| None))
+                .unify(),
+        )
+        .map(
+            |authorization: Option<String>, header: Option<String>, query: Option<String>| {
+                authorization
+                    .and_then(|value| value.strip_prefix("Bearer ").map(str::to_string))
+                    .or(header)
+                    .or(query)
+            },
+        )
+        .or(warp::any().map(|| None))
+        .unify()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempDir;
+
+    const KEYS: &str = r#"[
+        {"name": "app", "key": "k-app", "requests_per_minute": 2, "max_batch_size": 100},
+        {"name": "partner", "key": "k-partner", "points_per_day": 150},
+        {"name": "old", "key": "k-old", "enabled": false}
+    ]"#;
+
+    fn api_keys(name: &str, contents: &str) -> (ApiKeys, TempDir) {
+        let dir = TempDir::new(&format!("api-keys-{}", name));
+        let path = dir.join("keys.json");
+        std::fs::write(&path, contents).unwrap();
+        (ApiKeys::from_file(path.to_str().unwrap()).unwrap(), dir)
+    }
+
+    #[test]
+    fn test_quotas() {
+        let (api_keys, _dir) = api_keys("quotas", KEYS);
+
+        assert_eq!(api_keys.authorize(None, 1), Err(AuthError::Unauthorized));
+        assert_eq!(
+            api_keys.authorize(Some("k-unknown"), 1),
+            Err(AuthError::Unauthorized)
+        );
+        assert!(matches!(
+            api_keys.authorize(Some("k-old"), 1),
+            Err(AuthError::Forbidden(_))
+        ));
+        assert!(matches!(
+            api_keys.authorize(Some("k-app"), 101),
+            Err(AuthError::Forbidden(_))
+        ));
+
+        assert_eq!(api_keys.authorize(Some("k-app"), 100), Ok(()));
+        assert_eq!(api_keys.authorize(Some("k-app"), 1), Ok(()));
+        match api_keys.authorize(Some("k-app"), 1) {
+            Err(AuthError::TooManyRequests { retry_after, .. }) => {
+                assert!((59..=60).contains(&retry_after))
+            }
+            other => panic!("Expected the minute quota to be used up, got {:?}", other),
+        }
+
+        assert_eq!(api_keys.authorize(Some("k-partner"), 100), Ok(()));
+        assert!(matches!(
+            api_keys.authorize(Some("k-partner"), 51),
+            Err(AuthError::TooManyRequests { .. })
+        ));
+        assert_eq!(api_keys.authorize(Some("k-partner"), 50), Ok(()));
+    }
+
+    #[test]
+    fn test_authenticate_does_not_consume_quotas() {
+        let (api_keys, _dir) = api_keys("authenticate", KEYS);
+
+        assert_eq!(api_keys.authenticate(None), Err(AuthError::Unauthorized));
+        assert!(matches!(
+            api_keys.authenticate(Some("k-old")),
+            Err(AuthError::Forbidden(_))
+        ));
+        for _ in 0..3 {
+            assert_eq!(api_keys.authenticate(Some("k-app")), Ok(()));
+        }
+        assert_eq!(api_keys.authorize(Some("k-app"), 1), Ok(()));
+        assert_eq!(api_keys.authorize(Some("k-app"), 1), Ok(()));
+        assert_eq!(ApiKeys::default().authenticate(None), Ok(()));
+    }
+
+    #[test]
+    fn test_refused_requests_take_no_tokens() {
+        let (api_keys, _dir) = api_keys("tokens", KEYS);
+        let api_keys = Arc::new(api_keys);
+        let rate_limiter = Arc::new(RateLimiter::new(Some(0.001), 100.0));
+        let caller = |api_key: &str| Caller {
+            api_key: Some(api_key.to_string()),
+            client_ip: None,
+            api_keys: api_keys.clone(),
+            rate_limiter: rate_limiter.clone(),
+        };
+
+        let app = caller("k-app");
+        assert!(matches!(app.authorize(101), Err(AuthError::Forbidden(_))));
+        assert_eq!(app.authorize(100), Ok(()));
+
+        let partner = caller("k-partner");
+        assert!(matches!(
+            partner.authorize(151),
+            Err(AuthError::TooManyRequests { .. })
+        ));
+        assert_eq!(partner.authorize(100), Ok(()));
+        assert!(matches!(
+            partner.authorize(1),
+            Err(AuthError::TooManyRequests { .. })
+        ));
+    }
+
+    #[test]
+    fn test_reload_keeps_usage_and_previous_keys() {
+        let (api_keys, dir) = api_keys("reload", KEYS);
+        let path = dir.join("keys.json");
+        assert_eq!(api_keys.authorize(Some("k-app"), 1), Ok(()));
+        assert!(!api_keys.reload().unwrap());
+
+        std::fs::write(
+            &path,
+            r#"[{"name": "app", "key": "k-app", "requests_per_minute": 2}"#,
+        )
+        .unwrap();
+        assert!(api_keys.reload().is_err());
+        assert_eq!(api_keys.authorize(Some("k-partner"), 1), Ok(()));
+
+        std::fs::write(
+            &path,
+            r#"[{"name": "app", "key": "k-app", "requests_per_minute": 2}]"#,
+        )
+        .unwrap();
+        assert!(api_keys.reload().unwrap());
+        assert_eq!(api_keys.authorize(Some("k-app"), 1), Ok(()));
+        assert!(api_keys.authorize(Some("k-app"), 1).is_err());
+        assert_eq!(
+            api_keys.authorize(Some("k-partner"), 1),
+            Err(AuthError::Unauthorized)
+        );
+
+        assert!(parse_keys(br#"[{"name": "a", "key": "k"}, {"name": "b", "key": "k"}]"#).is_err());
+        assert!(parse_keys(br#"[{"name": "a", "key": "k", "quota": 1}]"#).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_api_key_sources() {
+        let filter = api_key();
+        let extract = |request: warp::test::RequestBuilder| {
+            let filter = filter.clone();
+            async move { request.filter(&filter).await.unwrap() }
+        };
+        assert_eq!(
+            extract(warp::test::request().header("authorization", "Bearer k-app")).await,
+            Some(String::from("k-app"))
+        );
+        assert_eq!(
+            extract(warp::test::request().header("x-api-key", "k-app")).await,
+            Some(String::from("k-app"))
+        );
+        assert_eq!(
+            extract(warp::test::request().path("/api?lat=1&lng=2&api_key=k-app")).await,
+            Some(String::from("k-app"))
+        );
+        assert_eq!(
+            extract(warp::test::request().path("/api?lat=1")).await,
+            None
+        );
+    }
+}
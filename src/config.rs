@@ -1,15 +1,28 @@
 // src/config.rs
 
+use crate::rate_limit::IpNet;
+use crate::tileset::{CacheSize, MissingTilePolicy, ResilienceOptions, TileFormat};
 use byte_unit::Byte;
 use dotenvy::dotenv;
-use once_cell::sync::Lazy;
-use std::{env, net::Ipv4Addr};
+use std::{
+    cell::RefCell,
+    collections::{HashMap, HashSet},
+    env,
+    fmt::Display,
+    fs,
+    io::{self, Error, ErrorKind},
+    net::Ipv4Addr,
+    path::Path,
+    str::FromStr,
+    time::Duration,
+};
 
 // Define the Config struct
 #[derive(Clone, Debug)]
 pub struct Config {
-    pub cache_size: u64,
+    pub cache_size: CacheSize,
     pub tile_set_path: String,
+    pub path_template: Option<String>,
     pub max_post_size: Byte,
     pub max_parallel_processing: usize,
     pub max_tokio_threads: Option<usize>,
@@ -21,42 +34,584 @@ pub struct Config {
     pub s3_access_key_id: Option<String>,
     pub s3_secret_access_key: Option<String>,
     pub s3_region: Option<String>,
+    pub datasets: Vec<DatasetConfig>,
+    pub default_dataset: String,
+    pub geoid_path: Option<String>,
+    pub bathymetry_dataset: Option<String>,
+    pub disk_cache_path: Option<String>,
+    pub disk_cache_size: Byte,
+    pub disk_cache_decompressed: bool,
+    pub warmup_bboxes: Option<String>,
+    pub warmup_geojson: Option<String>,
+    pub warmup_tiles: Option<String>,
+    pub warmup_datasets: Vec<String>,
+    pub warmup_concurrency: usize,
+    pub admin_token: Option<String>,
+    pub resilience: ResilienceOptions,
+    pub missing_tile_cache_size: u64,
+    pub missing_tile_cache_ttl: Duration,
+    pub access_log: bool,
+    pub readiness_probe_timeout: Duration,
+    pub shutdown_delay: Duration,
+    pub shutdown_drain_timeout: Duration,
+    pub tls_cert_path: Option<String>,
+    pub tls_key_path: Option<String>,
+    pub tls_client_ca_path: Option<String>,
+    pub tls_client_auth_optional: bool,
+    pub tls_reload_interval: Duration,
+    pub api_keys_path: Option<String>,
+    pub api_keys_reload_interval: Duration,
+    pub rate_limit_per_second: Option<f64>,
+    pub rate_limit_burst: Option<f64>,
+    pub trusted_proxies: Vec<IpNet>,
+    pub max_queue_time: Option<Duration>,
 }
 
-// Initialize dotenv and config only once
-pub static CONFIG: Lazy<Config> = Lazy::new(|| {
-    dotenv().ok(); // Loads .env (only the first time it's called)
+// A named, independently selectable dataset made of one or more layers
+#[derive(Clone, Debug)]
+pub struct DatasetConfig {
+    pub name: String,
+    pub layers: Vec<LayerConfig>,
+    pub missing_tile_policy: MissingTilePolicy,
+    pub probes: Vec<(f64, f64)>, // Known-good coordinates checked by the readiness probe
+}
+
+// Settings of a single tile source in the ordered layer list
+#[derive(Clone, Debug)]
+pub struct LayerConfig {
+    pub name: String,
+    pub tile_set_path: String,
+    pub cache_size: CacheSize,
+    pub gzip: bool,
+    pub path_template: Option<String>,
+    pub format: TileFormat,
+    pub zoom: u8,
+    pub mmap: bool,
+    pub range_reads: bool,
+    pub s3_endpoint: Option<String>,
+    pub s3_bucket: Option<String>,
+    pub s3_access_key_id: Option<String>,
+    pub s3_secret_access_key: Option<String>,
+    pub s3_region: Option<String>,
+}
+
+impl Config {
+    /// Loads the configuration from the environment, layered over the config file at
+    /// `path` or `CONFIG_FILE`. Fails with all invalid and unknown settings at once.
+    pub fn load(path: Option<&str>) -> io::Result<Config> {
+        dotenv().ok(); // Loads .env into the environment
+
+        let env: HashMap<String, String> = env::vars().collect();
+        let path = path
+            .map(str::to_string)
+            .or_else(|| non_empty(env.get("CONFIG_FILE")));
+        let file = match &path {
+            Some(path) => read_config_file(Path::new(path))?,
+            None => HashMap::new(),
+        };
+        Config::from_settings(Settings::new(env, file, path))
+    }
+
+    /// Builds a configuration from the given variables only, ignoring the environment
+    #[cfg(test)]
+    pub fn from_vars(vars: &[(&str, &str)]) -> io::Result<Config> {
+        let env = vars
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect();
+        Config::from_settings(Settings::new(env, HashMap::new(), None))
+    }
+
+    fn from_settings(settings: Settings) -> io::Result<Config> {
+        let settings = &settings;
+        let aws_access_key_id = settings.get("AWS_ACCESS_KEY_ID");
+        let aws_secret_access_key = settings.get("AWS_SECRET_ACCESS_KEY");
+        let aws_region = settings.get("AWS_REGION");
+
+        let mut config = Config {
+            cache_size: settings
+                .parse::<CacheSize>("TILE_SET_CACHE")
+                .unwrap_or(CacheSize::Tiles(128)),
+            tile_set_path: settings.get("TILE_SET_PATH").unwrap_or_else(|| {
+                String::from(
+                    env::current_dir()
+                        .expect("Failed to get current_dir")
+                        .to_str()
+                        .expect("Failed to convert path to string"),
+                )
+            }),
+            path_template: settings.get("TILE_SET_PATH_TEMPLATE"),
+            max_post_size: settings
+                .parse_byte("MAX_POST_SIZE")
+                .unwrap_or_else(|| Byte::parse_str("500kb", true).unwrap()),
+            max_parallel_processing: settings
+                .parse_positive::<usize>("MAX_PARALLEL_PROCESSING")
+                .unwrap_or(500),
+            max_tokio_threads: settings.parse_positive::<usize>("MAX_THREADS"),
+            max_concurrent_handlers: settings
+                .parse_positive::<usize>("MAX_CONCURRENT_HANDLERS")
+                .unwrap_or(1000),
+            port: settings.parse::<u16>("PORT").unwrap_or(3000),
+            bind: settings
+                .parse::<Ipv4Addr>("BIND")
+                .unwrap_or(Ipv4Addr::new(0, 0, 0, 0)),
+            s3_endpoint: settings.get("S3_ENDPOINT"),
+            s3_bucket: settings.get("S3_BUCKET"),
+            s3_access_key_id: settings.get("S3_ACCESS_KEY_ID").or(aws_access_key_id),
+            s3_secret_access_key: settings
+                .get("S3_SECRET_ACCESS_KEY")
+                .or(aws_secret_access_key),
+            s3_region: settings.get("S3_REGION").or(aws_region),
+            datasets: Vec::new(),
+            default_dataset: String::new(),
+            geoid_path: settings.get("GEOID_PATH"),
+            bathymetry_dataset: settings.get("BATHYMETRY_DATASET"),
+            disk_cache_path: settings.get("DISK_CACHE_PATH"),
+            disk_cache_size: settings
+                .parse_byte("DISK_CACHE_SIZE")
+                .unwrap_or_else(|| Byte::parse_str("10gb", true).unwrap()),
+            disk_cache_decompressed: settings
+                .parse_bool("DISK_CACHE_DECOMPRESSED")
+                .unwrap_or(false),
+            warmup_bboxes: settings.get("WARMUP_BBOXES"),
+            warmup_geojson: settings.get("WARMUP_GEOJSON"),
+            warmup_tiles: settings.get("WARMUP_TILES"),
+            warmup_datasets: Vec::new(),
+            warmup_concurrency: settings
+                .parse_positive::<usize>("WARMUP_CONCURRENCY")
+                .unwrap_or(16),
+            admin_token: settings.get("ADMIN_TOKEN"),
+            resilience: get_resilience(settings),
+            missing_tile_cache_size: settings
+                .parse::<u64>("MISSING_TILE_CACHE_SIZE")
+                .unwrap_or(100_000),
+            missing_tile_cache_ttl: settings
+                .parse::<u64>("MISSING_TILE_CACHE_TTL_SECS")
+                .map_or(Duration::from_secs(3600), Duration::from_secs),
+            access_log: settings.parse_bool("ACCESS_LOG").unwrap_or(true),
+            readiness_probe_timeout: settings
+                .parse::<u64>("READINESS_PROBE_TIMEOUT_MS")
+                .map_or(Duration::from_secs(2), Duration::from_millis),
+            shutdown_delay: settings
+                .parse::<u64>("SHUTDOWN_DELAY_SECS")
+                .map_or(Duration::from_secs(5), Duration::from_secs),
+            shutdown_drain_timeout: settings
+                .parse::<u64>("SHUTDOWN_DRAIN_TIMEOUT_SECS")
+                .map_or(Duration::from_secs(30), Duration::from_secs),
+            tls_cert_path: settings.get("TLS_CERT_PATH"),
+            tls_key_path: settings.get("TLS_KEY_PATH"),
+            tls_client_ca_path: settings.get("TLS_CLIENT_CA_PATH"),
+            tls_client_auth_optional: settings
+                .parse_bool("TLS_CLIENT_AUTH_OPTIONAL")
+                .unwrap_or(false),
+            tls_reload_interval: settings
+                .parse::<u64>("TLS_RELOAD_INTERVAL_SECS")
+                .map_or(Duration::from_secs(30), Duration::from_secs),
+            api_keys_path: settings.get("API_KEYS_PATH"),
+            api_keys_reload_interval: settings
+                .parse::<u64>("API_KEYS_RELOAD_INTERVAL_SECS")
+                .map_or(Duration::from_secs(30), Duration::from_secs),
+            rate_limit_per_second: settings.parse_positive::<f64>("RATE_LIMIT_POINTS_PER_SECOND"),
+            rate_limit_burst: settings.parse_positive::<f64>("RATE_LIMIT_BURST"),
+            trusted_proxies: settings
+                .parse_with("TRUSTED_PROXIES", |proxies| {
+                    split_list(proxies).map(IpNet::from_str).collect()
+                })
+                .unwrap_or_default(),
+            max_queue_time: match settings.parse::<u64>("MAX_QUEUE_TIME_MS") {
+                Some(0) => None,
+                millis => Some(Duration::from_millis(millis.unwrap_or(10_000))),
+            },
+        };
+        config.datasets = get_datasets(settings, &config);
+        for layer in config.datasets.iter().flat_map(|dataset| &dataset.layers) {
+            if let Err(e) = get_uri_from_config(layer.clone()) {
+                settings.error(format!("Layer '{}': {}", layer.name, e));
+            }
+        }
+        config.default_dataset = settings
+            .get("DEFAULT_DATASET")
+            .or_else(|| config.datasets.first().map(|dataset| dataset.name.clone()))
+            .unwrap_or_default();
+        config.warmup_datasets = match settings.get("WARMUP_DATASETS") {
+            Some(names) => split_list(&names).map(String::from).collect(),
+            None => vec![config.default_dataset.clone()],
+        };
+        settings.check()?;
+        Ok(config)
+    }
+
+    /// Returns a copy with the secrets replaced, safe to print or log.
+    pub fn redacted(&self) -> Config {
+        let redact = |secret: &Option<String>| secret.as_ref().map(|_| String::from("<redacted>"));
+        let mut config = self.clone();
+        config.s3_secret_access_key = redact(&self.s3_secret_access_key);
+        config.admin_token = redact(&self.admin_token);
+        for layer in config
+            .datasets
+            .iter_mut()
+            .flat_map(|dataset| dataset.layers.iter_mut())
+        {
+            layer.s3_secret_access_key = redact(&layer.s3_secret_access_key);
+        }
+        config
+    }
+
+    /// Returns the effective settings by environment variable name, including the
+    /// defaults, in a form `load` accepts again. Unset optional settings are left out.
+    pub fn vars(&self) -> Vec<(String, String)> {
+        let mut vars = Vars::default();
+        vars.set("TILE_SET_CACHE", cache_size(self.cache_size));
+        vars.set("TILE_SET_PATH", &self.tile_set_path);
+        vars.set_some("TILE_SET_PATH_TEMPLATE", &self.path_template);
+        vars.set("MAX_POST_SIZE", byte_size(self.max_post_size));
+        vars.set("MAX_PARALLEL_PROCESSING", self.max_parallel_processing);
+        vars.set_some("MAX_THREADS", &self.max_tokio_threads);
+        vars.set("MAX_CONCURRENT_HANDLERS", self.max_concurrent_handlers);
+        vars.set("PORT", self.port);
+        vars.set("BIND", self.bind);
+        vars.set_some("S3_ENDPOINT", &self.s3_endpoint);
+        vars.set_some("S3_BUCKET", &self.s3_bucket);
+        vars.set_some("S3_ACCESS_KEY_ID", &self.s3_access_key_id);
+        vars.set_some("S3_SECRET_ACCESS_KEY", &self.s3_secret_access_key);
+        vars.set_some("S3_REGION", &self.s3_region);
+        vars.set_list(
+            "DATASETS",
+            self.datasets.iter().map(|dataset| &dataset.name),
+        );
+        vars.set("DEFAULT_DATASET", &self.default_dataset);
+        vars.set_some("GEOID_PATH", &self.geoid_path);
+        vars.set_some("BATHYMETRY_DATASET", &self.bathymetry_dataset);
+        vars.set_some("DISK_CACHE_PATH", &self.disk_cache_path);
+        vars.set("DISK_CACHE_SIZE", byte_size(self.disk_cache_size));
+        vars.set("DISK_CACHE_DECOMPRESSED", self.disk_cache_decompressed);
+        vars.set_some("WARMUP_BBOXES", &self.warmup_bboxes);
+        vars.set_some("WARMUP_GEOJSON", &self.warmup_geojson);
+        vars.set_some("WARMUP_TILES", &self.warmup_tiles);
+        vars.set_list("WARMUP_DATASETS", &self.warmup_datasets);
+        vars.set("WARMUP_CONCURRENCY", self.warmup_concurrency);
+        vars.set_some("ADMIN_TOKEN", &self.admin_token);
+
+        let resilience = &self.resilience;
+        let millis = |duration: Duration| duration.as_millis();
+        vars.set(
+            "BACKEND_CONNECT_TIMEOUT_MS",
+            millis(resilience.connect_timeout),
+        );
+        vars.set("BACKEND_READ_TIMEOUT_MS", millis(resilience.read_timeout));
+        vars.set("BACKEND_MAX_RETRIES", resilience.max_retries);
+        vars.set(
+            "BACKEND_RETRY_BASE_DELAY_MS",
+            millis(resilience.retry_base_delay),
+        );
+        vars.set(
+            "BACKEND_RETRY_MAX_DELAY_MS",
+            millis(resilience.retry_max_delay),
+        );
+        vars.set("BACKEND_BREAKER_THRESHOLD", resilience.breaker_threshold);
+        vars.set(
+            "BACKEND_BREAKER_COOLDOWN_MS",
+            millis(resilience.breaker_cooldown),
+        );
+
+        vars.set("MISSING_TILE_CACHE_SIZE", self.missing_tile_cache_size);
+        vars.set(
+            "MISSING_TILE_CACHE_TTL_SECS",
+            self.missing_tile_cache_ttl.as_secs(),
+        );
+        vars.set("ACCESS_LOG", self.access_log);
+        vars.set(
+            "READINESS_PROBE_TIMEOUT_MS",
+            millis(self.readiness_probe_timeout),
+        );
+        vars.set("SHUTDOWN_DELAY_SECS", self.shutdown_delay.as_secs());
+        vars.set(
+            "SHUTDOWN_DRAIN_TIMEOUT_SECS",
+            self.shutdown_drain_timeout.as_secs(),
+        );
+        vars.set_some("TLS_CERT_PATH", &self.tls_cert_path);
+        vars.set_some("TLS_KEY_PATH", &self.tls_key_path);
+        vars.set_some("TLS_CLIENT_CA_PATH", &self.tls_client_ca_path);
+        vars.set("TLS_CLIENT_AUTH_OPTIONAL", self.tls_client_auth_optional);
+        vars.set(
+            "TLS_RELOAD_INTERVAL_SECS",
+            self.tls_reload_interval.as_secs(),
+        );
+        vars.set_some("API_KEYS_PATH", &self.api_keys_path);
+        vars.set(
+            "API_KEYS_RELOAD_INTERVAL_SECS",
+            self.api_keys_reload_interval.as_secs(),
+        );
+        vars.set_some("RATE_LIMIT_POINTS_PER_SECOND", &self.rate_limit_per_second);
+        vars.set_some("RATE_LIMIT_BURST", &self.rate_limit_burst);
+        if !self.trusted_proxies.is_empty() {
+            vars.set_list("TRUSTED_PROXIES", &self.trusted_proxies);
+        }
+        vars.set("MAX_QUEUE_TIME_MS", self.max_queue_time.map_or(0, millis));
+
+        let mut layers = HashSet::new();
+        for dataset in &self.datasets {
+            let prefix = format!("DATASET_{}", env_name(&dataset.name));
+            let key = |key: &str| format!("{}_{}", prefix, key);
+            vars.set_list(
+                &key("LAYERS"),
+                dataset.layers.iter().map(|layer| &layer.name),
+            );
+            vars.set(
+                &key("MISSING_TILE_POLICY"),
+                format!("{:?}", dataset.missing_tile_policy).to_lowercase(),
+            );
+            if !dataset.probes.is_empty() {
+                let probes = dataset
+                    .probes
+                    .iter()
+                    .map(|(lat, lng)| format!("{},{}", lat, lng))
+                    .collect::<Vec<_>>();
+                vars.set(&key("PROBES"), probes.join(";"));
+            }
+            // Layers shared by several datasets are the same layer
+            for layer in &dataset.layers {
+                if layers.insert(&layer.name) {
+                    vars.set_layer(layer);
+                }
+            }
+        }
+        vars.0
+    }
+}
+
+/// Settings by environment variable name, in the order they were set.
+#[derive(Default)]
+struct Vars(Vec<(String, String)>);
 
-    Config {
-        cache_size: parse_env_var::<u64>("TILE_SET_CACHE").unwrap_or(128),
-        tile_set_path: get_non_empty_env_var("TILE_SET_PATH").unwrap_or_else(|| {
-            String::from(
-                env::current_dir()
-                    .expect("Failed to get current_dir")
-                    .to_str()
-                    .expect("Failed to convert path to string"),
-            )
+impl Vars {
+    fn set(&mut self, key: &str, value: impl ToString) {
+        self.0.push((key.to_string(), value.to_string()));
+    }
+
+    fn set_some(&mut self, key: &str, value: &Option<impl ToString>) {
+        if let Some(value) = value {
+            self.set(key, value.to_string());
+        }
+    }
+
+    fn set_list<T: ToString>(&mut self, key: &str, values: impl IntoIterator<Item = T>) {
+        let values = values
+            .into_iter()
+            .map(|value| value.to_string())
+            .collect::<Vec<_>>();
+        self.set(key, values.join(","));
+    }
+
+    fn set_layer(&mut self, layer: &LayerConfig) {
+        let prefix = format!("LAYER_{}", env_name(&layer.name));
+        let key = |key: &str| format!("{}_{}", prefix, key);
+        self.set(&key("PATH"), &layer.tile_set_path);
+        self.set(&key("CACHE"), cache_size(layer.cache_size));
+        self.set(&key("GZIP"), layer.gzip);
+        self.set_some(&key("PATH_TEMPLATE"), &layer.path_template);
+        self.set(&key("FORMAT"), layer.format);
+        self.set(&key("ZOOM"), layer.zoom);
+        self.set(&key("MMAP"), layer.mmap);
+        self.set(&key("RANGE_READS"), layer.range_reads);
+        self.set_some(&key("S3_ENDPOINT"), &layer.s3_endpoint);
+        self.set_some(&key("S3_BUCKET"), &layer.s3_bucket);
+        self.set_some(&key("S3_ACCESS_KEY_ID"), &layer.s3_access_key_id);
+        self.set_some(&key("S3_SECRET_ACCESS_KEY"), &layer.s3_secret_access_key);
+        self.set_some(&key("S3_REGION"), &layer.s3_region);
+    }
+}
+
+/// Formats a cache size so that it parses again: tile counts as plain numbers,
+/// byte budgets with a unit.
+fn cache_size(cache_size: CacheSize) -> String {
+    match cache_size {
+        CacheSize::Tiles(tiles) => tiles.to_string(),
+        CacheSize::Bytes(bytes) => format!("{}B", bytes),
+    }
+}
+
+fn byte_size(size: Byte) -> String {
+    format!("{}B", size.as_u64())
+}
+
+/// Builds the named datasets from `DATASETS`. Each dataset is an ordered list of layers
+/// taken from `DATASET_<NAME>_LAYERS`, or a single layer with the dataset's name.
+/// Without `DATASETS` a single "default" dataset is built from `TILE_SET_LAYERS`.
+fn get_datasets(settings: &Settings, config: &Config) -> Vec<DatasetConfig> {
+    let missing_tile_policy = settings
+        .parse::<MissingTilePolicy>("MISSING_TILE_POLICY")
+        .unwrap_or(MissingTilePolicy::Error);
+    let probes = settings
+        .parse_with("READINESS_PROBES", parse_probes)
+        .unwrap_or_default();
+
+    let Some(names) = settings.get("DATASETS") else {
+        return vec![DatasetConfig {
+            name: String::from("default"),
+            layers: get_layers(settings, config),
+            missing_tile_policy,
+            probes,
+        }];
+    };
+
+    split_list(&names)
+        .map(|name| {
+            let prefix = format!("DATASET_{}", env_name(name));
+            let layers = settings
+                .get(&format!("{}_LAYERS", prefix))
+                .unwrap_or_else(|| name.to_string());
+            DatasetConfig {
+                name: name.to_string(),
+                layers: split_list(&layers)
+                    .map(|layer| get_layer(settings, config, layer))
+                    .collect(),
+                missing_tile_policy: settings
+                    .parse::<MissingTilePolicy>(&format!("{}_MISSING_TILE_POLICY", prefix))
+                    .unwrap_or(missing_tile_policy),
+                probes: settings
+                    .parse_with(&format!("{}_PROBES", prefix), parse_probes)
+                    .unwrap_or_else(|| probes.clone()),
+            }
+        })
+        .collect()
+}
+
+/// Builds the ordered layer list from `TILE_SET_LAYERS`.
+/// Without `TILE_SET_LAYERS` a single layer is built from the global settings.
+fn get_layers(settings: &Settings, config: &Config) -> Vec<LayerConfig> {
+    let Some(names) = settings.get("TILE_SET_LAYERS") else {
+        return vec![LayerConfig {
+            name: String::from("default"),
+            tile_set_path: config.tile_set_path.clone(),
+            cache_size: config.cache_size,
+            gzip: settings.parse_bool("TILE_SET_GZIP").unwrap_or(true),
+            path_template: config.path_template.clone(),
+            format: settings
+                .parse::<TileFormat>("TILE_SET_FORMAT")
+                .unwrap_or(TileFormat::Hgt),
+            zoom: settings.parse::<u8>("TILE_SET_ZOOM").unwrap_or(12),
+            mmap: settings.parse_bool("TILE_SET_MMAP").unwrap_or(false),
+            range_reads: settings.parse_bool("TILE_SET_RANGE_READS").unwrap_or(false),
+            s3_endpoint: config.s3_endpoint.clone(),
+            s3_bucket: config.s3_bucket.clone(),
+            s3_access_key_id: config.s3_access_key_id.clone(),
+            s3_secret_access_key: config.s3_secret_access_key.clone(),
+            s3_region: config.s3_region.clone(),
+        }];
+    };
+
+    split_list(&names)
+        .map(|name| get_layer(settings, config, name))
+        .collect()
+}
+
+/// Reads the settings of a named layer from `LAYER_<NAME>_*`,
+/// falling back to the global values.
+fn get_layer(settings: &Settings, config: &Config, name: &str) -> LayerConfig {
+    let prefix = format!("LAYER_{}", env_name(name));
+    let key = |key: &str| format!("{}_{}", prefix, key);
+
+    LayerConfig {
+        name: name.to_string(),
+        tile_set_path: settings.get(&key("PATH")).unwrap_or_else(|| {
+            settings.error(format!("{} is required for layer '{}'", key("PATH"), name));
+            String::new()
         }),
-        max_post_size: get_non_empty_env_var("MAX_POST_SIZE")
-            .and_then(|s| Byte::parse_str(s, true).ok())
-            .unwrap_or_else(|| Byte::parse_str("500kb", true).unwrap()),
-        max_parallel_processing: parse_env_var::<usize>("MAX_PARALLEL_PROCESSING").unwrap_or(500),
-        max_tokio_threads: parse_env_var::<usize>("MAX_THREADS"),
-        max_concurrent_handlers: parse_env_var::<usize>("MAX_CONCURRENT_HANDLERS").unwrap_or(1000),
-        port: parse_env_var::<u16>("PORT").unwrap_or(3000),
-        bind: parse_env_var::<Ipv4Addr>("BIND").unwrap_or(Ipv4Addr::new(0, 0, 0, 0)),
-        s3_endpoint: get_non_empty_env_var("S3_ENDPOINT"),
-        s3_bucket: get_non_empty_env_var("S3_BUCKET"),
-        s3_access_key_id: get_non_empty_env_var("S3_ACCESS_KEY_ID")
-            .or_else(|| get_non_empty_env_var("AWS_ACCESS_KEY_ID")),
-        s3_secret_access_key: get_non_empty_env_var("S3_SECRET_ACCESS_KEY")
-            .or_else(|| get_non_empty_env_var("AWS_SECRET_ACCESS_KEY")),
-        s3_region: get_non_empty_env_var("S3_REGION")
-            .or_else(|| get_non_empty_env_var("AWS_REGION")),
-    }
-});
-
-pub fn get_uri_from_config(config: Config) -> String {
+        cache_size: settings
+            .parse::<CacheSize>(&key("CACHE"))
+            .unwrap_or(config.cache_size),
+        gzip: settings
+            .parse_bool(&key("GZIP"))
+            .or(settings.parse_bool("TILE_SET_GZIP"))
+            .unwrap_or(true),
+        path_template: settings
+            .get(&key("PATH_TEMPLATE"))
+            .or_else(|| config.path_template.clone()),
+        format: settings
+            .parse::<TileFormat>(&key("FORMAT"))
+            .or(settings.parse::<TileFormat>("TILE_SET_FORMAT"))
+            .unwrap_or(TileFormat::Hgt),
+        zoom: settings
+            .parse::<u8>(&key("ZOOM"))
+            .or(settings.parse::<u8>("TILE_SET_ZOOM"))
+            .unwrap_or(12),
+        mmap: settings
+            .parse_bool(&key("MMAP"))
+            .or(settings.parse_bool("TILE_SET_MMAP"))
+            .unwrap_or(false),
+        range_reads: settings
+            .parse_bool(&key("RANGE_READS"))
+            .or(settings.parse_bool("TILE_SET_RANGE_READS"))
+            .unwrap_or(false),
+        s3_endpoint: settings
+            .get(&key("S3_ENDPOINT"))
+            .or_else(|| config.s3_endpoint.clone()),
+        s3_bucket: settings.get(&key("S3_BUCKET")),
+        s3_access_key_id: settings
+            .get(&key("S3_ACCESS_KEY_ID"))
+            .or_else(|| config.s3_access_key_id.clone()),
+        s3_secret_access_key: settings
+            .get(&key("S3_SECRET_ACCESS_KEY"))
+            .or_else(|| config.s3_secret_access_key.clone()),
+        s3_region: settings
+            .get(&key("S3_REGION"))
+            .or_else(|| config.s3_region.clone()),
+    }
+}
+
+/// Reads the timeouts, retries and circuit breaker settings of remote backends.
+fn get_resilience(settings: &Settings) -> ResilienceOptions {
+    let defaults = ResilienceOptions::default();
+    let millis = |key: &str, default: Duration| {
+        settings
+            .parse::<u64>(key)
+            .map_or(default, Duration::from_millis)
+    };
+
+    ResilienceOptions {
+        connect_timeout: millis("BACKEND_CONNECT_TIMEOUT_MS", defaults.connect_timeout),
+        read_timeout: millis("BACKEND_READ_TIMEOUT_MS", defaults.read_timeout),
+        max_retries: settings
+            .parse::<u32>("BACKEND_MAX_RETRIES")
+            .unwrap_or(defaults.max_retries),
+        retry_base_delay: millis("BACKEND_RETRY_BASE_DELAY_MS", defaults.retry_base_delay),
+        retry_max_delay: millis("BACKEND_RETRY_MAX_DELAY_MS", defaults.retry_max_delay),
+        breaker_threshold: settings
+            .parse::<u32>("BACKEND_BREAKER_THRESHOLD")
+            .unwrap_or(defaults.breaker_threshold),
+        breaker_cooldown: millis("BACKEND_BREAKER_COOLDOWN_MS", defaults.breaker_cooldown),
+    }
+}
+
+/// Parses coordinates given as `lat,lng` pairs separated by semicolons,
+/// e.g. `47.37,8.54;46.55,7.98`.
+fn parse_probes(probes: &str) -> Result<Vec<(f64, f64)>, String> {
+    let parse = |probe: &str| {
+        let (lat, lng) = probe.split_once(',')?;
+        Some((lat.trim().parse().ok()?, lng.trim().parse().ok()?))
+    };
+    probes
+        .split(';')
+        .map(str::trim)
+        .filter(|probe| !probe.is_empty())
+        .map(|probe| parse(probe).ok_or_else(|| format!("'{}' is not a lat,lng pair", probe)))
+        .collect()
+}
+
+/// Converts a dataset or layer name to the form used in environment variable names
+fn env_name(name: &str) -> String {
+    name.to_uppercase().replace('-', "_")
+}
+
+/// Splits a comma-separated list, skipping empty entries
+fn split_list(list: &str) -> impl Iterator<Item = &str> {
+    list.split(',')
+        .map(str::trim)
+        .filter(|item| !item.is_empty())
+}
+
+pub fn get_uri_from_config(config: LayerConfig) -> io::Result<String> {
     let tile_folder = config.tile_set_path;
     let s3_endpoint = config.s3_endpoint.as_ref().filter(|s| !s.trim().is_empty());
     let s3_bucket = config.s3_bucket.as_ref().filter(|s| !s.trim().is_empty());
@@ -66,22 +621,22 @@ pub fn get_uri_from_config(config: Config) -> String {
         if let (Some(_endpoint), Some(bucket)) = (s3_endpoint, s3_bucket) {
             // For explicit S3 endpoint and bucket, construct s3:// URL for direct S3 access
             let path = tile_folder.trim_start_matches('/');
-            return format!("s3://{}/{}", bucket, path);
+            return Ok(format!("s3://{}/{}", bucket, path));
         } else if tile_folder.starts_with("s3://") {
             // Already an S3 URL, return as-is for direct S3 access
-            return tile_folder;
+            return Ok(tile_folder);
         } else if tile_folder.starts_with("https://") && tile_folder.contains(".s3.") {
             // Convert HTTPS S3 URL back to s3:// for authenticated access
             // https://bucket.s3.amazonaws.com/path -> s3://bucket/path
-            if let Some(url) = tile_folder.strip_prefix("https://") {
-                if let Some(dot_pos) = url.find(".s3.") {
-                    let bucket = &url[..dot_pos];
-                    if let Some(slash_pos) = url.find('/') {
-                        let path = &url[slash_pos + 1..];
-                        return format!("s3://{}/{}", bucket, path);
-                    } else {
-                        return format!("s3://{}", bucket);
-                    }
+            if let Some(url) = tile_folder.strip_prefix("https://")
+                && let Some(dot_pos) = url.find(".s3.")
+            {
+                let bucket = &url[..dot_pos];
+                if let Some(slash_pos) = url.find('/') {
+                    let path = &url[slash_pos + 1..];
+                    return Ok(format!("s3://{}/{}", bucket, path));
+                } else {
+                    return Ok(format!("s3://{}", bucket));
                 }
             }
         }
@@ -89,36 +644,467 @@ pub fn get_uri_from_config(config: Config) -> String {
 
     // Fallback to HTTP access for public buckets
     if let (Some(endpoint), Some(bucket)) = (s3_endpoint, s3_bucket) {
-        format!("https://{}.{}", bucket, endpoint)
+        Ok(format!("https://{}.{}", bucket, endpoint))
     } else if tile_folder.starts_with("s3://") {
         let without_s3 = tile_folder.strip_prefix("s3://").unwrap_or(&tile_folder);
         let parts: Vec<&str> = without_s3.split('/').collect();
         if parts.len() < 2 {
-            panic!("Invalid S3 path: {}", tile_folder);
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "Invalid S3 path '{}', expected s3://<bucket>/<path>",
+                    tile_folder
+                ),
+            ));
         }
 
         let bucket = parts[0];
         let key = parts[1..].join("/");
-        format!("https://{}.s3.amazonaws.com/{}", bucket, key)
+        Ok(format!("https://{}.s3.amazonaws.com/{}", bucket, key))
     } else {
-        tile_folder
+        Ok(tile_folder)
     }
 }
 
-/// Helper function to get environment variable, treating empty strings as None
-/// This handles Docker Compose behavior where unset variables become empty strings
-fn get_non_empty_env_var(key: &str) -> Option<String> {
-    env::var(key).ok().and_then(|s| {
-        let trimmed = s.trim();
-        if trimmed.is_empty() {
-            None
-        } else {
-            Some(trimmed.to_string())
+/// A value read from the config file, with the key it was given as in the file
+#[derive(Debug)]
+struct FileSetting {
+    key: String,
+    value: String,
+}
+
+/// Raw settings by environment variable name: the environment layered over the
+/// config file. Records the names that were read and the invalid values, so all
+/// problems can be reported together.
+struct Settings {
+    env: HashMap<String, String>,
+    file: HashMap<String, FileSetting>,
+    path: Option<String>,
+    read: RefCell<HashSet<String>>,
+    errors: RefCell<Vec<String>>,
+}
+
+impl Settings {
+    fn new(
+        env: HashMap<String, String>,
+        file: HashMap<String, FileSetting>,
+        path: Option<String>,
+    ) -> Self {
+        Self {
+            env,
+            file,
+            path,
+            read: RefCell::default(),
+            errors: RefCell::default(),
+        }
+    }
+
+    /// Returns the value of a setting, treating empty strings as unset. This handles
+    /// Docker Compose behavior where unset variables become empty strings.
+    fn get(&self, key: &str) -> Option<String> {
+        self.read.borrow_mut().insert(key.to_string());
+        non_empty(self.env.get(key))
+            .or_else(|| non_empty(self.file.get(key).map(|setting| &setting.value)))
+    }
+
+    /// Parses a setting with `parse`, recording an error for invalid values.
+    fn parse_with<T, E: Display>(
+        &self,
+        key: &str,
+        parse: impl FnOnce(&str) -> Result<T, E>,
+    ) -> Option<T> {
+        let value = self.get(key)?;
+        match parse(&value) {
+            Ok(value) => Some(value),
+            Err(e) => {
+                self.error(format!(
+                    "Invalid value '{}' for {}: {}",
+                    value,
+                    self.describe(key),
+                    e
+                ));
+                None
+            }
+        }
+    }
+
+    fn parse<T: FromStr>(&self, key: &str) -> Option<T>
+    where
+        T::Err: Display,
+    {
+        self.parse_with(key, str::parse)
+    }
+
+    /// Parses a number that has to be greater than zero, e.g. a concurrency limit.
+    /// NaN and infinite values are rejected as well.
+    fn parse_positive<T: FromStr + PartialOrd + Default>(&self, key: &str) -> Option<T>
+    where
+        T::Err: Display,
+    {
+        self.parse_with(key, |value| {
+            let number = value.parse::<T>().map_err(|e| e.to_string())?;
+            let finite = value.parse::<f64>().is_ok_and(f64::is_finite);
+            match number > T::default() && finite {
+                true => Ok(number),
+                false => Err(String::from("expected a number greater than 0")),
+            }
+        })
+    }
+
+    /// Parses a boolean setting (true/false, 1/0, yes/no)
+    fn parse_bool(&self, key: &str) -> Option<bool> {
+        self.parse_with(key, |value| match value.to_lowercase().as_str() {
+            "true" | "1" | "yes" => Ok(true),
+            "false" | "0" | "no" => Ok(false),
+            _ => Err("expected true or false"),
+        })
+    }
+
+    /// Parses a size like `500kb` or `10gb`
+    fn parse_byte(&self, key: &str) -> Option<Byte> {
+        self.parse_with(key, |value| Byte::parse_str(value, true))
+    }
+
+    fn error(&self, message: String) {
+        self.errors.borrow_mut().push(message);
+    }
+
+    /// Names the source of a setting: the environment variable, or the key in the
+    /// config file if the value came from there.
+    fn describe(&self, key: &str) -> String {
+        match (non_empty(self.env.get(key)), self.file.get(key), &self.path) {
+            (None, Some(setting), Some(path)) => format!("'{}' in {}", setting.key, path),
+            _ => key.to_string(),
+        }
+    }
+
+    /// Fails with all recorded errors and the keys of the config file that were
+    /// never read, which are usually typos.
+    fn check(&self) -> io::Result<()> {
+        let read = self.read.borrow();
+        let mut unknown: Vec<&str> = self
+            .file
+            .iter()
+            .filter(|(key, _)| !read.contains(*key))
+            .map(|(_, setting)| setting.key.as_str())
+            .collect();
+        unknown.sort();
+
+        let mut errors = self.errors.borrow().clone();
+        for key in unknown {
+            errors.push(format!(
+                "Unknown or unused setting '{}' in {}",
+                key,
+                self.path.as_deref().unwrap_or_default()
+            ));
+        }
+        if errors.is_empty() {
+            return Ok(());
         }
-    })
+        Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!("Invalid configuration:\n  {}", errors.join("\n  ")),
+        ))
+    }
 }
 
-/// Helper function to parse environment variable as a specific type, treating empty strings as None
-fn parse_env_var<T: std::str::FromStr>(key: &str) -> Option<T> {
-    get_non_empty_env_var(key).and_then(|s| s.parse().ok())
+/// Reads a TOML or YAML config file. Nested tables are flattened to the names of the
+/// environment variables, e.g. `path` in `[layer.srtm]` becomes `LAYER_SRTM_PATH`.
+fn read_config_file(path: &Path) -> io::Result<HashMap<String, FileSetting>> {
+    let invalid = |e: &dyn Display| {
+        Error::new(
+            ErrorKind::InvalidData,
+            format!("Invalid config file {}: {}", path.display(), e),
+        )
+    };
+    let contents = fs::read_to_string(path).map_err(|e| {
+        Error::new(
+            e.kind(),
+            format!("Failed to read config file {}: {}", path.display(), e),
+        )
+    })?;
+    let value = match path.extension().and_then(|extension| extension.to_str()) {
+        Some("toml") => toml::from_str::<serde_json::Value>(&contents).map_err(|e| invalid(&e))?,
+        Some("yaml" | "yml") => {
+            serde_yaml::from_str::<serde_json::Value>(&contents).map_err(|e| invalid(&e))?
+        }
+        _ => {
+            return Err(invalid(&"expected a .toml, .yaml or .yml file"));
+        }
+    };
+
+    let mut settings = HashMap::new();
+    match value {
+        serde_json::Value::Object(_) | serde_json::Value::Null => {
+            flatten_setting("", &value, &mut settings).map_err(|e| invalid(&e))?
+        }
+        _ => return Err(invalid(&"expected a table of settings")),
+    }
+    Ok(settings)
+}
+
+/// Adds a value of the config file to `settings`. Lists are joined with commas,
+/// lists of lists like the coordinates of `readiness_probes` with semicolons.
+fn flatten_setting(
+    key: &str,
+    value: &serde_json::Value,
+    settings: &mut HashMap<String, FileSetting>,
+) -> Result<(), String> {
+    use serde_json::Value;
+
+    let scalar = |value: &Value| match value {
+        Value::String(value) => Some(value.clone()),
+        Value::Number(value) => Some(value.to_string()),
+        Value::Bool(value) => Some(value.to_string()),
+        _ => None,
+    };
+    let list = |values: &Vec<Value>| {
+        values
+            .iter()
+            .map(scalar)
+            .collect::<Option<Vec<_>>>()
+            .map(|values| values.join(","))
+    };
+
+    let value = match value {
+        Value::Null => return Ok(()),
+        Value::Object(table) => {
+            for (name, value) in table {
+                let key = match key {
+                    "" => name.clone(),
+                    key => format!("{}.{}", key, name),
+                };
+                flatten_setting(&key, value, settings)?;
+            }
+            return Ok(());
+        }
+        Value::Array(values) if values.iter().any(Value::is_array) => values
+            .iter()
+            .map(|value| value.as_array().and_then(list))
+            .collect::<Option<Vec<_>>>()
+            .map(|values| values.join(";")),
+        Value::Array(values) => list(values),
+        value => scalar(value),
+    }
+    .ok_or_else(|| format!("unsupported value for '{}'", key))?;
+
+    let name = key.split('.').map(env_name).collect::<Vec<_>>().join("_");
+    let setting = FileSetting {
+        key: key.to_string(),
+        value,
+    };
+    if let Some(previous) = settings.insert(name.clone(), setting) {
+        return Err(format!(
+            "'{}' and '{}' both set {}",
+            previous.key, key, name
+        ));
+    }
+    Ok(())
+}
+
+/// Trims a value, treating empty strings as unset
+fn non_empty(value: Option<&String>) -> Option<String> {
+    value
+        .map(|value| value.trim())
+        .filter(|value| !value.is_empty())
+        .map(str::to_string)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempDir;
+
+    fn write_config(name: &str, contents: &str) -> (TempDir, String) {
+        let dir = TempDir::new("config");
+        let path = dir.join(name);
+        fs::write(&path, contents).unwrap();
+        (dir, path.to_string_lossy().into_owned())
+    }
+
+    fn load(env: &[(&str, &str)], path: &str) -> io::Result<Config> {
+        let env = env
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect();
+        let file = read_config_file(Path::new(path))?;
+        Config::from_settings(Settings::new(env, file, Some(path.to_string())))
+    }
+
+    #[test]
+    fn test_config_file_under_env_overrides() {
+        let (_dir, path) = write_config(
+            "layers.yaml",
+            r#"
+port: 3100
+max_post_size: 1mb
+datasets: [terrain, ocean]
+dataset:
+  terrain:
+    layers: [srtm-hi, srtm]
+    probes: [[47.37, 8.54], [46.55, 7.98]]
+layer:
+  srtm-hi: {path: /data/hi, zoom: 13}
+  srtm: {path: /data/srtm, s3_secret_access_key: secret}
+  ocean: {path: /data/ocean}
+backend:
+  max_retries: 1
+"#,
+        );
+        let config = load(&[("PORT", "4000"), ("LAYER_SRTM_HI_ZOOM", "")], &path).unwrap();
+
+        assert_eq!(config.port, 4000);
+        assert_eq!(config.max_post_size.as_u64(), 1_000_000);
+        assert_eq!(config.resilience.max_retries, 1);
+        assert_eq!(config.default_dataset, "terrain");
+        let terrain = &config.datasets[0];
+        assert_eq!(terrain.probes, vec![(47.37, 8.54), (46.55, 7.98)]);
+        assert_eq!(terrain.layers[0].tile_set_path, "/data/hi");
+        // Empty variables do not override the file
+        assert_eq!(terrain.layers[0].zoom, 13);
+        assert_eq!(config.datasets[1].layers[0].tile_set_path, "/data/ocean");
+
+        let redacted = config.redacted();
+        assert_eq!(
+            redacted.datasets[0].layers[1]
+                .s3_secret_access_key
+                .as_deref(),
+            Some("<redacted>")
+        );
+    }
+
+    #[test]
+    fn test_missing_layer_path_is_an_error() {
+        let env = [
+            ("TILE_SET_LAYERS", "srtm-hi, srtm"),
+            ("LAYER_SRTM_PATH", "/data/srtm"),
+        ]
+        .iter()
+        .map(|(key, value)| (key.to_string(), value.to_string()))
+        .collect();
+        let error = Config::from_settings(Settings::new(env, HashMap::new(), None))
+            .unwrap_err()
+            .to_string();
+        assert!(
+            error.contains("LAYER_SRTM_HI_PATH is required for layer 'srtm-hi'"),
+            "{}",
+            error
+        );
+    }
+
+    #[test]
+    fn test_invalid_settings_are_reported() {
+        let (_dir, path) = write_config(
+            "typos.toml",
+            "max_post_sise = \"1mb\"\naccess_log = \"maybe\"\n[layer.default]\nzoom = 12\n",
+        );
+        let error = load(&[("PORT", "30OO"), ("READINESS_PROBES", "47.37")], &path)
+            .unwrap_err()
+            .to_string();
+
+        assert!(error.contains("Invalid value '30OO' for PORT"), "{}", error);
+        assert!(
+            error.contains("Invalid value 'maybe' for 'access_log' in"),
+            "{}",
+            error
+        );
+        assert!(error.contains("'47.37' is not a lat,lng pair"), "{}", error);
+        assert!(
+            error.contains("Unknown or unused setting 'max_post_sise'"),
+            "{}",
+            error
+        );
+        // Layer settings are only read for the layers in use
+        assert!(
+            error.contains("Unknown or unused setting 'layer.default.zoom'"),
+            "{}",
+            error
+        );
+
+        fs::write(&path, "port = 3000\nPORT = 3001\n").unwrap();
+        assert!(read_config_file(Path::new(&path)).is_err());
+    }
+
+    #[test]
+    fn test_out_of_range_settings_are_reported() {
+        let error = Config::from_vars(&[
+            ("MAX_CONCURRENT_HANDLERS", "0"),
+            ("WARMUP_CONCURRENCY", "0"),
+            ("RATE_LIMIT_POINTS_PER_SECOND", "NaN"),
+            ("RATE_LIMIT_BURST", "-5"),
+            ("TILE_SET_PATH", "s3://bucket"),
+        ])
+        .unwrap_err()
+        .to_string();
+
+        for key in [
+            "MAX_CONCURRENT_HANDLERS",
+            "WARMUP_CONCURRENCY",
+            "RATE_LIMIT_POINTS_PER_SECOND",
+            "RATE_LIMIT_BURST",
+        ] {
+            assert!(
+                error.contains(&format!("for {}: expected a number greater than 0", key)),
+                "{}",
+                error
+            );
+        }
+        assert!(
+            error.contains("Layer 'default': Invalid S3 path 's3://bucket'"),
+            "{}",
+            error
+        );
+        assert!(Config::from_vars(&[("RATE_LIMIT_POINTS_PER_SECOND", "inf")]).is_err());
+        assert!(Config::from_vars(&[("RATE_LIMIT_POINTS_PER_SECOND", "0.5")]).is_ok());
+    }
+
+    #[test]
+    fn test_vars_load_again() {
+        let config = Config::from_vars(&[
+            ("DATASETS", "terrain, ocean"),
+            ("DATASET_TERRAIN_LAYERS", "srtm-hi, srtm"),
+            ("DATASET_TERRAIN_PROBES", "47.37,8.54;46.55,7.98"),
+            ("DATASET_OCEAN_LAYERS", "srtm"),
+            ("DATASET_OCEAN_MISSING_TILE_POLICY", "zero"),
+            ("LAYER_SRTM_HI_PATH", "s3://bucket/hi"),
+            ("LAYER_SRTM_HI_CACHE", "2GiB"),
+            ("LAYER_SRTM_PATH", "/data/srtm"),
+            ("S3_SECRET_ACCESS_KEY", "secret"),
+            ("MAX_POST_SIZE", "1mb"),
+            ("MAX_QUEUE_TIME_MS", "0"),
+            ("RATE_LIMIT_POINTS_PER_SECOND", "2.5"),
+            ("TRUSTED_PROXIES", "10.0.0.0/8, 192.0.2.1"),
+        ])
+        .unwrap();
+        let vars = config.vars();
+        let get = |key: &str| {
+            vars.iter()
+                .find(|(name, _)| name == key)
+                .map(|(_, value)| value.as_str())
+        };
+        assert_eq!(get("DATASETS"), Some("terrain,ocean"));
+        assert_eq!(get("LAYER_SRTM_HI_CACHE"), Some("2147483648B"));
+        assert_eq!(get("DATASET_OCEAN_MISSING_TILE_POLICY"), Some("zero"));
+        assert_eq!(get("TRUSTED_PROXIES"), Some("10.0.0.0/8,192.0.2.1/32"));
+        assert_eq!(get("MAX_THREADS"), None);
+        assert_eq!(
+            vars.iter()
+                .filter(|(name, _)| name == "LAYER_SRTM_PATH")
+                .count(),
+            1
+        );
+
+        let borrowed = vars
+            .iter()
+            .map(|(key, value)| (key.as_str(), value.as_str()))
+            .collect::<Vec<_>>();
+        assert_eq!(Config::from_vars(&borrowed).unwrap().vars(), vars);
+
+        let redacted = config.redacted().vars();
+        assert!(redacted.contains(&(
+            String::from("LAYER_SRTM_HI_S3_SECRET_ACCESS_KEY"),
+            String::from("<redacted>")
+        )));
+        assert!(!redacted.iter().any(|(_, value)| value == "secret"));
+    }
 }
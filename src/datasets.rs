@@ -0,0 +1,336 @@
+use std::{
+    collections::HashMap,
+    io::{Error, ErrorKind},
+    path::PathBuf,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tracing::debug;
+
+use crate::{
+    config::{Config, LayerConfig, get_uri_from_config},
+    tileset::{
+        DiskCache, MissingTilePolicy, PathTemplate, SKADI_TEMPLATE, TileSetOptions,
+        TileSetWithCache, XYZ_TEMPLATE,
+    },
+    types::{DatasetInfo, ProbeResult},
+};
+
+/// Named tile sets served by this instance, selectable per request.
+pub struct Datasets {
+    default: String,
+    names: Vec<String>, // Keeps the configured order for listings
+    tilesets: HashMap<String, Arc<TileSetWithCache>>,
+    probes: Vec<(String, (f64, f64))>, // Known-good coordinates of each dataset
+}
+
+impl Datasets {
+    pub fn new(config: &Config) -> Result<Self, Box<dyn std::error::Error>> {
+        if config.datasets.is_empty() {
+            return Err("At least one dataset is required".into());
+        }
+
+        let disk_cache = match &config.disk_cache_path {
+            Some(path) => Some(Arc::new(DiskCache::new(
+                PathBuf::from(path),
+                config.disk_cache_size.as_u64(),
+                config.disk_cache_decompressed,
+            )?)),
+            None => None,
+        };
+
+        let mut names = Vec::new();
+        let mut unfinished = HashMap::new();
+        for dataset in &config.datasets {
+            debug!("Dataset: {}", dataset.name);
+            debug!("  Missing Tile Policy: {:?}", dataset.missing_tile_policy);
+            let layers = dataset
+                .layers
+                .iter()
+                .map(|layer| tile_set_options(layer, disk_cache.clone(), config))
+                .collect::<Result<Vec<_>, _>>()?;
+            let tileset = TileSetWithCache::new(layers)?;
+            if unfinished
+                .insert(dataset.name.clone(), (tileset, dataset.missing_tile_policy))
+                .is_some()
+            {
+                return Err(format!("Dataset '{}' is configured twice", dataset.name).into());
+            }
+            names.push(dataset.name.clone());
+        }
+
+        // The bathymetry dataset has to be finished first, as the others refer to it
+        let mut tilesets = HashMap::new();
+        let bathymetry = if unfinished
+            .values()
+            .any(|(_, policy)| *policy == MissingTilePolicy::Bathymetry)
+        {
+            let name = config
+                .bathymetry_dataset
+                .as_ref()
+                .ok_or("BATHYMETRY_DATASET is required for the bathymetry missing tile policy")?;
+            let (tileset, policy) = unfinished
+                .remove(name)
+                .ok_or_else(|| format!("Bathymetry dataset '{}' is not configured", name))?;
+            if policy == MissingTilePolicy::Bathymetry {
+                return Err(format!(
+                    "Bathymetry dataset '{}' cannot use the bathymetry missing tile policy",
+                    name
+                )
+                .into());
+            }
+            let tileset = Arc::new(tileset.with_missing_tile_policy(policy, None)?);
+            tilesets.insert(name.clone(), tileset.clone());
+            Some(tileset)
+        } else {
+            None
+        };
+        for (name, (tileset, policy)) in unfinished {
+            let tileset = tileset.with_missing_tile_policy(policy, bathymetry.clone())?;
+            tilesets.insert(name, Arc::new(tileset));
+        }
+
+        if !tilesets.contains_key(&config.default_dataset) {
+            return Err(format!(
+                "Default dataset '{}' is not configured",
+                config.default_dataset
+            )
+            .into());
+        }
+
+        let probes = config
+            .datasets
+            .iter()
+            .flat_map(|dataset| {
+                dataset
+                    .probes
+                    .iter()
+                    .map(|probe| (dataset.name.clone(), *probe))
+            })
+            .collect();
+
+        Ok(Self {
+            default: config.default_dataset.clone(),
+            names,
+            tilesets,
+            probes,
+        })
+    }
+
+    /// Returns the requested dataset, or the default one when none is given.
+    pub fn get(&self, name: Option<&str>) -> Result<Arc<TileSetWithCache>, Error> {
+        let name = name.unwrap_or(&self.default);
+        self.tilesets
+            .get(name)
+            .cloned()
+            .ok_or_else(|| Error::new(ErrorKind::NotFound, format!("Unknown dataset '{}'", name)))
+    }
+
+    pub fn default_name(&self) -> &str {
+        &self.default
+    }
+
+    pub fn info(&self) -> Vec<DatasetInfo> {
+        self.names
+            .iter()
+            .map(|name| DatasetInfo {
+                name: name.clone(),
+                default: *name == self.default,
+                missing_tile_policy: format!("{:?}", self.tilesets[name].missing_tile_policy())
+                    .to_lowercase(),
+                layers: self.tilesets[name].layers_info(),
+            })
+            .collect()
+    }
+
+    /// Looks up the known-good coordinates of all datasets concurrently. Probes go
+    /// through the tile caches, so only tiles not cached yet are fetched from the backends.
+    pub async fn probe(&self, timeout: Duration) -> Vec<ProbeResult> {
+        let probes = self.probes.iter().map(|(name, (lat, lng))| async move {
+            let start = Instant::now();
+            let lookup = self.tilesets[name].get_elevation(*lat, *lng);
+            let result = match tokio::time::timeout(timeout, lookup).await {
+                Ok(result) => result,
+                Err(_) => Err(Error::new(ErrorKind::TimedOut, "Probe timed out")),
+            };
+            ProbeResult {
+                dataset: name.clone(),
+                lat: *lat,
+                lng: *lng,
+                ok: result.is_ok(),
+                error: result.err().map(|e| e.to_string()),
+                duration_ms: start.elapsed().as_secs_f64() * 1000.0,
+            }
+        });
+        futures::future::join_all(probes).await
+    }
+}
+
+fn tile_set_options(
+    layer: &LayerConfig,
+    disk_cache: Option<Arc<DiskCache>>,
+    config: &Config,
+) -> Result<TileSetOptions, Error> {
+    let path = get_uri_from_config(layer.clone())?;
+    debug!("Layer: {}", layer.name);
+    debug!("  Cache Size: {}", layer.cache_size);
+    debug!("  Tile Set Path: {:?}", path);
+    debug!("  Gzip: {}", layer.gzip);
+    debug!("  Path Template: {:?}", layer.path_template);
+    debug!("  Format: {}", layer.format);
+    debug!("  Zoom: {}", layer.zoom);
+    debug!("  Mmap: {}", layer.mmap);
+    debug!("  Range Reads: {}", layer.range_reads);
+    debug!("  S3 Endpoint: {:?}", layer.s3_endpoint);
+    debug!("  S3 Bucket: {:?}", layer.s3_bucket);
+
+    Ok(TileSetOptions {
+        name: layer.name.clone(),
+        path,
+        cache_size: layer.cache_size,
+        gzip: layer.gzip,
+        path_template: PathTemplate::new(
+            layer
+                .path_template
+                .as_deref()
+                .unwrap_or(if layer.format.is_raster() {
+                    XYZ_TEMPLATE
+                } else {
+                    SKADI_TEMPLATE
+                }),
+            layer.gzip,
+        )?,
+        format: layer.format,
+        zoom: layer.zoom,
+        mmap: layer.mmap,
+        range_reads: layer.range_reads,
+        s3_access_key_id: layer.s3_access_key_id.clone(),
+        s3_secret_access_key: layer.s3_secret_access_key.clone(),
+        s3_region: layer.s3_region.clone(),
+        s3_endpoint: layer.s3_endpoint.clone(),
+        disk_cache,
+        resilience: config.resilience,
+        missing_tile_cache_size: config.missing_tile_cache_size,
+        missing_tile_ttl: config.missing_tile_cache_ttl,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempDir;
+    use std::path::Path;
+
+    /// Writes an uncompressed 3 arc-second tile with the same value in every sample
+    fn write_tile(dir: &Path, lat: f64, lng: f64, value: i16) {
+        let path = dir.join(
+            PathTemplate::new(SKADI_TEMPLATE, false)
+                .unwrap()
+                .render(lat, lng),
+        );
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(path, value.to_be_bytes().repeat(1201 * 1201)).unwrap();
+    }
+
+    fn error(vars: &[(&str, &str)]) -> String {
+        match Datasets::new(&Config::from_vars(vars).unwrap()) {
+            Ok(_) => panic!("Expected {:?} to be rejected", vars),
+            Err(e) => e.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_datasets_keep_order_and_default() {
+        let config = Config::from_vars(&[
+            ("DATASETS", "srtm, gebco"),
+            ("DEFAULT_DATASET", "gebco"),
+            ("LAYER_SRTM_PATH", "/data/srtm"),
+            ("LAYER_GEBCO_PATH", "/data/gebco"),
+        ])
+        .unwrap();
+        let datasets = Datasets::new(&config).unwrap();
+
+        let info = datasets.info();
+        let names: Vec<_> = info.iter().map(|dataset| dataset.name.as_str()).collect();
+        assert_eq!(names, ["srtm", "gebco"]);
+        assert!(!info[0].default && info[1].default);
+        assert_eq!(datasets.default_name(), "gebco");
+        assert!(Arc::ptr_eq(
+            &datasets.get(None).unwrap(),
+            &datasets.get(Some("gebco")).unwrap()
+        ));
+
+        let unknown = datasets.get(Some("etopo")).err().unwrap();
+        assert_eq!(unknown.kind(), ErrorKind::NotFound);
+        assert_eq!(unknown.to_string(), "Unknown dataset 'etopo'");
+    }
+
+    #[test]
+    fn test_invalid_datasets() {
+        let layers = [
+            ("LAYER_SRTM_PATH", "/data/srtm"),
+            ("LAYER_GEBCO_PATH", "/data/gebco"),
+        ];
+        let with = |vars: &[(&'static str, &'static str)]| [&layers[..], vars].concat();
+
+        assert!(error(&with(&[("DATASETS", "srtm, srtm")])).contains("configured twice"));
+        assert_eq!(
+            error(&with(&[("DATASETS", "srtm"), ("DEFAULT_DATASET", "gebco")])),
+            "Default dataset 'gebco' is not configured"
+        );
+        assert!(
+            error(&with(&[
+                ("DATASETS", "srtm"),
+                ("DATASET_SRTM_MISSING_TILE_POLICY", "bathymetry"),
+            ]))
+            .contains("BATHYMETRY_DATASET is required")
+        );
+        assert_eq!(
+            error(&with(&[
+                ("DATASETS", "srtm"),
+                ("MISSING_TILE_POLICY", "bathymetry"),
+                ("BATHYMETRY_DATASET", "gebco"),
+            ])),
+            "Bathymetry dataset 'gebco' is not configured"
+        );
+        assert!(
+            error(&with(&[
+                ("DATASETS", "srtm, gebco"),
+                ("MISSING_TILE_POLICY", "bathymetry"),
+                ("BATHYMETRY_DATASET", "gebco"),
+            ]))
+            .contains("cannot use the bathymetry missing tile policy")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_bathymetry_dataset_is_wired_in() {
+        let root = TempDir::new("datasets");
+        write_tile(&root.join("gebco"), 10.5, 9.5, -3000);
+        write_tile(&root.join("srtm"), 45.5, 9.5, 120);
+        let srtm = root.join("srtm").to_string_lossy().into_owned();
+        let gebco = root.join("gebco").to_string_lossy().into_owned();
+
+        let config = Config::from_vars(&[
+            ("DATASETS", "srtm, gebco"),
+            ("LAYER_SRTM_PATH", &srtm),
+            ("LAYER_GEBCO_PATH", &gebco),
+            ("TILE_SET_GZIP", "false"),
+            ("DATASET_SRTM_MISSING_TILE_POLICY", "bathymetry"),
+            ("BATHYMETRY_DATASET", "gebco"),
+        ])
+        .unwrap();
+        let datasets = Datasets::new(&config).unwrap();
+        let srtm = datasets.get(Some("srtm")).unwrap();
+        let land = srtm.get_elevation_with_policy(45.5, 9.5).await;
+        let ocean = srtm.get_elevation_with_policy(10.5, 9.5).await;
+
+        assert_eq!(land.unwrap(), Some(120));
+        assert_eq!(ocean.unwrap(), Some(-3000));
+        // The bathymetry dataset is built first, but listed in the configured order
+        let info = datasets.info();
+        assert_eq!(info[0].name, "srtm");
+        assert_eq!(info[0].missing_tile_policy, "bathymetry");
+        assert_eq!(info[1].missing_tile_policy, "error");
+    }
+}
@@ -0,0 +1,188 @@
+use std::fs::File;
+use std::io::{BufRead, BufReader, Error, ErrorKind};
+use std::path::Path;
+use tracing::{debug, info, instrument};
+
+/// Geoid undulation grid (height of the geoid above the WGS84 ellipsoid).
+///
+/// Reads the PGM files distributed by GeographicLib, e.g. `egm96-5.pgm` or
+/// `egm2008-1.pgm`. Rows run from 90°N to 90°S and columns from 0°E eastwards.
+pub struct Geoid {
+    width: usize,
+    height: usize,
+    offset: f64,
+    scale: f64,
+    data: Vec<u16>,
+}
+
+impl Geoid {
+    pub fn load(path: &Path) -> Result<Self, Error> {
+        let geoid = Self::from_reader(BufReader::new(File::open(path)?))?;
+        info!(
+            path = %path.display(),
+            width = geoid.width,
+            height = geoid.height,
+            "Loaded geoid grid"
+        );
+        Ok(geoid)
+    }
+
+    /// Reads a PGM file, decoding the samples block by block so that the file is
+    /// never held in memory next to the grid.
+    pub fn from_reader(mut reader: impl BufRead) -> Result<Self, Error> {
+        let invalid = |message: &str| Error::new(ErrorKind::InvalidData, message.to_string());
+
+        let mut offset = None;
+        let mut scale = None;
+        let mut values = Vec::new();
+        let mut line = Vec::new();
+
+        // Header: magic number, width, height and max value, interleaved with comment lines
+        while values.len() < 4 {
+            line.clear();
+            reader.read_until(b'\n', &mut line)?;
+            if line.pop() != Some(b'\n') {
+                return Err(invalid("Truncated geoid header"));
+            }
+            let line = std::str::from_utf8(&line)
+                .map_err(|_| invalid("Geoid header is not valid UTF-8"))?;
+
+            if let Some(comment) = line.strip_prefix('#') {
+                let mut parts = comment.split_whitespace();
+                match (parts.next(), parts.next()) {
+                    (Some("Offset"), Some(value)) => offset = value.parse::<f64>().ok(),
+                    (Some("Scale"), Some(value)) => scale = value.parse::<f64>().ok(),
+                    _ => {}
+                }
+                continue;
+            }
+            values.extend(line.split_whitespace().map(String::from));
+        }
+
+        if values[0] != "P5" {
+            return Err(invalid("Geoid file is not a binary PGM (P5) file"));
+        }
+        let parse = |value: &str| {
+            value
+                .parse::<usize>()
+                .map_err(|_| invalid("Invalid number in geoid header"))
+        };
+        let width = parse(&values[1])?;
+        let height = parse(&values[2])?;
+        if parse(&values[3])? != 65535 {
+            return Err(invalid("Geoid file must use 16 bit samples"));
+        }
+        if width == 0 || height < 2 {
+            return Err(invalid("Geoid grid is too small"));
+        }
+
+        let mismatch = || invalid("Geoid data does not match the grid size");
+        let samples = width.checked_mul(height).ok_or_else(mismatch)?;
+        let mut data = Vec::with_capacity(samples);
+        let mut block = vec![0u8; 64 * 1024];
+        while data.len() < samples {
+            let block = &mut block[..((samples - data.len()) * 2).min(64 * 1024)];
+            reader.read_exact(block).map_err(|e| match e.kind() {
+                ErrorKind::UnexpectedEof => mismatch(),
+                _ => e,
+            })?;
+            data.extend(
+                block
+                    .chunks_exact(2)
+                    .map(|pair| u16::from_be_bytes([pair[0], pair[1]])),
+            );
+        }
+        if !reader.fill_buf()?.is_empty() {
+            return Err(mismatch());
+        }
+
+        Ok(Self {
+            width,
+            height,
+            offset: offset.ok_or_else(|| invalid("Geoid header is missing the offset"))?,
+            scale: scale.ok_or_else(|| invalid("Geoid header is missing the scale"))?,
+            data,
+        })
+    }
+
+    /// Returns the geoid undulation in meters, interpolated bilinearly.
+    #[instrument(level = "debug", skip(self))]
+    pub fn undulation(&self, lat: f64, lng: f64) -> f64 {
+        let x = lng.rem_euclid(360.0) / 360.0 * self.width as f64;
+        let y =
+            ((90.0 - lat) / 180.0 * (self.height - 1) as f64).clamp(0.0, (self.height - 1) as f64);
+
+        let col = x.floor() as usize % self.width;
+        let row = (y.floor() as usize).min(self.height - 2);
+        let col_frac = x - x.floor();
+        let row_frac = y - row as f64;
+        let next_col = (col + 1) % self.width; // Wraps around at the antimeridian
+
+        let value = |row: usize, col: usize| {
+            self.offset + self.scale * self.data[row * self.width + col] as f64
+        };
+        let top = value(row, col) * (1.0 - col_frac) + value(row, next_col) * col_frac;
+        let bottom = value(row + 1, col) * (1.0 - col_frac) + value(row + 1, next_col) * col_frac;
+        let undulation = top * (1.0 - row_frac) + bottom * row_frac;
+
+        debug!(undulation, "Computed geoid undulation");
+        undulation
+    }
+
+    /// Converts an orthometric height (above the geoid) to an ellipsoidal height.
+    pub fn to_ellipsoidal(&self, elevation: i16, lat: f64, lng: f64) -> i16 {
+        (elevation as f64 + self.undulation(lat, lng)).round() as i16
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 90° grid: rows at 90N, 0 and 90S, columns at 0E, 90E, 180E and 270E
+    fn test_grid() -> Vec<u8> {
+        let mut buffer = b"P5\n# Offset -100\n# Scale 0.5\n4 3\n65535\n".to_vec();
+        for raw in [200u16, 200, 200, 200, 100, 200, 300, 400, 0, 0, 0, 0] {
+            buffer.extend_from_slice(&raw.to_be_bytes());
+        }
+        buffer
+    }
+
+    #[test]
+    fn test_grid_points() {
+        let geoid = Geoid::from_reader(&test_grid()[..]).unwrap();
+        assert_eq!(geoid.undulation(90.0, 0.0), 0.0);
+        assert_eq!(geoid.undulation(0.0, 0.0), -50.0);
+        assert_eq!(geoid.undulation(0.0, 90.0), 0.0);
+        assert_eq!(geoid.undulation(0.0, -90.0), 100.0);
+        assert_eq!(geoid.undulation(-90.0, 0.0), -100.0);
+    }
+
+    #[test]
+    fn test_interpolation_wraps_around() {
+        let geoid = Geoid::from_reader(&test_grid()[..]).unwrap();
+        assert_eq!(geoid.undulation(0.0, 45.0), -25.0);
+        assert_eq!(geoid.undulation(0.0, -45.0), 25.0);
+        assert_eq!(geoid.undulation(45.0, 0.0), -25.0);
+    }
+
+    #[test]
+    fn test_to_ellipsoidal() {
+        let geoid = Geoid::from_reader(&test_grid()[..]).unwrap();
+        assert_eq!(geoid.to_ellipsoidal(100, 0.0, 0.0), 50);
+        assert_eq!(geoid.to_ellipsoidal(100, 0.0, -45.0), 125);
+    }
+
+    #[test]
+    fn test_invalid_file() {
+        let mut buffer = test_grid();
+        buffer.truncate(buffer.len() - 2);
+        let error = Geoid::from_reader(&buffer[..]).err().unwrap();
+        assert_eq!(error.kind(), ErrorKind::InvalidData);
+        assert!(Geoid::from_reader(&b"P2\n4 3\n65535\n"[..]).is_err());
+
+        let mut buffer = test_grid();
+        buffer.push(0);
+        assert!(Geoid::from_reader(&buffer[..]).is_err());
+    }
+}
@@ -1,67 +1,209 @@
-use futures::stream::StreamExt;
-use rand;
 use std::{
     io::{Error, ErrorKind},
     sync::Arc,
+    time::{Duration, Instant},
 };
-use tokio::sync::Semaphore;
-use tracing::{error, info, instrument};
+use tokio::sync::{Semaphore, SemaphorePermit};
+use tracing::{error, info, instrument, warn};
 use warp::{Rejection, Reply, filters::path::FullPath, reply};
 
 use crate::{
+    api_keys::Caller,
     config::Config,
+    datasets::Datasets,
+    geoid::Geoid,
+    metrics::METRICS,
+    shutdown::Shutdown,
+    telemetry::record_request_points,
     tileset::TileSetWithCache,
-    types::{ElevationResponse, LatLng, LatLngs},
+    types::{
+        ElevationQuery, ElevationResponse, GeoidQuery, HeightReference, LatLng, LatLngs,
+        StatusResponse, WarmupRequest, WarmupResponse,
+    },
+    warmup::{Warmup, regions_from_request},
 };
 
-#[derive(Debug)]
-struct InternalError;
+/// The semaphore limiting concurrent handlers, with the longest time a request
+/// may wait for a permit.
+pub struct HandlerPermits {
+    semaphore: Arc<Semaphore>,
+    max_queue_time: Option<Duration>,
+}
 
-impl warp::reject::Reject for InternalError {}
+impl HandlerPermits {
+    pub fn new(semaphore: Arc<Semaphore>, max_queue_time: Option<Duration>) -> Self {
+        Self {
+            semaphore,
+            max_queue_time,
+        }
+    }
 
-#[instrument(skip(tileset, semaphore))]
-pub async fn get_status(
-    tileset: Arc<TileSetWithCache>,
-    semaphore: Arc<Semaphore>,
+    /// Waits for a concurrent handler permit, recording the time spent waiting.
+    /// Sheds the request with `503` if none frees up within the max queue time.
+    async fn acquire(&self, request: &str) -> Result<SemaphorePermit<'_>, reply::Response> {
+        let start = Instant::now();
+        let acquired = match self.max_queue_time {
+            Some(max_queue_time) => {
+                match tokio::time::timeout(max_queue_time, self.semaphore.acquire()).await {
+                    Ok(acquired) => acquired,
+                    Err(_) => {
+                        warn!(?max_queue_time, "Shedding {}", request);
+                        METRICS.shed_requests.add(1, &[]);
+                        let retry_after = max_queue_time.as_secs_f64().ceil().max(1.0);
+                        return Err(reply::with_header(
+                            reply::with_status(
+                                "Service overloaded, retry later",
+                                warp::http::StatusCode::SERVICE_UNAVAILABLE,
+                            ),
+                            "retry-after",
+                            retry_after.to_string(),
+                        )
+                        .into_response());
+                    }
+                }
+            }
+            None => self.semaphore.acquire().await,
+        };
+        METRICS
+            .semaphore_wait
+            .record(start.elapsed().as_secs_f64(), &[]);
+        acquired.map_err(|_| {
+            error!("Failed to acquire semaphore permit for {}", request);
+            reply::with_status(
+                "Internal server error",
+                warp::http::StatusCode::INTERNAL_SERVER_ERROR,
+            )
+            .into_response()
+        })
+    }
+}
+
+/// Liveness: the process runs and answers requests.
+#[instrument(skip_all)]
+pub async fn get_livez() -> Result<impl Reply, Rejection> {
+    Ok(reply::with_status("Ok", warp::http::StatusCode::OK))
+}
+
+/// Readiness: the service is not shutting down, the startup warmup has finished and
+/// the known-good coordinates of all datasets can be looked up. Does not wait for
+/// the handler semaphore, so a busy instance is not taken out of rotation.
+#[instrument(skip_all)]
+pub async fn get_readyz(
+    datasets: Arc<Datasets>,
+    warmup: Arc<Warmup>,
+    shutdown: Arc<Shutdown>,
+    config: Config,
 ) -> Result<impl Reply, Rejection> {
-    let _permit = semaphore.acquire().await.map_err(|_| {
-        error!("Failed to acquire semaphore permit for status check");
-        warp::reject::custom(InternalError)
-    })?;
+    if shutdown.is_draining() {
+        return Ok(reply::with_status(
+            "Shutting down",
+            warp::http::StatusCode::SERVICE_UNAVAILABLE,
+        ));
+    }
+    if !warmup.is_ready() {
+        info!("Readiness check during startup warmup");
+        return Ok(reply::with_status(
+            "Warming up",
+            warp::http::StatusCode::SERVICE_UNAVAILABLE,
+        ));
+    }
 
-    info!("Status check requested");
-    let random_lat = rand::random::<f64>() * 180.0 - 90.0;
-    let random_lng = rand::random::<f64>() * 360.0 - 180.0;
-    match tileset.get_elevation(random_lat, random_lng).await {
-        Ok(_) => {
-            info!("Status check passed");
-            Ok(reply::with_status("Ok", warp::http::StatusCode::OK))
-        }
-        Err(_) => {
-            error!("Status check failed");
+    let probes = datasets.probe(config.readiness_probe_timeout).await;
+    match probes.iter().find(|probe| !probe.ok) {
+        None => Ok(reply::with_status("Ok", warp::http::StatusCode::OK)),
+        Some(failed) => {
+            error!(
+                dataset = %failed.dataset,
+                lat = failed.lat,
+                lng = failed.lng,
+                error = ?failed.error,
+                "Readiness probe failed"
+            );
             Ok(reply::with_status(
                 "Error",
-                warp::http::StatusCode::INTERNAL_SERVER_ERROR,
+                warp::http::StatusCode::SERVICE_UNAVAILABLE,
             ))
         }
     }
 }
 
-#[instrument(skip_all, fields(coord = format!("{},{}", query.lat, query.lng)))]
+/// Detailed status: readiness, probe results, backend reachability and cache usage.
+/// Answers 503 whenever `/readyz` would.
+#[instrument(skip_all)]
+pub async fn get_status(
+    datasets: Arc<Datasets>,
+    warmup: Arc<Warmup>,
+    shutdown: Arc<Shutdown>,
+    config: Config,
+) -> Result<impl Reply, Rejection> {
+    info!("Status check requested");
+    let probes = datasets.probe(config.readiness_probe_timeout).await;
+    let status = if shutdown.is_draining() {
+        "shutting_down"
+    } else if !warmup.is_ready() {
+        "warming_up"
+    } else if probes.iter().all(|probe| probe.ok) {
+        "ok"
+    } else {
+        "error"
+    };
+    let code = match status {
+        "ok" => warp::http::StatusCode::OK,
+        _ => warp::http::StatusCode::SERVICE_UNAVAILABLE,
+    };
+    let response = StatusResponse {
+        status,
+        version: env!("CARGO_PKG_VERSION"),
+        warmup: if warmup.is_ready() { "done" } else { "running" },
+        probes,
+        datasets: datasets.info(),
+    };
+    Ok(reply::with_status(reply::json(&response), code))
+}
+
+#[instrument(skip_all, fields(coord = format!("{},{}", query.lat, query.lng), dataset = ?query.dataset))]
 pub async fn get_elevation(
     query: LatLng,
-    tileset: Arc<TileSetWithCache>,
-    semaphore: Arc<Semaphore>,
+    caller: Caller,
+    datasets: Arc<Datasets>,
+    geoid: Option<Arc<Geoid>>,
+    permits: Arc<HandlerPermits>,
 ) -> Result<impl Reply, Rejection> {
-    let _permit = semaphore.acquire().await.map_err(|_| {
-        error!("Failed to acquire semaphore permit for elevation request");
-        warp::reject::custom(InternalError)
-    })?;
+    record_request_points(1);
+    if let Err(e) = caller.authenticate() {
+        return Ok(e.into_response());
+    }
+    let tileset = match datasets.get(query.dataset.as_deref()) {
+        Ok(tileset) => tileset,
+        Err(e) => return Ok(convert_io_error_to_warp_replay(e).into_response()),
+    };
+    let geoid = match select_geoid(query.height, geoid) {
+        Ok(geoid) => geoid,
+        Err(e) => return Ok(convert_io_error_to_warp_replay(e).into_response()),
+    };
+    if let Err(e) = TileSetWithCache::validate_coordinates(query.lat, query.lng) {
+        return Ok(convert_io_error_to_warp_replay(e).into_response());
+    }
+    // Only requests that are valid and get a permit count against quotas and rate limits
+    let _permit = match permits.acquire("elevation request").await {
+        Ok(permit) => permit,
+        Err(response) => return Ok(response),
+    };
+    if let Err(e) = caller.authorize(1) {
+        return Ok(e.into_response());
+    }
 
     info!("Single elevation request");
-    let elevation = match tileset.get_elevation(query.lat, query.lng).await {
+    let elevation = match tileset
+        .get_elevation_with_policy(query.lat, query.lng)
+        .await
+    {
         Ok(elevation) => {
-            info!(elevation = elevation, "Elevation retrieved successfully");
+            let elevation = elevation.map(|elevation| match &geoid {
+                Some(geoid) => geoid.to_ellipsoidal(elevation, query.lat, query.lng),
+                None => elevation,
+            });
+            info!(elevation = ?elevation, "Elevation retrieved successfully");
             elevation
         }
         Err(e) => {
@@ -73,47 +215,67 @@ pub async fn get_elevation(
     Ok(reply::json(&elevation).into_response())
 }
 
-#[instrument(skip_all, fields(points_count = locations.latlngs.len()))]
+#[instrument(skip_all, fields(points_count = locations.latlngs.len(), dataset = ?query.dataset))]
 pub async fn post_elevations(
+    query: ElevationQuery,
     locations: LatLngs,
-    tileset: Arc<TileSetWithCache>,
+    caller: Caller,
+    datasets: Arc<Datasets>,
+    geoid: Option<Arc<Geoid>>,
     config: Config,
-    semaphore: Arc<Semaphore>,
+    permits: Arc<HandlerPermits>,
 ) -> Result<impl Reply, Rejection> {
-    let _permit = semaphore.acquire().await.map_err(|_| {
-        error!("Failed to acquire semaphore permit for batch elevation request");
-        warp::reject::custom(InternalError)
-    })?;
+    record_request_points(locations.latlngs.len());
+    if let Err(e) = caller.authenticate() {
+        return Ok(e.into_response());
+    }
+    let tileset = match datasets.get(query.dataset.as_deref()) {
+        Ok(tileset) => tileset,
+        Err(e) => return Ok(convert_io_error_to_warp_replay(e).into_response()),
+    };
+    let geoid = match select_geoid(query.height, geoid) {
+        Ok(geoid) => geoid,
+        Err(e) => return Ok(convert_io_error_to_warp_replay(e).into_response()),
+    };
+    if let Err(e) = locations
+        .latlngs
+        .iter()
+        .try_for_each(|&(lat, lng)| TileSetWithCache::validate_coordinates(lat, lng))
+    {
+        return Ok(convert_io_error_to_warp_replay(e).into_response());
+    }
+    // Only requests that are valid and get a permit count against quotas and rate limits
+    let _permit = match permits.acquire("batch elevation request").await {
+        Ok(permit) => permit,
+        Err(response) => return Ok(response),
+    };
+    if let Err(e) = caller.authorize(locations.latlngs.len()) {
+        return Ok(e.into_response());
+    }
 
     info!("Batch elevation request");
-    let elevation_futures = locations.into_iter().map(|loc| {
-        let lat = loc.0;
-        let lng = loc.1;
-        let tileset = tileset.clone();
-
-        async move {
-            tileset
-                .get_elevation(lat, lng)
-                .await
-                .map(|elevation| elevation)
-        }
-    });
-
-    let results = futures::stream::iter(elevation_futures)
-        .buffered(config.max_parallel_processing)
-        .collect::<Vec<_>>()
-        .await;
-
-    let mut elevations = Vec::new();
-    for result in results {
-        match result {
-            Ok(elevation) => elevations.push(elevation),
-            Err(e) => {
-                error!(error = %e, "Failed to get elevation in batch request");
-                return Ok(convert_io_error_to_warp_replay(e).into_response());
-            }
+    let points = locations.latlngs;
+    METRICS.batch_points.record(points.len() as u64, &[]);
+    let elevations = match tileset
+        .get_elevations_with_policy(&points, config.max_parallel_processing)
+        .await
+    {
+        Ok(elevations) => elevations,
+        Err(e) => {
+            error!(error = %e, "Failed to get elevation in batch request");
+            return Ok(convert_io_error_to_warp_replay(e).into_response());
         }
-    }
+    };
+    let elevations = match &geoid {
+        Some(geoid) => points
+            .iter()
+            .zip(elevations)
+            .map(|(&(lat, lng), elevation)| {
+                elevation.map(|elevation| geoid.to_ellipsoidal(elevation, lat, lng))
+            })
+            .collect::<Vec<_>>(),
+        None => elevations,
+    };
 
     info!(
         elevations_count = elevations.len(),
@@ -122,20 +284,322 @@ pub async fn post_elevations(
     Ok(reply::json(&ElevationResponse { elevations }).into_response())
 }
 
+#[instrument(skip_all)]
+pub async fn list_datasets(
+    caller: Caller,
+    datasets: Arc<Datasets>,
+) -> Result<impl Reply, Rejection> {
+    if let Err(e) = caller.authorize(0) {
+        return Ok(e.into_response());
+    }
+    info!("Dataset listing requested");
+    Ok(reply::json(&datasets.info()).into_response())
+}
+
+#[instrument(skip_all)]
+pub async fn get_metrics() -> Result<impl Reply, Rejection> {
+    match METRICS.render() {
+        Ok(metrics) => Ok(reply::with_header(
+            metrics,
+            "content-type",
+            "text/plain; version=0.0.4; charset=utf-8",
+        )
+        .into_response()),
+        Err(e) => {
+            error!(error = %e, "Failed to collect metrics");
+            Ok(reply::with_status(
+                "Failed to collect metrics",
+                warp::http::StatusCode::INTERNAL_SERVER_ERROR,
+            )
+            .into_response())
+        }
+    }
+}
+
+#[instrument(skip_all, fields(coord = format!("{},{}", query.lat, query.lng)))]
+pub async fn get_geoid_undulation(
+    query: GeoidQuery,
+    caller: Caller,
+    geoid: Option<Arc<Geoid>>,
+) -> Result<impl Reply, Rejection> {
+    if let Err(e) = caller.authenticate() {
+        return Ok(e.into_response());
+    }
+    let Some(geoid) = geoid else {
+        return Ok(convert_io_error_to_warp_replay(no_geoid_error()).into_response());
+    };
+    if let Err(e) = TileSetWithCache::validate_coordinates(query.lat, query.lng) {
+        return Ok(convert_io_error_to_warp_replay(e).into_response());
+    }
+    if let Err(e) = caller.authorize(1) {
+        return Ok(e.into_response());
+    }
+    info!("Geoid undulation request");
+
+    Ok(reply::json(&geoid.undulation(query.lat, query.lng)).into_response())
+}
+
+#[instrument(skip_all)]
+pub async fn post_warmup(
+    authorization: Option<String>,
+    request: WarmupRequest,
+    warmup: Arc<Warmup>,
+    config: Config,
+) -> Result<impl Reply, Rejection> {
+    if let Err(e) = authorize_admin(authorization.as_deref(), &config) {
+        return Ok(convert_io_error_to_warp_replay(e).into_response());
+    }
+
+    info!(dataset = ?request.dataset, "Warmup requested");
+    let started = regions_from_request(&request.bboxes, &request.tiles, request.geojson.as_ref())
+        .and_then(|regions| warmup.trigger(request.dataset.as_deref(), &regions));
+    match started {
+        Ok((dataset, tiles)) => Ok(reply::with_status(
+            reply::json(&WarmupResponse { dataset, tiles }),
+            warp::http::StatusCode::ACCEPTED,
+        )
+        .into_response()),
+        Err(e) => Ok(convert_io_error_to_warp_replay(e).into_response()),
+    }
+}
+
 #[instrument]
 pub async fn handle_options(_: FullPath) -> Result<impl warp::Reply, warp::Rejection> {
     info!("CORS preflight request handled");
     Ok(warp::reply::with_status("", warp::http::StatusCode::OK))
 }
 
+/// Returns the geoid needed to convert to the requested height reference, if any.
+fn select_geoid(
+    height: HeightReference,
+    geoid: Option<Arc<Geoid>>,
+) -> Result<Option<Arc<Geoid>>, Error> {
+    match (height, geoid) {
+        (HeightReference::Orthometric, _) => Ok(None),
+        (HeightReference::Ellipsoidal, Some(geoid)) => Ok(Some(geoid)),
+        (HeightReference::Ellipsoidal, None) => Err(no_geoid_error()),
+    }
+}
+
+fn no_geoid_error() -> Error {
+    Error::new(
+        ErrorKind::Unsupported,
+        "No geoid model configured (GEOID_PATH), ellipsoidal heights are not available.",
+    )
+}
+
+/// Checks the bearer token of admin requests. Without `ADMIN_TOKEN` the admin API is disabled.
+fn authorize_admin(authorization: Option<&str>, config: &Config) -> Result<(), Error> {
+    let Some(token) = &config.admin_token else {
+        return Err(Error::new(ErrorKind::NotFound, "Admin API is disabled"));
+    };
+    let given = authorization
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .unwrap_or_default();
+    // Compare in constant time to not leak the token through timing
+    let matches = given.len() == token.len()
+        && given
+            .bytes()
+            .zip(token.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0;
+    if matches {
+        Ok(())
+    } else {
+        Err(Error::new(
+            ErrorKind::PermissionDenied,
+            "Invalid or missing admin token",
+        ))
+    }
+}
+
 fn convert_io_error_to_warp_replay(err: Error) -> impl Reply {
     let status = match err.kind() {
         ErrorKind::NotFound => warp::http::StatusCode::NOT_FOUND,
         ErrorKind::InvalidInput => warp::http::StatusCode::BAD_REQUEST,
+        ErrorKind::Unsupported => warp::http::StatusCode::NOT_IMPLEMENTED,
+        ErrorKind::PermissionDenied => warp::http::StatusCode::UNAUTHORIZED,
+        ErrorKind::ConnectionRefused | ErrorKind::TimedOut => {
+            error!(error = %err, "Tile backend unavailable");
+            warp::http::StatusCode::SERVICE_UNAVAILABLE
+        }
         _ => {
             error!(error = %err, "Error fetching elevation");
             warp::http::StatusCode::INTERNAL_SERVER_ERROR
         }
     };
-    return reply::with_status(err.to_string(), status).into_response();
+    reply::with_status(err.to_string(), status).into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        api_keys::{self, ApiKeys},
+        rate_limit::RateLimiter,
+        test_util::TempDir,
+    };
+    use std::convert::Infallible;
+    use warp::{Filter, http::StatusCode};
+
+    /// Geoid 50 m above the ellipsoid everywhere
+    fn geoid() -> Option<Arc<Geoid>> {
+        let mut buffer = b"P5\n# Offset -100\n# Scale 0.5\n4 3\n65535\n".to_vec();
+        buffer.extend(300u16.to_be_bytes().repeat(12));
+        Some(Arc::new(Geoid::from_reader(&buffer[..]).unwrap()))
+    }
+
+    fn with<T: Clone + Send>(value: T) -> impl Filter<Extract = (T,), Error = Infallible> + Clone {
+        warp::any().map(move || value.clone())
+    }
+
+    /// The elevation and geoid routes as set up by `main`, without API keys
+    fn routes(
+        config: Config,
+        geoid: Option<Arc<Geoid>>,
+    ) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+        let caller = api_keys::caller(
+            Arc::new(ApiKeys::from_config(&config).unwrap()),
+            Arc::new(RateLimiter::from_config(&config)),
+            Vec::new(),
+        );
+        let datasets = Arc::new(Datasets::new(&config).unwrap());
+        let permits = Arc::new(HandlerPermits::new(Arc::new(Semaphore::new(4)), None));
+
+        let get = warp::path::end()
+            .and(warp::get())
+            .and(warp::query::<LatLng>())
+            .and(caller.clone())
+            .and(with(datasets.clone()))
+            .and(with(geoid.clone()))
+            .and(with(permits.clone()))
+            .and_then(get_elevation);
+        let post = warp::path::end()
+            .and(warp::post())
+            .and(warp::query::<ElevationQuery>())
+            .and(warp::body::json::<LatLngs>())
+            .and(caller.clone())
+            .and(with(datasets))
+            .and(with(geoid.clone()))
+            .and(with(config))
+            .and(with(permits))
+            .and_then(post_elevations);
+        let geoid = warp::path!("api" / "geoid")
+            .and(warp::query::<GeoidQuery>())
+            .and(caller)
+            .and(with(geoid))
+            .and_then(get_geoid_undulation);
+        get.or(post).or(geoid)
+    }
+
+    #[tokio::test]
+    async fn test_geoid_undulation() {
+        let config = Config::from_vars(&[]).unwrap();
+        let request = |path: &str| warp::test::request().path(path);
+
+        let response = request("/api/geoid?lat=45.5&lng=9.5")
+            .reply(&routes(config.clone(), geoid()))
+            .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.body(), "50.0");
+        let response = request("/api/geoid?lat=95&lng=9.5")
+            .reply(&routes(config.clone(), geoid()))
+            .await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let response = request("/api/geoid?lat=45.5&lng=9.5")
+            .reply(&routes(config, None))
+            .await;
+        assert_eq!(response.status(), StatusCode::NOT_IMPLEMENTED);
+    }
+
+    #[tokio::test]
+    async fn test_ellipsoidal_heights() {
+        let root = TempDir::new("handlers");
+        let path = root.join("N45/N45E009.hgt");
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(&path, 120i16.to_be_bytes().repeat(1201 * 1201)).unwrap();
+        let config = Config::from_vars(&[
+            ("TILE_SET_PATH", &root.to_string_lossy()),
+            ("TILE_SET_GZIP", "false"),
+        ])
+        .unwrap();
+        let request = |path: &str| warp::test::request().path(path);
+
+        let orthometric = request("/?lat=45.5&lng=9.5")
+            .reply(&routes(config.clone(), geoid()))
+            .await;
+        let ellipsoidal = request("/?lat=45.5&lng=9.5&height=ellipsoidal")
+            .reply(&routes(config.clone(), geoid()))
+            .await;
+        let unavailable = request("/?lat=45.5&lng=9.5&height=ellipsoidal")
+            .reply(&routes(config.clone(), None))
+            .await;
+        let batch = request("/?height=ellipsoidal")
+            .method("POST")
+            .body("[[45.5, 9.5], [45.1, 9.9]]")
+            .reply(&routes(config, geoid()))
+            .await;
+
+        assert_eq!(orthometric.body(), "120");
+        assert_eq!(ellipsoidal.body(), "170");
+        assert_eq!(unavailable.status(), StatusCode::NOT_IMPLEMENTED);
+        assert_eq!(batch.body(), "[170,170]");
+    }
+
+    #[tokio::test]
+    async fn test_rejected_requests_are_not_charged() {
+        let root = TempDir::new("charged");
+        let path = root.join("N45/N45E009.hgt");
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(&path, 120i16.to_be_bytes().repeat(1201 * 1201)).unwrap();
+        let config = Config::from_vars(&[
+            ("TILE_SET_PATH", &root.to_string_lossy()),
+            ("TILE_SET_GZIP", "false"),
+            ("RATE_LIMIT_POINTS_PER_SECOND", "0.001"),
+            ("RATE_LIMIT_BURST", "2"),
+        ])
+        .unwrap();
+        let routes = routes(config, None);
+        let request = |path: &str| {
+            warp::test::request()
+                .path(path)
+                .remote_addr(([192, 0, 2, 1], 40000).into())
+        };
+
+        let mut rejected = Vec::new();
+        for path in [
+            "/?lat=95&lng=9.5",
+            "/?lat=45.5&lng=9.5&dataset=unknown",
+            "/?lat=45.5&lng=9.5&height=ellipsoidal",
+        ] {
+            rejected.push(request(path).reply(&routes).await.status());
+        }
+        let batch = request("/")
+            .method("POST")
+            .body("[[45.5, 9.5], [95, 9.9]]")
+            .reply(&routes)
+            .await;
+        let mut accepted = Vec::new();
+        for _ in 0..3 {
+            accepted.push(request("/?lat=45.5&lng=9.5").reply(&routes).await.status());
+        }
+
+        assert_eq!(
+            rejected,
+            [
+                StatusCode::BAD_REQUEST,
+                StatusCode::NOT_FOUND,
+                StatusCode::NOT_IMPLEMENTED
+            ]
+        );
+        assert_eq!(batch.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(
+            accepted,
+            [
+                StatusCode::OK,
+                StatusCode::OK,
+                StatusCode::TOO_MANY_REQUESTS
+            ]
+        );
+    }
 }
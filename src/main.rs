@@ -1,25 +1,101 @@
 use crate::{
-    config::get_uri_from_config,
-    handlers::{get_elevation, get_status, handle_options, post_elevations},
-    telemetry::init_telemetry,
-    tileset::{TileSetOptions, TileSetWithCache},
-    types::{LatLng, LatLngs},
+    api_keys::ApiKeys,
+    config::Config,
+    datasets::Datasets,
+    geoid::Geoid,
+    handlers::{
+        HandlerPermits, get_elevation, get_geoid_undulation, get_livez, get_metrics, get_readyz,
+        get_status, handle_options, list_datasets, post_elevations, post_warmup,
+    },
+    metrics::METRICS,
+    rate_limit::RateLimiter,
+    shutdown::Shutdown,
+    telemetry::{
+        access_log, extract_context, init_telemetry, record_remote_addr, shutdown_telemetry,
+    },
+    tls::Tls,
+    types::{ElevationQuery, GeoidQuery, LatLng, LatLngs, WarmupRequest},
+    warmup::Warmup,
 };
-use opentelemetry::global;
-use std::sync::Arc;
+use std::{path::Path, sync::Arc};
 use tokio::sync::Semaphore;
-use tracing::{debug, info};
+use tracing::{debug, error, info, info_span, warn};
+use tracing_opentelemetry::OpenTelemetrySpanExt;
 use warp::Filter;
 
+mod api_keys;
 mod config;
+mod datasets;
+mod geoid;
 mod handlers;
+mod metrics;
+mod rate_limit;
+mod shutdown;
 mod telemetry;
+#[cfg(test)]
+mod test_util;
 mod tileset;
+mod tls;
 mod types;
+mod warmup;
+
+const USAGE: &str = "Usage: elevation-service [--config <file>] [--print-config]
+
+Options:
+  --config <file>  TOML or YAML config file, overridden by environment variables
+                   (default: CONFIG_FILE)
+  --print-config   Print the effective settings as KEY=value lines, with secrets
+                   redacted, and exit
+  --help           Print this help";
+
+/// Command line options, all other settings come from the config file and the environment
+#[derive(Debug, Default)]
+struct Args {
+    config_path: Option<String>,
+    print_config: bool,
+}
+
+fn parse_args(args: impl IntoIterator<Item = String>) -> Result<Args, String> {
+    let mut parsed = Args::default();
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--config" => {
+                parsed.config_path =
+                    Some(args.next().ok_or("--config requires the path of a file")?);
+            }
+            "--print-config" => parsed.print_config = true,
+            arg => match arg.strip_prefix("--config=") {
+                Some(path) => parsed.config_path = Some(path.to_string()),
+                None => return Err(format!("Unknown argument '{}'", arg)),
+            },
+        }
+    }
+    Ok(parsed)
+}
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
-    // Access the configuration values early to use them in runtime creation
-    let config = config::CONFIG.clone();
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if args.iter().any(|arg| arg == "--help" || arg == "-h") {
+        println!("{}", USAGE);
+        return Ok(());
+    }
+    let args = parse_args(args).unwrap_or_else(|e| {
+        eprintln!("{}\n\n{}", e, USAGE);
+        std::process::exit(2);
+    });
+    // Load the configuration early to use it in runtime creation, and to fail
+    // with readable messages instead of a panic
+    let config = Config::load(args.config_path.as_deref()).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    });
+    if args.print_config {
+        for (key, value) in config.redacted().vars() {
+            println!("{}={}", key, value);
+        }
+        return Ok(());
+    }
 
     // Create tokio runtime with optional thread limit
     let runtime = if let Some(max_threads) = config.max_tokio_threads {
@@ -33,23 +109,23 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
             .build()?
     };
 
-    runtime.block_on(async_main())
+    runtime.block_on(async_main(config))
 }
 
-async fn async_main() -> Result<(), Box<dyn std::error::Error>> {
+async fn async_main(config: Config) -> Result<(), Box<dyn std::error::Error>> {
     // Initialize telemetry FIRST, before anything else
     init_telemetry()?;
 
     // Access the configuration values
-    let config = config::CONFIG.clone();
     let port = config.port;
     let bind = config.bind;
     let max_post_size = config.max_post_size;
-    let path = get_uri_from_config(config.clone());
+    let access_log_enabled = config.access_log;
+    let max_concurrent_handlers = config.max_concurrent_handlers;
+    let shutdown_delay = config.shutdown_delay;
+    let shutdown_drain_timeout = config.shutdown_drain_timeout;
 
     info!("Starting elevation service");
-    debug!("Cache Size: {}", config.cache_size);
-    debug!("Tile Set Path: {:?}", path);
     debug!("Max Post Size: {}", max_post_size);
     debug!("Port: {}", port);
     debug!("Bind Address: {:?}", bind);
@@ -62,67 +138,172 @@ async fn async_main() -> Result<(), Box<dyn std::error::Error>> {
         "Max Concurrent Handlers: {}",
         config.max_concurrent_handlers
     );
-    debug!("S3 Endpoint: {:?}", config.s3_endpoint);
-    debug!("S3 Bucket: {:?}", config.s3_bucket);
 
     // Create semaphore for limiting concurrent handlers
     let semaphore = Arc::new(Semaphore::new(config.max_concurrent_handlers));
 
-    let options = TileSetOptions {
-        path: path,
-        cache_size: config.cache_size,
-        gzip: true,
-        s3_access_key_id: config.s3_access_key_id.clone(),
-        s3_secret_access_key: config.s3_secret_access_key.clone(),
-        s3_region: config.s3_region.clone(),
-        s3_endpoint: config.s3_endpoint.clone(),
+    debug!("Default Dataset: {}", config.default_dataset);
+    debug!("Geoid Path: {:?}", config.geoid_path);
+    let datasets = Arc::new(Datasets::new(&config)?);
+    METRICS.observe_datasets(datasets.clone());
+    METRICS.observe_semaphore(semaphore.clone(), config.max_concurrent_handlers);
+
+    let geoid = match &config.geoid_path {
+        Some(path) => Some(Arc::new(Geoid::load(Path::new(path))?)),
+        None => None,
     };
-    let tileset = Arc::new(TileSetWithCache::new(options)?);
 
-    // Create a shared filter for tileset
-    let tileset_filter = warp::any().map(move || tileset.clone());
+    debug!("Warmup Datasets: {:?}", config.warmup_datasets);
+    debug!("Warmup Concurrency: {}", config.warmup_concurrency);
+    let warmup = Arc::new(Warmup::new(datasets.clone(), config.warmup_concurrency));
+    warmup.start(&config)?;
+
+    let tls = Tls::from_config(&config)?;
+    let tls_reload_interval = config.tls_reload_interval;
+    let api_keys = Arc::new(ApiKeys::from_config(&config)?);
+    api_keys.watch(config.api_keys_reload_interval);
+    let rate_limiter = Arc::new(RateLimiter::from_config(&config));
+    let trusted_proxies = config.trusted_proxies.clone();
+    let permits = Arc::new(HandlerPermits::new(
+        semaphore.clone(),
+        config.max_queue_time,
+    ));
+
+    // Create a shared filter for the datasets
+    let datasets_filter = warp::any().map(move || datasets.clone());
+    let geoid_filter = warp::any().map(move || geoid.clone());
+    let warmup_filter = warp::any().map(move || warmup.clone());
+    let shutdown = Arc::new(Shutdown::default());
+    let shutdown_filter = {
+        let shutdown = shutdown.clone();
+        warp::any().map(move || shutdown.clone())
+    };
     let config_filter = warp::any().map(move || config.clone());
-    let semaphore_filter = warp::any().map(move || semaphore.clone());
+    let caller_filter = api_keys::caller(api_keys, rate_limiter, trusted_proxies);
+    let permits_filter = warp::any().map(move || permits.clone());
 
-    // Define the /status route
+    // Define the health check routes, which do not wait for the handler semaphore
+    let livez_route = warp::path!("livez").and(warp::get()).and_then(get_livez);
+    let readyz_route = warp::path!("readyz")
+        .and(warp::get())
+        .and(datasets_filter.clone())
+        .and(warmup_filter.clone())
+        .and(shutdown_filter.clone())
+        .and(config_filter.clone())
+        .and_then(get_readyz);
     let status_route = warp::path("status")
         .and(warp::get())
-        .and(tileset_filter.clone())
-        .and(semaphore_filter.clone())
+        .and(datasets_filter.clone())
+        .and(warmup_filter.clone())
+        .and(shutdown_filter.clone())
+        .and(config_filter.clone())
         .and_then(get_status);
 
     // Define the GET route for elevation
     let get_elevation_route = warp::path::end()
         .and(warp::get())
         .and(warp::query::<LatLng>())
-        .and(tileset_filter.clone())
-        .and(semaphore_filter.clone())
+        .and(caller_filter.clone())
+        .and(datasets_filter.clone())
+        .and(geoid_filter.clone())
+        .and(permits_filter.clone())
         .and_then(get_elevation)
         .or(warp::path("api")
+            .and(warp::path::end())
             .and(warp::get())
             .and(warp::query::<LatLng>())
-            .and(tileset_filter.clone())
-            .and(semaphore_filter.clone())
+            .and(caller_filter.clone())
+            .and(datasets_filter.clone())
+            .and(geoid_filter.clone())
+            .and(permits_filter.clone())
             .and_then(get_elevation));
 
+    // Define the GET route for elevation with the dataset as path segment
+    let get_dataset_elevation_route = warp::path!("api" / "datasets" / String)
+        .and(warp::get())
+        .and(warp::query::<LatLng>())
+        .map(|dataset, query: LatLng| LatLng {
+            dataset: Some(dataset),
+            ..query
+        })
+        .and(caller_filter.clone())
+        .and(datasets_filter.clone())
+        .and(geoid_filter.clone())
+        .and(permits_filter.clone())
+        .and_then(get_elevation);
+
     // Define the POST route for elevations
     let post_elevation_route = warp::path::end()
         .and(warp::post())
+        .and(warp::query::<ElevationQuery>())
         .and(warp::body::content_length_limit(max_post_size.as_u64()))
         .and(warp::body::json::<LatLngs>())
-        .and(tileset_filter.clone())
+        .and(caller_filter.clone())
+        .and(datasets_filter.clone())
+        .and(geoid_filter.clone())
         .and(config_filter.clone())
-        .and(semaphore_filter.clone())
+        .and(permits_filter.clone())
         .and_then(post_elevations)
         .or(warp::path("api")
+            .and(warp::path::end())
             .and(warp::post())
+            .and(warp::query::<ElevationQuery>())
             .and(warp::body::content_length_limit(max_post_size.as_u64()))
             .and(warp::body::json::<LatLngs>())
-            .and(tileset_filter.clone())
+            .and(caller_filter.clone())
+            .and(datasets_filter.clone())
+            .and(geoid_filter.clone())
             .and(config_filter.clone())
-            .and(semaphore_filter.clone())
+            .and(permits_filter.clone())
             .and_then(post_elevations));
 
+    // Define the POST route for elevations with the dataset as path segment
+    let post_dataset_elevation_route = warp::path!("api" / "datasets" / String)
+        .and(warp::post())
+        .and(warp::query::<ElevationQuery>())
+        .map(|dataset, query: ElevationQuery| ElevationQuery {
+            dataset: Some(dataset),
+            ..query
+        })
+        .and(warp::body::content_length_limit(max_post_size.as_u64()))
+        .and(warp::body::json::<LatLngs>())
+        .and(caller_filter.clone())
+        .and(datasets_filter.clone())
+        .and(geoid_filter.clone())
+        .and(config_filter.clone())
+        .and(permits_filter.clone())
+        .and_then(post_elevations);
+
+    // Define the route listing the configured datasets
+    let datasets_route = warp::path!("api" / "datasets")
+        .and(warp::get())
+        .and(caller_filter.clone())
+        .and(datasets_filter.clone())
+        .and_then(list_datasets);
+
+    // Define the route returning the geoid undulation
+    let geoid_route = warp::path!("api" / "geoid")
+        .and(warp::get())
+        .and(warp::query::<GeoidQuery>())
+        .and(caller_filter.clone())
+        .and(geoid_filter.clone())
+        .and_then(get_geoid_undulation);
+
+    // Define the admin route loading regions into the tile caches
+    let warmup_route = warp::path!("api" / "admin" / "warmup")
+        .and(warp::post())
+        .and(warp::header::optional::<String>("authorization"))
+        .and(warp::body::content_length_limit(max_post_size.as_u64()))
+        .and(warp::body::json::<WarmupRequest>())
+        .and(warmup_filter.clone())
+        .and(config_filter.clone())
+        .and_then(post_warmup);
+
+    // Define the route exporting metrics in the Prometheus text format
+    let metrics_route = warp::path!("metrics")
+        .and(warp::get())
+        .and_then(get_metrics);
+
     // Define OPTIONS route to handle CORS preflight requests
     let options_route = warp::options()
         .and(warp::path::full())
@@ -131,28 +312,102 @@ async fn async_main() -> Result<(), Box<dyn std::error::Error>> {
     // Create CORS configuration
     let cors = warp::cors()
         .allow_any_origin()
-        .allow_headers(vec!["Content-Type", "Authorization"])
+        .allow_headers(vec!["Content-Type", "Authorization", "X-API-Key"])
         .allow_methods(vec!["GET", "POST", "OPTIONS"]);
 
-    // Combine routes and apply CORS
+    // Combine routes and apply CORS. The client address is recorded for the access
+    // log first, warp itself does not know it for TLS connections.
     let routes = warp::any()
+        .and(tls::remote_addr())
+        .map(|addr: Option<std::net::SocketAddr>| {
+            if let Some(addr) = addr {
+                record_remote_addr(addr);
+            }
+        })
+        .untuple_one()
         .and(
-            status_route
+            livez_route
+                .or(readyz_route)
+                .or(status_route)
                 .or(get_elevation_route)
                 .or(post_elevation_route)
+                .or(datasets_route)
+                .or(geoid_route)
+                .or(warmup_route)
+                .or(metrics_route)
+                .or(get_dataset_elevation_route)
+                .or(post_dataset_elevation_route)
                 .or(options_route),
         )
-        .with(warp::log("elevation-service"))
+        // The access log is written within the request span to carry its trace ID
+        .with(warp::log::custom(move |info| {
+            if access_log_enabled {
+                access_log(info)
+            }
+        }))
+        // Handler spans are children of the request span, which continues the caller's trace
+        .with(warp::trace(|info| {
+            let span = info_span!(
+                "request",
+                otel.kind = "server",
+                http.method = %info.method(),
+                http.target = info.path(),
+            );
+            span.set_parent(extract_context(info.request_headers()));
+            span
+        }))
+        .with(warp::log::custom(|info| {
+            METRICS.record_request(
+                info.method().as_str(),
+                info.path(),
+                info.status().as_u16(),
+                info.elapsed(),
+            )
+        }))
         .with(cors);
 
-    // Start the server
-    info!("Starting server on {}:{}", bind, port);
-    warp::serve(routes).run((bind, port)).await;
+    // Start the server, it stops accepting connections once `stop` is sent
+    let (stop, stopped) = tokio::sync::oneshot::channel::<()>();
+    let stopped = async {
+        stopped.await.ok();
+    };
+    let server = match tls {
+        Some(tls) => {
+            info!("Starting server on {}:{} with TLS", bind, port);
+            tls.watch(tls_reload_interval);
+            let listener = tokio::net::TcpListener::bind((bind, port)).await?;
+            let server = tls::serve(warp::service(routes), tls.incoming(listener), stopped);
+            tokio::spawn(async move {
+                if let Err(e) = server.await {
+                    error!(error = %e, "Server failed");
+                }
+            })
+        }
+        None => {
+            info!("Starting server on {}:{}", bind, port);
+            let (_, server) =
+                warp::serve(routes).try_bind_with_graceful_shutdown((bind, port), stopped)?;
+            tokio::spawn(server)
+        }
+    };
 
-    info!("Server shutting down");
+    // Fail readiness first, so load balancers stop sending requests before the
+    // listener closes, then let the active handlers finish
+    shutdown::signal().await;
+    info!(delay = ?shutdown_delay, "Server shutting down, failing readiness");
+    shutdown.begin();
+    tokio::time::sleep(shutdown_delay).await;
+    stop.send(()).ok();
+    let deadline = tokio::time::Instant::now() + shutdown_drain_timeout;
+    if shutdown::drain(&semaphore, max_concurrent_handlers, shutdown_drain_timeout).await
+        && tokio::time::timeout_at(deadline, server).await.is_err()
+    {
+        warn!("Drain timeout passed with open connections");
+    }
+    info!("Server stopped");
 
     // Shutdown telemetry
-    global::shutdown_tracer_provider();
+    shutdown_telemetry();
 
     Ok(())
 }
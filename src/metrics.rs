@@ -0,0 +1,433 @@
+use once_cell::sync::Lazy;
+use opentelemetry::{
+    KeyValue,
+    metrics::{Counter, Histogram, MeterProvider as _, Result as MetricsResult},
+};
+use opentelemetry_sdk::{
+    Resource,
+    metrics::{
+        Aggregation, Instrument, InstrumentKind, ManualReader, MeterProvider, PeriodicReader,
+        Pipeline, Stream,
+        data::{self, ResourceMetrics, Temporality},
+        new_view,
+        reader::{AggregationSelector, MetricReader, TemporalitySelector},
+    },
+};
+use std::fmt::Write;
+use std::sync::{Arc, Weak};
+use std::time::Duration;
+use tokio::sync::Semaphore;
+
+use crate::datasets::Datasets;
+use crate::telemetry;
+
+/// Bucket bounds of latency histograms, in seconds.
+const LATENCY_BUCKETS: [f64; 14] = [
+    0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+/// Bucket bounds of the number of points in batch requests.
+const BATCH_BUCKETS: [f64; 9] = [
+    1.0, 10.0, 50.0, 100.0, 500.0, 1000.0, 5000.0, 10000.0, 50000.0,
+];
+
+/// Routes reported as the `route` label, anything else is reported as `other`.
+const ROUTES: [&str; 9] = [
+    "/",
+    "/api",
+    "/api/datasets",
+    "/api/geoid",
+    "/api/admin/warmup",
+    "/livez",
+    "/readyz",
+    "/status",
+    "/metrics",
+];
+
+pub static METRICS: Lazy<Metrics> = Lazy::new(|| Metrics::new(telemetry::otlp_metrics_reader()));
+
+/// Instruments of the service, exported in the Prometheus text format on `/metrics`
+/// and, if configured, pushed over OTLP.
+pub struct Metrics {
+    provider: MeterProvider,
+    reader: Arc<ManualReader>,
+    pub requests: Counter<u64>,
+    pub request_duration: Histogram<f64>,
+    pub batch_points: Histogram<u64>,
+    pub cache_hits: Counter<u64>,
+    pub cache_misses: Counter<u64>,
+    pub cache_evictions: Counter<u64>,
+    pub missing_tile_cache_hits: Counter<u64>,
+    pub backend_duration: Histogram<f64>,
+    pub backend_errors: Counter<u64>,
+    pub decompression_duration: Histogram<f64>,
+    pub semaphore_wait: Histogram<f64>,
+    pub api_key_requests: Counter<u64>,
+    pub api_key_points: Counter<u64>,
+    pub rate_limited_requests: Counter<u64>,
+    pub shed_requests: Counter<u64>,
+}
+
+impl Metrics {
+    pub(crate) fn new(otlp: Option<PeriodicReader>) -> Self {
+        let reader = Arc::new(ManualReader::builder().build());
+        let mut builder = MeterProvider::builder()
+            .with_resource(telemetry::resource())
+            .with_reader(SharedReader(reader.clone()))
+            .with_view(histogram_view("*_seconds", &LATENCY_BUCKETS))
+            .with_view(histogram_view("batch_points", &BATCH_BUCKETS));
+        if let Some(otlp) = otlp {
+            builder = builder.with_reader(otlp);
+        }
+        let provider = builder.build();
+        let meter = provider.meter("elevation-service");
+
+        Self {
+            requests: meter
+                .u64_counter("http_requests")
+                .with_description("HTTP requests by route, method and status")
+                .init(),
+            request_duration: meter
+                .f64_histogram("http_request_duration_seconds")
+                .with_description("Duration of HTTP requests by route")
+                .init(),
+            batch_points: meter
+                .u64_histogram("batch_points")
+                .with_description("Number of points in batch requests")
+                .init(),
+            cache_hits: meter
+                .u64_counter("tile_cache_hits")
+                .with_description("Tile lookups answered from the in-memory cache")
+                .init(),
+            cache_misses: meter
+                .u64_counter("tile_cache_misses")
+                .with_description("Tile lookups that had to load the tile")
+                .init(),
+            cache_evictions: meter
+                .u64_counter("tile_cache_evictions")
+                .with_description("Tiles evicted from the in-memory cache")
+                .init(),
+            missing_tile_cache_hits: meter
+                .u64_counter("missing_tile_cache_hits")
+                .with_description("Tile lookups answered from the cache of missing tiles")
+                .init(),
+            backend_duration: meter
+                .f64_histogram("backend_fetch_duration_seconds")
+                .with_description("Duration of tile fetches by backend type")
+                .init(),
+            backend_errors: meter
+                .u64_counter("backend_fetch_errors")
+                .with_description("Failed tile fetches by backend type and error kind")
+                .init(),
+            decompression_duration: meter
+                .f64_histogram("tile_decompression_duration_seconds")
+                .with_description("Duration of gzip decompression of tiles")
+                .init(),
+            semaphore_wait: meter
+                .f64_histogram("handler_semaphore_wait_seconds")
+                .with_description("Time requests waited for a concurrent handler permit")
+                .init(),
+            api_key_requests: meter
+                .u64_counter("api_key_requests")
+                .with_description("Requests checked against the API keys by key and outcome")
+                .init(),
+            api_key_points: meter
+                .u64_counter("api_key_points")
+                .with_description("Points looked up by key")
+                .init(),
+            rate_limited_requests: meter
+                .u64_counter("rate_limited_requests")
+                .with_description("Requests refused by the rate limiter by key type")
+                .init(),
+            shed_requests: meter
+                .u64_counter("shed_requests")
+                .with_description("Requests refused after waiting too long for a handler permit")
+                .init(),
+            provider,
+            reader,
+        }
+    }
+
+    /// Reports the size of the tile caches of all datasets when metrics are collected.
+    pub fn observe_datasets(&self, datasets: Arc<Datasets>) {
+        let meter = self.provider.meter("elevation-service");
+        let tiles_datasets = datasets.clone();
+        meter
+            .u64_observable_gauge("tile_cache_tiles")
+            .with_description("Tiles held in the in-memory cache")
+            .with_callback(move |observer| {
+                for dataset in tiles_datasets.info() {
+                    for layer in dataset.layers {
+                        observer.observe(
+                            layer.cached_tiles,
+                            &cache_labels(&dataset.name, &layer.name),
+                        );
+                    }
+                }
+            })
+            .init();
+        meter
+            .u64_observable_gauge("tile_cache_bytes")
+            .with_description("Memory used by the in-memory tile cache")
+            .with_callback(move |observer| {
+                for dataset in datasets.info() {
+                    for layer in dataset.layers {
+                        observer
+                            .observe(layer.cache_bytes, &cache_labels(&dataset.name, &layer.name));
+                    }
+                }
+            })
+            .init();
+    }
+
+    /// Reports the available permits of the concurrent handler semaphore.
+    pub fn observe_semaphore(&self, semaphore: Arc<Semaphore>, permits: usize) {
+        let meter = self.provider.meter("elevation-service");
+        meter
+            .u64_observable_gauge("handler_semaphore_available")
+            .with_description("Concurrent handler permits currently available")
+            .with_callback(move |observer| {
+                observer.observe(semaphore.available_permits() as u64, &[]);
+            })
+            .init();
+        meter
+            .u64_observable_gauge("handler_semaphore_permits")
+            .with_description("Configured number of concurrent handler permits")
+            .with_callback(move |observer| observer.observe(permits as u64, &[]))
+            .init();
+    }
+
+    pub fn record_request(&self, method: &str, path: &str, status: u16, elapsed: Duration) {
+        let route = route_label(path);
+        self.requests.add(
+            1,
+            &[
+                KeyValue::new("route", route),
+                KeyValue::new("method", method.to_string()),
+                KeyValue::new("status", status.to_string()),
+            ],
+        );
+        self.request_duration
+            .record(elapsed.as_secs_f64(), &[KeyValue::new("route", route)]);
+    }
+
+    /// Exports pending metrics to the OTLP collector, if configured, and stops the export.
+    pub fn shutdown(&self) {
+        // The periodic reader refuses to collect once it is shutting down, so flush first
+        if let Err(e) = self
+            .provider
+            .force_flush()
+            .and_then(|_| self.provider.shutdown())
+        {
+            tracing::warn!(error = %e, "Failed to shut down metrics export");
+        }
+    }
+
+    /// Collects all instruments and renders them in the Prometheus text format.
+    pub fn render(&self) -> MetricsResult<String> {
+        let mut metrics = ResourceMetrics {
+            resource: Resource::empty(),
+            scope_metrics: Vec::new(),
+        };
+        self.reader.collect(&mut metrics)?;
+
+        let mut output = String::new();
+        for metric in metrics
+            .scope_metrics
+            .iter()
+            .flat_map(|scope| &scope.metrics)
+        {
+            render_metric(&mut output, metric);
+        }
+        Ok(output)
+    }
+}
+
+fn cache_labels(dataset: &str, layer: &str) -> [KeyValue; 2] {
+    [
+        KeyValue::new("dataset", dataset.to_string()),
+        KeyValue::new("layer", layer.to_string()),
+    ]
+}
+
+/// Maps request paths to a fixed set of routes, so that the label stays bounded.
+fn route_label(path: &str) -> &'static str {
+    if path.starts_with("/api/datasets/") {
+        return "/api/datasets/{dataset}";
+    }
+    ROUTES
+        .into_iter()
+        .find(|route| *route == path)
+        .unwrap_or("other")
+}
+
+fn histogram_view(
+    name: &'static str,
+    boundaries: &[f64],
+) -> Box<dyn opentelemetry_sdk::metrics::View> {
+    new_view(
+        Instrument::new().name(name),
+        Stream::new().aggregation(Aggregation::ExplicitBucketHistogram {
+            boundaries: boundaries.to_vec(),
+            record_min_max: false,
+        }),
+    )
+    .expect("Histogram views are valid")
+}
+
+fn render_metric(output: &mut String, metric: &data::Metric) {
+    let data = metric.data.as_any();
+    let name = &metric.name;
+    if let Some(sum) = data.downcast_ref::<data::Sum<u64>>() {
+        render_sum(output, metric, sum.is_monotonic, &sum.data_points);
+    } else if let Some(sum) = data.downcast_ref::<data::Sum<i64>>() {
+        render_sum(output, metric, sum.is_monotonic, &sum.data_points);
+    } else if let Some(sum) = data.downcast_ref::<data::Sum<f64>>() {
+        render_sum(output, metric, sum.is_monotonic, &sum.data_points);
+    } else if let Some(gauge) = data.downcast_ref::<data::Gauge<u64>>() {
+        render_sum(output, metric, false, &gauge.data_points);
+    } else if let Some(gauge) = data.downcast_ref::<data::Gauge<i64>>() {
+        render_sum(output, metric, false, &gauge.data_points);
+    } else if let Some(gauge) = data.downcast_ref::<data::Gauge<f64>>() {
+        render_sum(output, metric, false, &gauge.data_points);
+    } else if let Some(histogram) = data.downcast_ref::<data::Histogram<u64>>() {
+        render_histogram(output, metric, &histogram.data_points);
+    } else if let Some(histogram) = data.downcast_ref::<data::Histogram<f64>>() {
+        render_histogram(output, metric, &histogram.data_points);
+    } else {
+        tracing::warn!(%name, "Metric type not supported by the Prometheus export");
+    }
+}
+
+fn render_header(output: &mut String, name: &str, description: &str, kind: &str) {
+    let _ = writeln!(output, "# HELP {} {}", name, description);
+    let _ = writeln!(output, "# TYPE {} {}", name, kind);
+}
+
+fn render_sum<T: ToString>(
+    output: &mut String,
+    metric: &data::Metric,
+    monotonic: bool,
+    points: &[data::DataPoint<T>],
+) {
+    let (name, kind) = match monotonic {
+        true => (format!("{}_total", metric.name), "counter"),
+        false => (metric.name.to_string(), "gauge"),
+    };
+    render_header(output, &name, &metric.description, kind);
+    for point in points {
+        let labels = labels(point.attributes.iter(), None);
+        let _ = writeln!(output, "{}{} {}", name, labels, point.value.to_string());
+    }
+}
+
+fn render_histogram<T: ToString>(
+    output: &mut String,
+    metric: &data::Metric,
+    points: &[data::HistogramDataPoint<T>],
+) {
+    let name = &metric.name;
+    render_header(output, name, &metric.description, "histogram");
+    for point in points {
+        let mut cumulative = 0;
+        for (bound, count) in point.bounds.iter().zip(&point.bucket_counts) {
+            cumulative += count;
+            let labels = labels(point.attributes.iter(), Some(&bound.to_string()));
+            let _ = writeln!(output, "{}_bucket{} {}", name, labels, cumulative);
+        }
+        let labels_inf = labels(point.attributes.iter(), Some("+Inf"));
+        let _ = writeln!(output, "{}_bucket{} {}", name, labels_inf, point.count);
+        let labels = labels(point.attributes.iter(), None);
+        let _ = writeln!(output, "{}_sum{} {}", name, labels, point.sum.to_string());
+        let _ = writeln!(output, "{}_count{} {}", name, labels, point.count);
+    }
+}
+
+/// Formats the attributes of a data point as Prometheus labels, with an optional `le` label.
+fn labels<'a>(
+    attributes: impl Iterator<Item = (&'a opentelemetry::Key, &'a opentelemetry::Value)>,
+    le: Option<&str>,
+) -> String {
+    let mut labels = attributes
+        .map(|(key, value)| format!("{}=\"{}\"", key, escape(&value.as_str())))
+        .collect::<Vec<_>>();
+    if let Some(le) = le {
+        labels.push(format!("le=\"{}\"", le));
+    }
+    match labels.is_empty() {
+        true => String::new(),
+        false => format!("{{{}}}", labels.join(",")),
+    }
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Lets the meter provider own the reader while `/metrics` collects from it as well.
+#[derive(Debug)]
+struct SharedReader(Arc<ManualReader>);
+
+impl TemporalitySelector for SharedReader {
+    fn temporality(&self, kind: InstrumentKind) -> Temporality {
+        self.0.temporality(kind)
+    }
+}
+
+impl AggregationSelector for SharedReader {
+    fn aggregation(&self, kind: InstrumentKind) -> Aggregation {
+        self.0.aggregation(kind)
+    }
+}
+
+impl MetricReader for SharedReader {
+    fn register_pipeline(&self, pipeline: Weak<Pipeline>) {
+        self.0.register_pipeline(pipeline)
+    }
+
+    fn collect(&self, metrics: &mut ResourceMetrics) -> MetricsResult<()> {
+        self.0.collect(metrics)
+    }
+
+    fn force_flush(&self) -> MetricsResult<()> {
+        self.0.force_flush()
+    }
+
+    fn shutdown(&self) -> MetricsResult<()> {
+        self.0.shutdown()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render_prometheus_text() {
+        let metrics = Metrics::new(None);
+        metrics.record_request("GET", "/api", 200, Duration::from_millis(3));
+        metrics.record_request("GET", "/api/datasets/srtm", 404, Duration::from_millis(1));
+        metrics.record_request("GET", "/wp-login.php", 404, Duration::from_millis(1));
+        metrics.batch_points.record(250, &[]);
+
+        let output = metrics.render().unwrap();
+        assert!(output.contains("# TYPE http_requests_total counter"));
+        assert!(output.contains(r#"route="/api""#));
+        assert!(output.contains(r#"route="/api/datasets/{dataset}""#));
+        assert!(output.contains(r#"route="other""#));
+        assert!(output.contains("# TYPE http_request_duration_seconds histogram"));
+        assert!(
+            output.contains(r#"http_request_duration_seconds_bucket{route="/api",le="0.005"} 1"#)
+        );
+        assert!(output.contains(r#"batch_points_bucket{le="100"} 0"#));
+        assert!(output.contains(r#"batch_points_bucket{le="500"} 1"#));
+        assert!(output.contains("batch_points_count 1"));
+    }
+
+    #[test]
+    fn test_escape_label_values() {
+        assert_eq!(escape("a\"b\\c\nd"), "a\\\"b\\\\c\\nd");
+    }
+}
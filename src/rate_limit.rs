@@ -0,0 +1,275 @@
+use opentelemetry::KeyValue;
+use std::{
+    collections::HashMap,
+    convert::Infallible,
+    net::{IpAddr, SocketAddr},
+    str::FromStr,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+use tracing::debug;
+use warp::Filter;
+
+use crate::{api_keys::AuthError, config::Config, metrics::METRICS, tls};
+
+/// Interval in which buckets that have filled up again are dropped.
+const CLEANUP_INTERVAL: Duration = Duration::from_secs(60);
+
+/// An IP address range in CIDR notation, e.g. `10.0.0.0/8`. A single address
+/// stands for itself.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct IpNet {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl IpNet {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, ip.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                prefix_matches(&net.octets(), &ip.octets(), self.prefix)
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                prefix_matches(&net.octets(), &ip.octets(), self.prefix)
+            }
+            _ => false,
+        }
+    }
+}
+
+fn prefix_matches(net: &[u8], ip: &[u8], prefix: u8) -> bool {
+    net.iter().zip(ip).enumerate().all(|(i, (net, ip))| {
+        let bits = (prefix as usize).saturating_sub(i * 8).min(8);
+        let mask = (!0u8).checked_shl(8 - bits as u32).unwrap_or(0);
+        net & mask == ip & mask
+    })
+}
+
+impl FromStr for IpNet {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("Invalid IP address or CIDR range '{}'", s);
+        let (addr, prefix) = match s.trim().split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s.trim(), None),
+        };
+        let addr = IpAddr::from_str(addr).map_err(|_| invalid())?;
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix.parse::<u8>().map_err(|_| invalid())?,
+            None => max,
+        };
+        if prefix > max {
+            return Err(invalid());
+        }
+        Ok(Self { addr, prefix })
+    }
+}
+
+impl std::fmt::Display for IpNet {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix)
+    }
+}
+
+/// Returns the address of the client, taken from `X-Forwarded-For` if the request
+/// comes from a trusted proxy. The header is read from the right, skipping the
+/// trusted proxies the request passed through.
+pub fn client_ip(
+    remote_addr: Option<SocketAddr>,
+    forwarded_for: Option<&str>,
+    trusted_proxies: &[IpNet],
+) -> Option<IpAddr> {
+    let trusted = |ip: IpAddr| trusted_proxies.iter().any(|net| net.contains(ip));
+    let remote_ip = remote_addr?.ip().to_canonical();
+    if !trusted(remote_ip) {
+        return Some(remote_ip);
+    }
+    let mut client = remote_ip;
+    for hop in forwarded_for.unwrap_or_default().rsplit(',') {
+        match IpAddr::from_str(hop.trim()) {
+            Ok(ip) => {
+                client = ip.to_canonical();
+                if !trusted(client) {
+                    break;
+                }
+            }
+            Err(_) => break,
+        }
+    }
+    Some(client)
+}
+
+/// Extracts the client address of a request, see [`client_ip`].
+pub fn client(
+    trusted_proxies: Vec<IpNet>,
+) -> impl Filter<Extract = (Option<IpAddr>,), Error = Infallible> + Clone {
+    tls::remote_addr()
+        .and(
+            warp::header::optional::<String>("x-forwarded-for")
+                .or(warp::any().map(|| None))
+                .unify(),
+        )
+        .map(
+            move |remote_addr: Option<SocketAddr>, forwarded_for: Option<String>| {
+                client_ip(remote_addr, forwarded_for.as_deref(), &trusted_proxies)
+            },
+        )
+}
+
+/// What a bucket of the rate limiter belongs to.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum BucketKey {
+    ApiKey(String),
+    Ip(IpAddr),
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// Token bucket rate limiting per client. Each request costs one token per point it
+/// looks up, at least one.
+pub struct RateLimiter {
+    /// Tokens added per second, `None` disables rate limiting
+    rate: Option<f64>,
+    burst: f64,
+    buckets: Mutex<(HashMap<BucketKey, Bucket>, Instant)>,
+}
+
+impl RateLimiter {
+    pub fn new(rate: Option<f64>, burst: f64) -> Self {
+        Self {
+            rate: rate.filter(|rate| *rate > 0.0),
+            burst: burst.max(1.0),
+            buckets: Mutex::new((HashMap::new(), Instant::now())),
+        }
+    }
+
+    pub fn from_config(config: &Config) -> Self {
+        let burst = config
+            .rate_limit_burst
+            .or(config.rate_limit_per_second.map(|rate| rate * 10.0))
+            .unwrap_or_default();
+        Self::new(config.rate_limit_per_second, burst)
+    }
+
+    /// Takes the tokens of a request looking up `points` points. Batches larger
+    /// than the burst size pass once the bucket is full and leave it in debt, so
+    /// the caller waits until all their points are paid for.
+    pub fn check(&self, key: BucketKey, points: usize) -> Result<(), AuthError> {
+        let Some(rate) = self.rate else {
+            return Ok(());
+        };
+        let cost = points.max(1) as f64;
+        let required = cost.min(self.burst);
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap_or_else(|e| e.into_inner());
+        let (buckets, cleaned) = &mut *buckets;
+        if now.duration_since(*cleaned) >= CLEANUP_INTERVAL {
+            let burst = self.burst;
+            buckets.retain(|_, bucket| {
+                bucket.tokens + now.duration_since(bucket.updated).as_secs_f64() * rate < burst
+            });
+            *cleaned = now;
+        }
+
+        let bucket = buckets.entry(key.clone()).or_insert(Bucket {
+            tokens: self.burst,
+            updated: now,
+        });
+        bucket.tokens = (bucket.tokens + now.duration_since(bucket.updated).as_secs_f64() * rate)
+            .min(self.burst);
+        bucket.updated = now;
+        if bucket.tokens >= required {
+            bucket.tokens -= cost;
+            return Ok(());
+        }
+
+        let key_type = match key {
+            BucketKey::ApiKey(_) => "api_key",
+            BucketKey::Ip(_) => "ip",
+        };
+        debug!(?key, cost, tokens = bucket.tokens, "Rate limited request");
+        METRICS
+            .rate_limited_requests
+            .add(1, &[KeyValue::new("key_type", key_type)]);
+        Err(AuthError::TooManyRequests {
+            message: String::from("Rate limit exceeded"),
+            retry_after: ((required - bucket.tokens) / rate).ceil() as u64,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_client_ip_behind_trusted_proxies() {
+        let proxies = ["10.0.0.0/8".parse().unwrap(), "::1".parse().unwrap()];
+        let remote = |addr: &str| Some(SocketAddr::new(addr.parse().unwrap(), 40000));
+        let ip = |addr: &str| Some(addr.parse::<IpAddr>().unwrap());
+
+        // Direct clients cannot spoof their address
+        assert_eq!(
+            client_ip(remote("203.0.113.7"), Some("198.51.100.1"), &proxies),
+            ip("203.0.113.7")
+        );
+        assert_eq!(
+            client_ip(
+                remote("10.1.2.3"),
+                Some("198.51.100.1, 203.0.113.7, 10.0.0.5"),
+                &proxies
+            ),
+            ip("203.0.113.7")
+        );
+        assert_eq!(
+            client_ip(remote("::ffff:10.1.2.3"), Some("203.0.113.7"), &proxies),
+            ip("203.0.113.7")
+        );
+        assert_eq!(client_ip(remote("::1"), None, &proxies), ip("::1"));
+        assert_eq!(client_ip(None, Some("203.0.113.7"), &proxies), None);
+
+        assert!("10.0.0.0/33".parse::<IpNet>().is_err());
+        assert!(
+            "fd00::/8"
+                .parse::<IpNet>()
+                .unwrap()
+                .contains(ip("fd12::1").unwrap())
+        );
+        assert!(
+            !"fd00::/8"
+                .parse::<IpNet>()
+                .unwrap()
+                .contains(ip("fe80::1").unwrap())
+        );
+    }
+
+    #[test]
+    fn test_token_bucket_weighs_points() {
+        let limiter = RateLimiter::new(Some(1.0), 100.0);
+        let client = BucketKey::Ip("203.0.113.7".parse().unwrap());
+        let other = BucketKey::ApiKey(String::from("app"));
+
+        assert_eq!(limiter.check(client.clone(), 60), Ok(()));
+        assert_eq!(limiter.check(client.clone(), 40), Ok(()));
+        match limiter.check(client.clone(), 0) {
+            Err(AuthError::TooManyRequests { retry_after, .. }) => assert_eq!(retry_after, 1),
+            other => panic!("Expected the bucket to be empty, got {:?}", other),
+        }
+        // Batches above the burst size pass with a full bucket, but pay for every point
+        assert_eq!(limiter.check(other.clone(), 5000), Ok(()));
+        match limiter.check(other, 1) {
+            Err(AuthError::TooManyRequests { retry_after, .. }) => {
+                assert!((4900..=4901).contains(&retry_after))
+            }
+            other => panic!("Expected the bucket to be in debt, got {:?}", other),
+        }
+
+        assert_eq!(RateLimiter::new(None, 0.0).check(client, 1_000_000), Ok(()));
+    }
+}
@@ -0,0 +1,84 @@
+use std::{
+    sync::atomic::{AtomicBool, Ordering},
+    time::Duration,
+};
+use tokio::sync::Semaphore;
+use tracing::{info, warn};
+
+/// Shutdown state shared with the health checks, so readiness fails while draining.
+#[derive(Default)]
+pub struct Shutdown {
+    draining: AtomicBool,
+}
+
+impl Shutdown {
+    /// Returns true once a shutdown has been requested.
+    pub fn is_draining(&self) -> bool {
+        self.draining.load(Ordering::Acquire)
+    }
+
+    pub fn begin(&self) {
+        self.draining.store(true, Ordering::Release);
+    }
+}
+
+/// Waits for SIGTERM or SIGINT.
+pub async fn signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{SignalKind, signal};
+
+        let mut terminate =
+            signal(SignalKind::terminate()).expect("SIGTERM handler can be installed");
+        tokio::select! {
+            _ = terminate.recv() => info!("Received SIGTERM"),
+            _ = tokio::signal::ctrl_c() => info!("Received SIGINT"),
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        tokio::signal::ctrl_c()
+            .await
+            .expect("Ctrl-C handler can be installed");
+        info!("Received Ctrl-C");
+    }
+}
+
+/// Waits until all handlers have returned their permits, at most `timeout`.
+/// Returns false if handlers were still running when the timeout passed.
+pub async fn drain(semaphore: &Semaphore, permits: usize, timeout: Duration) -> bool {
+    let active = permits.saturating_sub(semaphore.available_permits());
+    if active > 0 {
+        info!(active, ?timeout, "Waiting for active handlers");
+    }
+    let permits = u32::try_from(permits).unwrap_or(u32::MAX);
+    match tokio::time::timeout(timeout, semaphore.acquire_many(permits)).await {
+        Ok(_) => true,
+        Err(_) => {
+            warn!(
+                active = (permits as usize).saturating_sub(semaphore.available_permits()),
+                "Drain timeout passed with active handlers"
+            );
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn test_drain_waits_for_active_handlers() {
+        let semaphore = Arc::new(Semaphore::new(4));
+        let permit = semaphore.clone().acquire_owned().await.unwrap();
+        assert!(!drain(&semaphore, 4, Duration::from_millis(20)).await);
+
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            drop(permit);
+        });
+        assert!(drain(&semaphore, 4, Duration::from_secs(5)).await);
+    }
+}
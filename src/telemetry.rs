@@ -1,24 +1,79 @@
-use opentelemetry::trace::TracerProvider as _;
-use opentelemetry_otlp::WithExportConfig;
-use opentelemetry_sdk::{runtime::Tokio, trace::Sampler};
+use crate::metrics::METRICS;
+use logging::{JsonFormat, LogFormat};
+use opentelemetry::{
+    Key,
+    logs::{AnyValue, LogRecord, Logger as _, LoggerProvider as _, Severity},
+    metrics::MetricsError,
+    trace::{SpanContext, TraceContextExt, TraceFlags, TraceState, TracerProvider as _},
+};
+use opentelemetry_otlp::{
+    HttpExporterBuilder, LogExporterBuilder, MetricsExporterBuilder, SpanExporterBuilder,
+    TonicExporterBuilder, WithExportConfig,
+};
+use opentelemetry_sdk::{
+    logs::{Logger, LoggerProvider},
+    metrics::{
+        PeriodicReader,
+        reader::{DefaultAggregationSelector, DefaultTemporalitySelector},
+    },
+    runtime::Tokio,
+    trace::Sampler,
+};
 use opentelemetry_stdout as stdout;
-use std::time::Duration;
-use tracing::{info, warn};
-use tracing_opentelemetry::OpenTelemetryLayer;
-use tracing_subscriber::{EnvFilter, fmt::format::FmtSpan, prelude::*};
+use std::time::{Duration, SystemTime};
+use tonic::metadata::{MetadataKey, MetadataMap, MetadataValue};
+use tracing::{Event, Metadata, Subscriber, field::Field, info, warn};
+use tracing_opentelemetry::{OpenTelemetryLayer, OtelData};
+use tracing_subscriber::{
+    EnvFilter, Layer,
+    fmt::format::FmtSpan,
+    layer::{Context, Filter},
+    prelude::*,
+    registry::LookupSpan,
+};
+
+mod logging;
+mod propagation;
+
+pub use logging::{access_log, record_remote_addr, record_request_points};
+pub use propagation::{extract_context, inject_context};
+
+/// Collector endpoints used when only signal-specific endpoints are configured.
+const DEFAULT_OTLP_ENDPOINT: &str = "http://localhost:4318";
+const DEFAULT_OTLP_GRPC_ENDPOINT: &str = "http://localhost:4317";
+/// Spans exported below each root span unless `OTEL_TRACES_MAX_CHILD_SPANS` is set.
+const DEFAULT_MAX_CHILD_SPANS: usize = 1000;
+/// Targets whose events are not exported as logs, as exporting them would cause more of them.
+const EXPORTER_TARGETS: [&str; 5] = ["opentelemetry", "hyper", "h2", "reqwest", "tower"];
 
 pub fn init_telemetry() -> Result<(), Box<dyn std::error::Error>> {
     // Get service name from environment or use default
     let service_name =
         std::env::var("SERVICE_NAME").unwrap_or_else(|_| "elevation-service".to_string());
 
+    // Console output as text or JSON lines
+    let log_format = LogFormat::from_env()?;
+
     // Check for debug mode - prints traces to console
     let debug_traces = std::env::var("OTEL_DEBUG_TRACES").is_ok();
 
-    // Check if any OTLP endpoint is configured (general or signal-specific)
-    let general_endpoint = std::env::var("OTEL_EXPORTER_OTLP_ENDPOINT").ok();
+    // Context of incoming requests is extracted and passed on to backend requests
+    opentelemetry::global::set_text_map_propagator(propagation::propagator(
+        std::env::var("OTEL_PROPAGATORS").ok().as_deref(),
+    )?);
+
+    // Legacy trace collector URL, used instead of the OTLP endpoint variables
     let traces_endpoint = std::env::var("OTEL_TRACES_COLLECTOR_URL").ok();
 
+    // Logs are bridged from tracing events, independently of the trace export
+    let log_layer = if otlp_signal_enabled("LOGS") {
+        let (layer, provider) = build_log_layer(&OtlpTarget::from_env("LOGS")?, resource())?;
+        opentelemetry::global::set_logger_provider(provider);
+        Some(layer)
+    } else {
+        None
+    };
+
     if debug_traces {
         // Debug mode: print traces to stdout
         let provider = opentelemetry_sdk::trace::TracerProvider::builder()
@@ -38,31 +93,30 @@ pub fn init_telemetry() -> Result<(), Box<dyn std::error::Error>> {
         // Set as global provider so it doesn't get dropped
         opentelemetry::global::set_tracer_provider(provider);
 
-        let telemetry_layer = OpenTelemetryLayer::new(tracer);
+        let telemetry_layer = OpenTelemetryLayer::new(tracer).with_filter(SpanCap::from_env()?);
 
         tracing_subscriber::registry()
             .with(EnvFilter::from_default_env())
-            .with(tracing_subscriber::fmt::layer())
+            .with(fmt_layer(log_format, tracing_subscriber::fmt::layer()))
             .with(telemetry_layer)
+            .with(log_layer)
             .try_init()
             .map_err(|e| format!("Failed to initialize tracing: {}", e))?;
 
         warn!("OpenTelemetry DEBUG mode - traces printed to stdout");
-    } else if general_endpoint.is_some() || traces_endpoint.is_some() {
-        // Determine which endpoint to use for traces (signal-specific takes priority)
-        let trace_endpoint = traces_endpoint
-            .or_else(|| general_endpoint.clone())
-            .unwrap_or_else(|| "http://localhost:4318".to_string())
+    } else if otlp_signal_enabled("TRACES") || traces_endpoint.is_some() {
+        let mut target = OtlpTarget::from_env("TRACES")?;
+        if let Some(traces_endpoint) = traces_endpoint {
             // Remove /v1/traces suffix if present (HTTP exporter appends it automatically)
-            .trim_end_matches("/v1/traces")
-            .trim_end_matches('/')
-            .to_string();
+            target.endpoint = traces_endpoint
+                .trim_end_matches("/v1/traces")
+                .trim_end_matches('/')
+                .to_string();
+        }
 
         // Build the OTLP exporter
-        let exporter = opentelemetry_otlp::new_exporter()
-            .http()
-            .with_endpoint(&trace_endpoint)
-            .with_timeout(Duration::from_secs(10))
+        let exporter = target
+            .exporter::<SpanExporterBuilder>()?
             .build_span_exporter()?;
 
         // Configure batch processor with larger queue to handle high throughput
@@ -79,13 +133,11 @@ pub fn init_telemetry() -> Result<(), Box<dyn std::error::Error>> {
             .with_span_processor(batch_processor)
             .with_config(
                 opentelemetry_sdk::trace::config()
-                    .with_sampler(Sampler::AlwaysOn)
-                    .with_resource(opentelemetry_sdk::Resource::new(vec![
-                        opentelemetry_semantic_conventions::resource::SERVICE_NAME
-                            .string(service_name.clone()),
-                        opentelemetry_semantic_conventions::resource::SERVICE_VERSION
-                            .string(env!("CARGO_PKG_VERSION")),
-                    ])),
+                    .with_sampler(sampler(
+                        std::env::var("OTEL_TRACES_SAMPLER").ok().as_deref(),
+                        std::env::var("OTEL_TRACES_SAMPLER_ARG").ok().as_deref(),
+                    )?)
+                    .with_resource(resource()),
             )
             .build();
 
@@ -95,17 +147,18 @@ pub fn init_telemetry() -> Result<(), Box<dyn std::error::Error>> {
         opentelemetry::global::set_tracer_provider(provider);
 
         // Set up tracing subscriber with OpenTelemetry layer
-        let telemetry_layer = OpenTelemetryLayer::new(tracer);
+        let telemetry_layer = OpenTelemetryLayer::new(tracer).with_filter(SpanCap::from_env()?);
 
         tracing_subscriber::registry()
             .with(EnvFilter::from_default_env())
-            .with(tracing_subscriber::fmt::layer())
+            .with(fmt_layer(log_format, tracing_subscriber::fmt::layer()))
             .with(telemetry_layer)
+            .with(log_layer)
             .try_init()
             .map_err(|e| format!("Failed to initialize tracing with OpenTelemetry: {}", e))?;
 
         info!("OpenTelemetry tracing initialized successfully");
-        info!("Trace endpoint: {}/v1/traces", trace_endpoint);
+        info!(protocol = ?target.protocol, "Trace endpoint: {}", target.endpoint);
     } else {
         warn!("OTEL_EXPORTER_OTLP_ENDPOINT not set, falling back to basic tracing");
         let stdout_layer = tracing_subscriber::fmt::layer()
@@ -116,12 +169,638 @@ pub fn init_telemetry() -> Result<(), Box<dyn std::error::Error>> {
         // Fall back to basic tracing without OTLP export
         tracing_subscriber::registry()
             .with(EnvFilter::from_default_env())
-            .with(stdout_layer)
+            .with(fmt_layer(log_format, stdout_layer))
+            .with(log_layer)
             .try_init()
             .map_err(|e| format!("Failed to initialize basic tracing: {}", e))?;
 
-        info!("Basic tracing initialized (no OTLP export)");
+        info!("Basic tracing initialized (no OTLP trace export)");
+    }
+
+    // Instruments are created on first use, which starts the OTLP metrics export
+    once_cell::sync::Lazy::force(&METRICS);
+    if otlp_signal_enabled("LOGS") {
+        info!("OpenTelemetry log export initialized");
     }
 
     Ok(())
 }
+
+/// Console output in the format selected with `LOG_FORMAT`, `text` keeps its own settings.
+fn fmt_layer<S, L>(format: LogFormat, text: L) -> Box<dyn Layer<S> + Send + Sync>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    L: Layer<S> + Send + Sync + 'static,
+{
+    match format {
+        LogFormat::Text => Box::new(text),
+        LogFormat::Json => Box::new(tracing_subscriber::fmt::layer().event_format(JsonFormat)),
+    }
+}
+
+/// Stops the metrics and log export, flushing pending data.
+pub fn shutdown_telemetry() {
+    METRICS.shutdown();
+    opentelemetry::global::shutdown_logger_provider();
+    opentelemetry::global::shutdown_tracer_provider();
+}
+
+/// Resource describing the service, attached to exported metrics and logs.
+pub fn resource() -> opentelemetry_sdk::Resource {
+    let service_name =
+        std::env::var("SERVICE_NAME").unwrap_or_else(|_| "elevation-service".to_string());
+    opentelemetry_sdk::Resource::new(vec![
+        opentelemetry_semantic_conventions::resource::SERVICE_NAME.string(service_name),
+        opentelemetry_semantic_conventions::resource::SERVICE_VERSION
+            .string(env!("CARGO_PKG_VERSION")),
+    ])
+}
+
+/// Returns true if a signal (`METRICS` or `LOGS`) has an OTLP endpoint and is not
+/// disabled with `OTEL_<SIGNAL>_EXPORTER=none`.
+fn otlp_signal_enabled(signal: &str) -> bool {
+    let disabled = std::env::var(format!("OTEL_{}_EXPORTER", signal))
+        .is_ok_and(|exporter| exporter.trim().eq_ignore_ascii_case("none"));
+    let configured = std::env::var(format!("OTEL_EXPORTER_OTLP_{}_ENDPOINT", signal)).is_ok()
+        || std::env::var("OTEL_EXPORTER_OTLP_ENDPOINT").is_ok();
+    configured && !disabled
+}
+
+/// Wire protocol of an OTLP export.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Protocol {
+    Grpc,
+    HttpProtobuf,
+}
+
+/// Collector, protocol and headers of one signal, from the `OTEL_EXPORTER_OTLP_*` variables.
+/// Signal-specific variables take precedence over the general ones.
+#[derive(Debug, Clone, PartialEq)]
+struct OtlpTarget {
+    protocol: Protocol,
+    /// Base endpoint, signal-specific endpoints are resolved by the exporters
+    endpoint: String,
+    headers: Vec<(String, String)>,
+}
+
+impl OtlpTarget {
+    fn from_env(signal: &str) -> Result<Self, String> {
+        let signal_var = |name: &str| {
+            std::env::var(format!("OTEL_EXPORTER_OTLP_{}_{}", signal, name))
+                .or_else(|_| std::env::var(format!("OTEL_EXPORTER_OTLP_{}", name)))
+                .ok()
+        };
+
+        let protocol = match signal_var("PROTOCOL").as_deref().map(str::trim) {
+            None | Some("http/protobuf") => Protocol::HttpProtobuf,
+            Some("grpc") => Protocol::Grpc,
+            Some(other) => {
+                return Err(format!(
+                    "Unsupported OTLP protocol '{}', expected 'grpc' or 'http/protobuf'",
+                    other
+                ));
+            }
+        };
+        let default_endpoint = match protocol {
+            Protocol::Grpc => DEFAULT_OTLP_GRPC_ENDPOINT,
+            Protocol::HttpProtobuf => DEFAULT_OTLP_ENDPOINT,
+        };
+        let endpoint = std::env::var("OTEL_EXPORTER_OTLP_ENDPOINT")
+            .unwrap_or_else(|_| default_endpoint.to_string())
+            .trim_end_matches('/')
+            .to_string();
+        let headers = signal_var("HEADERS")
+            .map(|headers| parse_headers(&headers))
+            .unwrap_or_default();
+
+        Ok(Self {
+            protocol,
+            endpoint,
+            headers,
+        })
+    }
+
+    /// Creates the exporter builder of a signal, e.g. a `SpanExporterBuilder`.
+    fn exporter<B>(&self) -> Result<B, String>
+    where
+        B: From<HttpExporterBuilder> + From<TonicExporterBuilder>,
+    {
+        let timeout = Duration::from_secs(10);
+        match self.protocol {
+            Protocol::HttpProtobuf => Ok(opentelemetry_otlp::new_exporter()
+                .http()
+                .with_endpoint(&self.endpoint)
+                .with_timeout(timeout)
+                .with_headers(self.headers.iter().cloned().collect())
+                .into()),
+            Protocol::Grpc => {
+                let mut metadata = MetadataMap::new();
+                for (key, value) in &self.headers {
+                    let key = MetadataKey::from_bytes(key.as_bytes())
+                        .map_err(|_| format!("Invalid OTLP header name '{}'", key))?;
+                    let value = MetadataValue::try_from(value.as_str())
+                        .map_err(|_| format!("Invalid value of OTLP header '{}'", key))?;
+                    metadata.insert(key, value);
+                }
+                Ok(opentelemetry_otlp::new_exporter()
+                    .tonic()
+                    .with_endpoint(&self.endpoint)
+                    .with_timeout(timeout)
+                    .with_metadata(metadata)
+                    .into())
+            }
+        }
+    }
+}
+
+/// Parses `key1=value1,key2=value2` as used by `OTEL_EXPORTER_OTLP_HEADERS`.
+fn parse_headers(headers: &str) -> Vec<(String, String)> {
+    headers
+        .split(',')
+        .filter_map(|pair| pair.split_once('='))
+        .map(|(key, value)| (key.trim().to_string(), value.trim().to_string()))
+        .filter(|(key, value)| !key.is_empty() && !value.is_empty())
+        .collect()
+}
+
+/// Sampler from `OTEL_TRACES_SAMPLER` and `OTEL_TRACES_SAMPLER_ARG`, parent-based
+/// always on by default.
+fn sampler(name: Option<&str>, arg: Option<&str>) -> Result<Sampler, String> {
+    let ratio = || match arg.map(str::trim) {
+        None => Ok(1.0),
+        Some(arg) => arg
+            .parse::<f64>()
+            .ok()
+            .filter(|ratio| (0.0..=1.0).contains(ratio))
+            .ok_or_else(|| {
+                format!(
+                    "Invalid OTEL_TRACES_SAMPLER_ARG '{}', expected a ratio between 0 and 1",
+                    arg
+                )
+            }),
+    };
+
+    Ok(
+        match name.map(str::trim).unwrap_or("parentbased_always_on") {
+            "always_on" => Sampler::AlwaysOn,
+            "always_off" => Sampler::AlwaysOff,
+            "traceidratio" => Sampler::TraceIdRatioBased(ratio()?),
+            "parentbased_always_on" => Sampler::ParentBased(Box::new(Sampler::AlwaysOn)),
+            "parentbased_always_off" => Sampler::ParentBased(Box::new(Sampler::AlwaysOff)),
+            "parentbased_traceidratio" => {
+                Sampler::ParentBased(Box::new(Sampler::TraceIdRatioBased(ratio()?)))
+            }
+            other => return Err(format!("Unsupported OTEL_TRACES_SAMPLER '{}'", other)),
+        },
+    )
+}
+
+/// Caps the spans exported below each root span, so a batch request does not send a
+/// span per point to the collector. Spans beyond the cap are not exported, and their
+/// events are attached to the closest exported ancestor.
+struct SpanCap {
+    /// Spans exported below a root span, 0 disables the cap
+    max_child_spans: usize,
+}
+
+/// Number of child spans created below a root span.
+struct ChildSpans(usize);
+
+impl SpanCap {
+    fn from_env() -> Result<Self, String> {
+        let max_child_spans = match std::env::var("OTEL_TRACES_MAX_CHILD_SPANS") {
+            Ok(value) => value.trim().parse().map_err(|_| {
+                format!(
+                    "Invalid OTEL_TRACES_MAX_CHILD_SPANS '{}', expected a number",
+                    value
+                )
+            })?,
+            Err(_) => DEFAULT_MAX_CHILD_SPANS,
+        };
+        Ok(Self { max_child_spans })
+    }
+}
+
+impl<S> Filter<S> for SpanCap
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn enabled(&self, metadata: &Metadata<'_>, cx: &Context<'_, S>) -> bool {
+        if !metadata.is_span() || self.max_child_spans == 0 {
+            return true;
+        }
+        let Some(root) = cx
+            .lookup_current()
+            .and_then(|span| span.scope().from_root().next())
+        else {
+            return true;
+        };
+
+        let mut extensions = root.extensions_mut();
+        match extensions.get_mut::<ChildSpans>() {
+            Some(ChildSpans(count)) => {
+                *count += 1;
+                *count <= self.max_child_spans
+            }
+            None => {
+                extensions.insert(ChildSpans(1));
+                true
+            }
+        }
+    }
+}
+
+fn env_millis(key: &str, default: Duration) -> Duration {
+    std::env::var(key)
+        .ok()
+        .and_then(|value| value.trim().parse().ok())
+        .map_or(default, Duration::from_millis)
+}
+
+/// Builds the reader exporting metrics over OTLP, if configured. Needs the Tokio runtime.
+pub fn otlp_metrics_reader() -> Option<PeriodicReader> {
+    if !otlp_signal_enabled("METRICS") {
+        return None;
+    }
+    let interval = env_millis("OTEL_METRIC_EXPORT_INTERVAL", Duration::from_secs(60));
+    match OtlpTarget::from_env("METRICS")
+        .map_err(MetricsError::Config)
+        .and_then(|target| build_metrics_reader(&target, interval))
+    {
+        Ok(reader) => {
+            info!(?interval, "OpenTelemetry metrics export initialized");
+            Some(reader)
+        }
+        Err(e) => {
+            warn!(error = %e, "Failed to initialize OpenTelemetry metrics export");
+            None
+        }
+    }
+}
+
+fn build_metrics_reader(
+    target: &OtlpTarget,
+    interval: Duration,
+) -> opentelemetry::metrics::Result<PeriodicReader> {
+    let exporter = target
+        .exporter::<MetricsExporterBuilder>()
+        .map_err(MetricsError::Config)?
+        .build_metrics_exporter(
+            Box::new(DefaultTemporalitySelector::new()),
+            Box::new(DefaultAggregationSelector::new()),
+        )?;
+    Ok(PeriodicReader::builder(exporter, Tokio)
+        .with_interval(interval)
+        .with_timeout(env_millis(
+            "OTEL_METRIC_EXPORT_TIMEOUT",
+            Duration::from_secs(30),
+        ))
+        .build())
+}
+
+fn build_log_layer(
+    target: &OtlpTarget,
+    resource: opentelemetry_sdk::Resource,
+) -> opentelemetry::logs::LogResult<(OtelLogLayer, LoggerProvider)> {
+    let exporter = target
+        .exporter::<LogExporterBuilder>()?
+        .build_log_exporter()?;
+    let provider = LoggerProvider::builder()
+        .with_batch_exporter(exporter, Tokio)
+        .with_config(opentelemetry_sdk::logs::config().with_resource(resource))
+        .build();
+    let logger = provider.logger("elevation-service");
+    Ok((OtelLogLayer { logger }, provider))
+}
+
+/// Bridges `tracing` events to OpenTelemetry log records, linked to the current span.
+pub struct OtelLogLayer {
+    logger: Logger,
+}
+
+impl<S> Layer<S> for OtelLogLayer
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
+        let metadata = event.metadata();
+        if EXPORTER_TARGETS
+            .iter()
+            .any(|target| metadata.target().starts_with(target))
+        {
+            return;
+        }
+
+        let mut visitor = LogVisitor::default();
+        event.record(&mut visitor);
+        let (severity, severity_text) = match *metadata.level() {
+            tracing::Level::ERROR => (Severity::Error, "ERROR"),
+            tracing::Level::WARN => (Severity::Warn, "WARN"),
+            tracing::Level::INFO => (Severity::Info, "INFO"),
+            tracing::Level::DEBUG => (Severity::Debug, "DEBUG"),
+            tracing::Level::TRACE => (Severity::Trace, "TRACE"),
+        };
+        visitor.attributes.push((
+            Key::from_static_str("target"),
+            AnyValue::from(metadata.target().to_string()),
+        ));
+
+        let mut record = LogRecord::builder()
+            .with_timestamp(SystemTime::now())
+            .with_observed_timestamp(SystemTime::now())
+            .with_severity_number(severity)
+            .with_severity_text(severity_text)
+            .with_body(AnyValue::from(visitor.message))
+            .with_attributes(visitor.attributes);
+        // Spans dropped by the span cap have no OpenTelemetry data, their ancestors do
+        if let Some(span_context) = ctx.event_scope(event).and_then(|mut scope| {
+            scope.find_map(|span| span_context(span.extensions().get::<OtelData>()?))
+        }) {
+            record = record.with_span_context(&span_context);
+        }
+        self.logger.emit(record.build());
+    }
+}
+
+/// Returns the context of the span that the OpenTelemetry layer builds for a tracing span.
+fn span_context(data: &OtelData) -> Option<SpanContext> {
+    // Spans continuing a trace of the caller belong to its trace, as in the exported span
+    let parent = data.parent_cx.span();
+    let trace_id = match parent.span_context().is_valid() {
+        true => parent.span_context().trace_id(),
+        false => data.builder.trace_id?,
+    };
+    let span_id = data.builder.span_id?;
+    Some(SpanContext::new(
+        trace_id,
+        span_id,
+        TraceFlags::SAMPLED,
+        false,
+        TraceState::default(),
+    ))
+}
+
+#[derive(Default)]
+struct LogVisitor {
+    message: String,
+    attributes: Vec<(Key, AnyValue)>,
+}
+
+impl LogVisitor {
+    fn push(&mut self, field: &Field, value: AnyValue) {
+        self.attributes
+            .push((Key::from_static_str(field.name()), value));
+    }
+}
+
+impl tracing::field::Visit for LogVisitor {
+    fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+        match field.name() {
+            "message" => self.message = format!("{:?}", value),
+            _ => self.push(field, AnyValue::from(format!("{:?}", value))),
+        }
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        match field.name() {
+            "message" => self.message = value.to_string(),
+            _ => self.push(field, AnyValue::from(value.to_string())),
+        }
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.push(field, AnyValue::from(value));
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.push(field, AnyValue::from(value as i64));
+    }
+
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        self.push(field, AnyValue::from(value));
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.push(field, AnyValue::from(value));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metrics::Metrics;
+    use opentelemetry::trace::Tracer as _;
+    use opentelemetry_proto::tonic::collector::trace::v1::{
+        ExportTraceServiceRequest, ExportTraceServiceResponse,
+        trace_service_server::{TraceService, TraceServiceServer},
+    };
+    use std::sync::{Arc, Mutex};
+    use tokio_stream::wrappers::TcpListenerStream;
+    use warp::{Filter, hyper::body::Bytes};
+
+    type Exports = Mutex<Vec<(String, Bytes)>>;
+
+    fn http_target(endpoint: &str) -> OtlpTarget {
+        OtlpTarget {
+            protocol: Protocol::HttpProtobuf,
+            endpoint: endpoint.to_string(),
+            headers: Vec::new(),
+        }
+    }
+
+    /// Stands in for an OTLP collector, recording the path and body of every export.
+    fn collector() -> (String, Arc<Exports>) {
+        let exports = Arc::new(Mutex::new(Vec::new()));
+        let recorded = exports.clone();
+        let route = warp::post()
+            .and(warp::path::full())
+            .and(warp::body::bytes())
+            .map(move |path: warp::path::FullPath, body: Bytes| {
+                recorded
+                    .lock()
+                    .unwrap()
+                    .push((path.as_str().to_string(), body));
+                warp::reply()
+            });
+        let (address, server) = warp::serve(route).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+        (format!("http://{}", address), exports)
+    }
+
+    fn received(exports: &Exports, path: &str, text: &str) -> bool {
+        exports.lock().unwrap().iter().any(|(export, body)| {
+            export == path
+                && body
+                    .windows(text.len())
+                    .any(|window| window == text.as_bytes())
+        })
+    }
+
+    // The exporters block on their export tasks when flushing, which needs a second worker
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_exports_metrics_and_logs() {
+        let (endpoint, exports) = collector();
+        let target = http_target(&endpoint);
+
+        let reader = build_metrics_reader(&target, Duration::from_secs(3600)).unwrap();
+        let metrics = Metrics::new(Some(reader));
+        metrics.cache_hits.add(3, &[]);
+        metrics.shutdown();
+        assert!(received(&exports, "/v1/metrics", "tile_cache_hits"));
+
+        let (layer, provider) = build_log_layer(&target, resource()).unwrap();
+        let subscriber = tracing_subscriber::registry().with(layer);
+        tracing::subscriber::with_default(subscriber, || {
+            info!(tile = "N47E011.hgt", "Loaded tile from collector test");
+            // Events of the exporter itself are not fed back into the export
+            warn!(target: "hyper::client", "Connection dropped");
+        });
+        for result in provider.force_flush() {
+            result.unwrap();
+        }
+        assert!(received(
+            &exports,
+            "/v1/logs",
+            "Loaded tile from collector test"
+        ));
+        assert!(received(&exports, "/v1/logs", "N47E011.hgt"));
+        assert!(!received(&exports, "/v1/logs", "Connection dropped"));
+    }
+
+    /// Stands in for a gRPC trace collector, recording the authorization header and
+    /// the span names of every export.
+    #[derive(Clone, Default)]
+    struct GrpcCollector(Arc<Mutex<Vec<TraceExport>>>);
+
+    /// Authorization header and span names of a trace export.
+    type TraceExport = (Option<String>, Vec<String>);
+
+    #[tonic::async_trait]
+    impl TraceService for GrpcCollector {
+        async fn export(
+            &self,
+            request: tonic::Request<ExportTraceServiceRequest>,
+        ) -> Result<tonic::Response<ExportTraceServiceResponse>, tonic::Status> {
+            let authorization = request
+                .metadata()
+                .get("authorization")
+                .and_then(|value| value.to_str().ok())
+                .map(str::to_string);
+            let names = request
+                .into_inner()
+                .resource_spans
+                .into_iter()
+                .flat_map(|spans| spans.scope_spans)
+                .flat_map(|spans| spans.spans)
+                .map(|span| span.name)
+                .collect();
+            self.0.lock().unwrap().push((authorization, names));
+            Ok(tonic::Response::new(ExportTraceServiceResponse::default()))
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_exports_traces_over_grpc_with_headers() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let collector = GrpcCollector::default();
+        tokio::spawn(
+            tonic::transport::Server::builder()
+                .add_service(TraceServiceServer::new(collector.clone()))
+                .serve_with_incoming(TcpListenerStream::new(listener)),
+        );
+
+        let target = OtlpTarget {
+            protocol: Protocol::Grpc,
+            endpoint: format!("http://{}", address),
+            headers: parse_headers("Authorization=Bearer secret"),
+        };
+        let exporter = target
+            .exporter::<SpanExporterBuilder>()
+            .unwrap()
+            .build_span_exporter()
+            .unwrap();
+        let provider = opentelemetry_sdk::trace::TracerProvider::builder()
+            .with_batch_exporter(exporter, Tokio)
+            .build();
+        provider.tracer("test").in_span("get_elevation", |_| {});
+        for result in provider.force_flush() {
+            result.unwrap();
+        }
+
+        let exports = collector.0.lock().unwrap();
+        assert_eq!(
+            *exports,
+            vec![(
+                Some("Bearer secret".to_string()),
+                vec!["get_elevation".to_string()]
+            )]
+        );
+    }
+
+    #[test]
+    fn test_parse_headers() {
+        assert_eq!(
+            parse_headers("Authorization=Bearer a=b, x-api-key = key ,,invalid,empty="),
+            vec![
+                ("Authorization".to_string(), "Bearer a=b".to_string()),
+                ("x-api-key".to_string(), "key".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_sampler() {
+        let parsed = |name, arg| format!("{:?}", sampler(name, arg).unwrap());
+        assert_eq!(parsed(None, None), "ParentBased(AlwaysOn)");
+        assert_eq!(
+            parsed(Some("parentbased_traceidratio"), Some("0.25")),
+            "ParentBased(TraceIdRatioBased(0.25))"
+        );
+        assert_eq!(parsed(Some("always_off"), None), "AlwaysOff");
+        assert!(sampler(Some("traceidratio"), Some("1.5")).is_err());
+        assert!(sampler(Some("jaeger_remote"), None).is_err());
+    }
+
+    /// Counts the spans it sees.
+    struct SpanCounter(Arc<Mutex<Vec<String>>>);
+
+    impl<S> Layer<S> for SpanCounter
+    where
+        S: Subscriber + for<'a> LookupSpan<'a>,
+    {
+        fn on_new_span(
+            &self,
+            attrs: &tracing::span::Attributes<'_>,
+            _id: &tracing::span::Id,
+            _ctx: Context<'_, S>,
+        ) {
+            self.0
+                .lock()
+                .unwrap()
+                .push(attrs.metadata().name().to_string());
+        }
+    }
+
+    #[test]
+    fn test_span_cap_limits_child_spans_per_root() {
+        let spans = Arc::new(Mutex::new(Vec::new()));
+        let subscriber = tracing_subscriber::registry()
+            .with(SpanCounter(spans.clone()).with_filter(SpanCap { max_child_spans: 2 }));
+
+        tracing::subscriber::with_default(subscriber, || {
+            for _ in 0..2 {
+                let _request = tracing::info_span!("request").entered();
+                for _ in 0..5 {
+                    let _point = tracing::info_span!("point").entered();
+                    let _tile = tracing::info_span!("tile").entered();
+                }
+            }
+        });
+
+        assert_eq!(
+            *spans.lock().unwrap(),
+            ["request", "point", "tile", "request", "point", "tile"]
+        );
+    }
+}
@@ -0,0 +1,309 @@
+use serde_json::{Map, Value};
+use std::{fmt, net::SocketAddr};
+use tracing::{Event, Subscriber, field::Field, info};
+use tracing_opentelemetry::OtelData;
+use tracing_subscriber::{
+    Registry,
+    fmt::{
+        FmtContext, FormatEvent, FormatFields,
+        format::Writer,
+        time::{FormatTime, SystemTime},
+    },
+    registry::LookupSpan,
+};
+
+use super::span_context;
+
+/// Format of the log lines written to stdout, from `LOG_FORMAT`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LogFormat {
+    Text,
+    Json,
+}
+
+impl LogFormat {
+    pub fn from_env() -> Result<Self, String> {
+        match std::env::var("LOG_FORMAT").as_deref().map(str::trim) {
+            Err(_) | Ok("") | Ok("text") => Ok(LogFormat::Text),
+            Ok("json") => Ok(LogFormat::Json),
+            Ok(other) => Err(format!(
+                "Unsupported LOG_FORMAT '{}', expected 'text' or 'json'",
+                other
+            )),
+        }
+    }
+}
+
+/// Writes each event as one JSON object with the fixed keys `timestamp`, `level`,
+/// `target`, `message`, `span`, `trace_id`, `span_id` and the event's own `fields`.
+pub struct JsonFormat;
+
+impl<S, N> FormatEvent<S, N> for JsonFormat
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    N: for<'a> FormatFields<'a> + 'static,
+{
+    fn format_event(
+        &self,
+        ctx: &FmtContext<'_, S, N>,
+        mut writer: Writer<'_>,
+        event: &Event<'_>,
+    ) -> fmt::Result {
+        let mut timestamp = String::new();
+        SystemTime.format_time(&mut Writer::new(&mut timestamp))?;
+        let metadata = event.metadata();
+        let mut visitor = JsonVisitor::default();
+        event.record(&mut visitor);
+
+        let mut line = Map::new();
+        line.insert("timestamp".into(), timestamp.into());
+        line.insert("level".into(), metadata.level().as_str().into());
+        line.insert("target".into(), metadata.target().into());
+        line.insert("message".into(), visitor.message.into());
+        if let Some(scope) = ctx.event_scope() {
+            let mut scope = scope.peekable();
+            if let Some(span) = scope.peek() {
+                line.insert("span".into(), span.name().into());
+            }
+            // Spans dropped by the span cap have no OpenTelemetry data, their ancestors do
+            if let Some(span_context) =
+                scope.find_map(|span| span_context(span.extensions().get::<OtelData>()?))
+            {
+                line.insert(
+                    "trace_id".into(),
+                    span_context.trace_id().to_string().into(),
+                );
+                line.insert("span_id".into(), span_context.span_id().to_string().into());
+            }
+        }
+        line.insert("fields".into(), Value::Object(visitor.fields));
+
+        let line = serde_json::to_string(&line).map_err(|_| fmt::Error)?;
+        writeln!(writer, "{}", line)
+    }
+}
+
+#[derive(Default)]
+struct JsonVisitor {
+    message: String,
+    fields: Map<String, Value>,
+}
+
+impl tracing::field::Visit for JsonVisitor {
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        match field.name() {
+            "message" => self.message = format!("{:?}", value),
+            name => {
+                self.fields
+                    .insert(name.into(), format!("{:?}", value).into());
+            }
+        }
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        match field.name() {
+            "message" => self.message = value.to_string(),
+            name => {
+                self.fields.insert(name.into(), value.into());
+            }
+        }
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.fields.insert(field.name().into(), value.into());
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.fields.insert(field.name().into(), value.into());
+    }
+
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        self.fields.insert(field.name().into(), value.into());
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.fields.insert(field.name().into(), value.into());
+    }
+}
+
+/// Number of points looked up by a request, kept with its root span for the access log.
+#[derive(Clone, Copy)]
+struct RequestPoints(usize);
+
+/// Address of the client of a request, kept with its root span for the access log.
+/// Warp reports it only for connections without TLS.
+#[derive(Clone, Copy)]
+struct RemoteAddr(SocketAddr);
+
+/// Records the number of points looked up by the current request.
+pub fn record_request_points(points: usize) {
+    record(RequestPoints(points));
+}
+
+/// Records the address of the client of the current request, see [`crate::tls::remote_addr`].
+pub fn record_remote_addr(addr: SocketAddr) {
+    record(RemoteAddr(addr));
+}
+
+/// Stores `value` with the root span of the current request, replacing an earlier value.
+fn record<T: Send + Sync + 'static>(value: T) {
+    tracing::Span::current().with_subscriber(|(id, dispatch)| {
+        let Some(registry) = dispatch.downcast_ref::<Registry>() else {
+            return;
+        };
+        if let Some(root) = registry
+            .span(id)
+            .and_then(|span| span.scope().from_root().next())
+        {
+            root.extensions_mut().replace(value);
+        }
+    });
+}
+
+/// Returns the value recorded for the current request.
+fn recorded<T: Copy + Send + Sync + 'static>() -> Option<T> {
+    tracing::Span::current()
+        .with_subscriber(|(id, dispatch)| {
+            let registry = dispatch.downcast_ref::<Registry>()?;
+            let root = registry.span(id)?.scope().from_root().next()?;
+            root.extensions().get::<T>().copied()
+        })
+        .flatten()
+}
+
+/// Writes the access log line of a request. Runs within the request span, so the line
+/// carries its trace ID.
+pub fn access_log(info: warp::log::Info<'_>) {
+    let method = info.method().as_str();
+    let path = info.path();
+    let status = info.status().as_u16();
+    let duration_ms = info.elapsed().as_secs_f64() * 1000.0;
+    info!(
+        target: "access_log",
+        method,
+        path,
+        status,
+        duration_ms,
+        points = recorded::<RequestPoints>().map(|RequestPoints(points)| points),
+        remote_addr = info
+            .remote_addr()
+            .or(recorded::<RemoteAddr>().map(|RemoteAddr(addr)| addr))
+            .map(|addr| addr.to_string()),
+        user_agent = info.user_agent(),
+        "{} {} {} {:.3}ms",
+        method,
+        path,
+        status,
+        duration_ms
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::telemetry::extract_context;
+    use opentelemetry::trace::TracerProvider as _;
+    use std::io;
+    use std::sync::{Arc, Mutex};
+    use tracing_opentelemetry::OpenTelemetrySpanExt;
+    use tracing_subscriber::{fmt::MakeWriter, prelude::*};
+    use warp::Filter;
+
+    /// Collects the written log lines.
+    #[derive(Clone, Default)]
+    struct Lines(Arc<Mutex<Vec<u8>>>);
+
+    impl io::Write for Lines {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl<'a> MakeWriter<'a> for Lines {
+        type Writer = Lines;
+
+        fn make_writer(&'a self) -> Self::Writer {
+            self.clone()
+        }
+    }
+
+    impl Lines {
+        fn json(&self) -> Vec<Value> {
+            String::from_utf8(self.0.lock().unwrap().clone())
+                .unwrap()
+                .lines()
+                .map(|line| serde_json::from_str(line).unwrap())
+                .collect()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_json_access_log_with_trace_ids() {
+        opentelemetry::global::set_text_map_propagator(
+            opentelemetry_sdk::propagation::TraceContextPropagator::new(),
+        );
+        let provider = opentelemetry_sdk::trace::TracerProvider::builder().build();
+        let lines = Lines::default();
+        let subscriber = tracing_subscriber::registry()
+            .with(tracing_opentelemetry::layer().with_tracer(provider.tracer("test")))
+            .with(
+                tracing_subscriber::fmt::layer()
+                    .event_format(JsonFormat)
+                    .with_writer(lines.clone()),
+            );
+        let _guard = tracing::subscriber::set_default(subscriber);
+
+        let route = warp::path!("api")
+            .and(warp::body::json::<Vec<[f64; 2]>>())
+            .map(|points: Vec<[f64; 2]>| {
+                record_request_points(points.len());
+                info!(dataset = "srtm", cached = true, "Batch elevation request");
+                "[]"
+            })
+            .with(warp::log::custom(access_log))
+            .with(warp::trace(|info| {
+                let span = tracing::info_span!("request");
+                span.set_parent(extract_context(info.request_headers()));
+                span
+            }));
+        let response = warp::test::request()
+            .method("POST")
+            .path("/api")
+            .header(
+                "traceparent",
+                "00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01",
+            )
+            .json(&[[47.0, 11.0], [47.1, 11.1], [47.2, 11.2]])
+            .reply(&route)
+            .await;
+        assert_eq!(response.status(), 200);
+
+        let lines = lines.json();
+        let handler = lines
+            .iter()
+            .find(|line| line["message"] == "Batch elevation request")
+            .unwrap();
+        assert_eq!(handler["level"], "INFO");
+        assert_eq!(handler["span"], "request");
+        assert_eq!(handler["trace_id"], "0af7651916cd43dd8448eb211c80319c");
+        assert_eq!(handler["fields"]["dataset"], "srtm");
+        assert_eq!(handler["fields"]["cached"], true);
+
+        let access = lines
+            .iter()
+            .find(|line| line["target"] == "access_log")
+            .unwrap();
+        assert_eq!(access["trace_id"], "0af7651916cd43dd8448eb211c80319c");
+        assert_eq!(access["span_id"], handler["span_id"]);
+        assert_eq!(access["fields"]["method"], "POST");
+        assert_eq!(access["fields"]["path"], "/api");
+        assert_eq!(access["fields"]["status"], 200);
+        assert_eq!(access["fields"]["points"], 3);
+        assert!(access["fields"]["duration_ms"].is_f64());
+    }
+}
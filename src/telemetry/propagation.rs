@@ -0,0 +1,262 @@
+use opentelemetry::{
+    Context,
+    propagation::{Extractor, Injector, TextMapPropagator, text_map_propagator::FieldIter},
+    trace::{SpanContext, SpanId, TraceContextExt, TraceFlags, TraceId, TraceState},
+};
+use opentelemetry_sdk::propagation::{
+    BaggagePropagator, TextMapCompositePropagator, TraceContextPropagator,
+};
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+use warp::http::{HeaderMap, HeaderName, HeaderValue};
+
+/// Builds the propagators listed in `OTEL_PROPAGATORS`, `tracecontext,baggage` by default.
+pub fn propagator(names: Option<&str>) -> Result<TextMapCompositePropagator, String> {
+    let mut propagators: Vec<Box<dyn TextMapPropagator + Send + Sync>> = Vec::new();
+    for name in names.unwrap_or("tracecontext,baggage").split(',') {
+        match name.trim() {
+            "tracecontext" => propagators.push(Box::new(TraceContextPropagator::new())),
+            "baggage" => propagators.push(Box::new(BaggagePropagator::new())),
+            "b3" => propagators.push(Box::new(B3Propagator::new(true))),
+            "b3multi" => propagators.push(Box::new(B3Propagator::new(false))),
+            "none" | "" => {}
+            other => return Err(format!("Unsupported OTEL_PROPAGATORS entry '{}'", other)),
+        }
+    }
+    Ok(TextMapCompositePropagator::new(propagators))
+}
+
+/// Returns the trace context sent by the caller of a request.
+pub fn extract_context(headers: &HeaderMap) -> Context {
+    opentelemetry::global::get_text_map_propagator(|propagator| {
+        propagator.extract_with_context(&Context::new(), &HeaderExtractor(headers))
+    })
+}
+
+/// Returns the headers passing the context of the current span on to backend requests.
+pub fn inject_context() -> HeaderMap {
+    let context = tracing::Span::current().context();
+    let mut headers = HeaderMap::new();
+    opentelemetry::global::get_text_map_propagator(|propagator| {
+        propagator.inject_context(&context, &mut HeaderInjector(&mut headers))
+    });
+    headers
+}
+
+struct HeaderExtractor<'a>(&'a HeaderMap);
+
+impl Extractor for HeaderExtractor<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).and_then(|value| value.to_str().ok())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.keys().map(HeaderName::as_str).collect()
+    }
+}
+
+struct HeaderInjector<'a>(&'a mut HeaderMap);
+
+impl Injector for HeaderInjector<'_> {
+    fn set(&mut self, key: &str, value: String) {
+        if let (Ok(name), Ok(value)) = (
+            HeaderName::from_bytes(key.as_bytes()),
+            HeaderValue::from_str(&value),
+        ) {
+            self.0.insert(name, value);
+        }
+    }
+}
+
+/// Propagates the B3 headers of Zipkin instrumented services. Both the single `b3`
+/// header and the `x-b3-*` headers are extracted, `single_header` selects the injected one.
+#[derive(Debug)]
+struct B3Propagator {
+    single_header: bool,
+    fields: Vec<String>,
+}
+
+impl B3Propagator {
+    fn new(single_header: bool) -> Self {
+        let fields = match single_header {
+            true => vec!["b3"],
+            false => vec!["x-b3-traceid", "x-b3-spanid", "x-b3-sampled"],
+        };
+        Self {
+            single_header,
+            fields: fields.into_iter().map(String::from).collect(),
+        }
+    }
+}
+
+impl TextMapPropagator for B3Propagator {
+    fn inject_context(&self, cx: &Context, injector: &mut dyn Injector) {
+        let span = cx.span();
+        let span_context = span.span_context();
+        if !span_context.is_valid() {
+            return;
+        }
+        let sampled = if span_context.is_sampled() { "1" } else { "0" };
+        if self.single_header {
+            injector.set(
+                "b3",
+                format!(
+                    "{}-{}-{}",
+                    span_context.trace_id(),
+                    span_context.span_id(),
+                    sampled
+                ),
+            );
+        } else {
+            injector.set("x-b3-traceid", span_context.trace_id().to_string());
+            injector.set("x-b3-spanid", span_context.span_id().to_string());
+            injector.set("x-b3-sampled", sampled.to_string());
+        }
+    }
+
+    fn extract_with_context(&self, cx: &Context, extractor: &dyn Extractor) -> Context {
+        let span_context = match extractor.get("b3") {
+            Some(header) => {
+                let mut parts = header.trim().split('-');
+                b3_span_context(parts.next(), parts.next(), parts.next())
+            }
+            None => b3_span_context(
+                extractor.get("x-b3-traceid"),
+                extractor.get("x-b3-spanid"),
+                extractor
+                    .get("x-b3-sampled")
+                    .or_else(|| extractor.get("x-b3-flags")),
+            ),
+        };
+        match span_context {
+            Some(span_context) => cx.with_remote_span_context(span_context),
+            None => cx.clone(),
+        }
+    }
+
+    fn fields(&self) -> FieldIter<'_> {
+        FieldIter::new(&self.fields)
+    }
+}
+
+/// Parses B3 IDs, trace IDs may have 64 or 128 bits. Without a sampling decision
+/// the trace is treated as sampled, leaving it to the sampler of this service.
+fn b3_span_context(
+    trace_id: Option<&str>,
+    span_id: Option<&str>,
+    sampled: Option<&str>,
+) -> Option<SpanContext> {
+    let trace_id = trace_id
+        .map(str::trim)
+        .filter(|id| id.len() == 16 || id.len() == 32)?;
+    let span_id = span_id.map(str::trim).filter(|id| id.len() == 16)?;
+    let trace_flags = match sampled.map(str::trim) {
+        Some("0") | Some("false") => TraceFlags::default(),
+        Some("1") | Some("true") | Some("d") | None => TraceFlags::SAMPLED,
+        Some(_) => return None,
+    };
+    let span_context = SpanContext::new(
+        TraceId::from_hex(trace_id).ok()?,
+        SpanId::from_hex(span_id).ok()?,
+        trace_flags,
+        true,
+        TraceState::default(),
+    );
+    span_context.is_valid().then_some(span_context)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn extract(propagator: &impl TextMapPropagator, headers: &[(&str, &str)]) -> SpanContext {
+        let mut map = HeaderMap::new();
+        for (name, value) in headers {
+            map.insert(
+                HeaderName::from_bytes(name.as_bytes()).unwrap(),
+                HeaderValue::from_str(value).unwrap(),
+            );
+        }
+        propagator
+            .extract_with_context(&Context::new(), &HeaderExtractor(&map))
+            .span()
+            .span_context()
+            .clone()
+    }
+
+    #[test]
+    fn test_extract_trace_context_and_b3() {
+        let composite = propagator(Some("tracecontext,b3")).unwrap();
+        let traceparent = extract(
+            &composite,
+            &[(
+                "traceparent",
+                "00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01",
+            )],
+        );
+        assert_eq!(
+            traceparent.trace_id(),
+            TraceId::from_hex("0af7651916cd43dd8448eb211c80319c").unwrap()
+        );
+        assert!(traceparent.is_remote() && traceparent.is_sampled());
+
+        let single = extract(
+            &composite,
+            &[("b3", "80f198ee56343ba864fe8b2a57d3eff7-e457b5a2e4d86bd1-0")],
+        );
+        assert_eq!(
+            single.span_id(),
+            SpanId::from_hex("e457b5a2e4d86bd1").unwrap()
+        );
+        assert!(!single.is_sampled());
+
+        let multi = extract(
+            &composite,
+            &[
+                ("X-B3-TraceId", "64fe8b2a57d3eff7"),
+                ("X-B3-SpanId", "e457b5a2e4d86bd1"),
+            ],
+        );
+        assert_eq!(
+            multi.trace_id(),
+            TraceId::from_hex("64fe8b2a57d3eff7").unwrap()
+        );
+        assert!(multi.is_sampled());
+
+        assert!(!extract(&composite, &[("b3", "0")]).is_valid());
+        assert!(propagator(Some("jaeger")).is_err());
+    }
+
+    #[test]
+    fn test_inject_b3() {
+        let span_context = SpanContext::new(
+            TraceId::from_hex("80f198ee56343ba864fe8b2a57d3eff7").unwrap(),
+            SpanId::from_hex("e457b5a2e4d86bd1").unwrap(),
+            TraceFlags::SAMPLED,
+            false,
+            TraceState::default(),
+        );
+        let cx = Context::new().with_remote_span_context(span_context.clone());
+
+        let mut headers = HeaderMap::new();
+        B3Propagator::new(false).inject_context(&cx, &mut HeaderInjector(&mut headers));
+        assert_eq!(headers["x-b3-traceid"], "80f198ee56343ba864fe8b2a57d3eff7");
+        assert_eq!(headers["x-b3-spanid"], "e457b5a2e4d86bd1");
+        assert_eq!(headers["x-b3-sampled"], "1");
+
+        let mut headers = HeaderMap::new();
+        B3Propagator::new(true).inject_context(&cx, &mut HeaderInjector(&mut headers));
+        assert_eq!(
+            extract(
+                &B3Propagator::new(true),
+                &[("b3", headers["b3"].to_str().unwrap())]
+            ),
+            SpanContext::new(
+                span_context.trace_id(),
+                span_context.span_id(),
+                TraceFlags::SAMPLED,
+                true,
+                TraceState::default(),
+            )
+        );
+    }
+}
@@ -1,41 +1,148 @@
+use crate::metrics::METRICS;
+pub use crate::tileset::disk_cache::DiskCache;
 use crate::tileset::file_tileset::FileTileSet;
-use crate::tileset::hgt::HGT;
+use crate::tileset::hgt::{HGT, VOID_VALUE};
 use crate::tileset::http_tileset::HTTPTileSet;
+pub use crate::tileset::path_template::{PathTemplate, SKADI_TEMPLATE, XYZ_TEMPLATE};
+use crate::tileset::range_reader::RangeReader;
+pub use crate::tileset::raster::TileFormat;
+use crate::tileset::raster::{RasterTile, check_mercator_lat, mercator_tile, mercator_tile_center};
+pub use crate::tileset::resilience::ResilienceOptions;
 use crate::tileset::s3_tileset::S3TileSet;
+use crate::types::{BoundingBox, LayerInfo};
+use byte_unit::Byte;
+use flate2::read::GzDecoder;
+use futures::stream::StreamExt;
 use moka::future::Cache;
+use opentelemetry::KeyValue;
+use std::collections::{HashMap, HashSet};
+use std::io::Read;
+use std::str::FromStr;
 use std::sync::Arc;
-use tracing::{debug, info, instrument};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+use tracing::{debug, error, info, instrument, warn};
 
+mod disk_cache;
 mod file_tileset;
 mod hgt;
 mod http_tileset;
+mod path_template;
+mod range_reader;
+mod raster;
+mod resilience;
 mod s3_tileset;
 
 #[derive(Debug, Clone)]
 pub struct TileSetOptions {
+    pub name: String,
     pub path: String,
-    pub cache_size: u64,
+    pub cache_size: CacheSize,
     pub gzip: bool,
+    pub path_template: PathTemplate,
+    pub format: TileFormat,
+    pub zoom: u8,
+    /// Map uncompressed local HGT tiles instead of reading them
+    pub mmap: bool,
+    /// Fetch single samples of uncompressed remote HGT tiles with range requests
+    pub range_reads: bool,
     pub s3_access_key_id: Option<String>,
     pub s3_secret_access_key: Option<String>,
     pub s3_region: Option<String>,
     pub s3_endpoint: Option<String>,
+    /// Second cache tier on local disk, only used by remote backends
+    pub disk_cache: Option<Arc<DiskCache>>,
+    /// Timeouts, retries and circuit breaking of remote backends
+    pub resilience: ResilienceOptions,
+    /// Number of tiles remembered as not existing, 0 disables the missing tile cache
+    pub missing_tile_cache_size: u64,
+    /// Time after which a missing tile is looked up again
+    pub missing_tile_ttl: Duration,
 }
 
 impl Default for TileSetOptions {
     fn default() -> Self {
         Self {
+            name: String::from("default"),
             path: String::new(),
-            cache_size: 128,
+            cache_size: CacheSize::Tiles(128),
             gzip: true,
+            path_template: PathTemplate::default(),
+            format: TileFormat::Hgt,
+            zoom: 12,
+            mmap: false,
+            range_reads: false,
             s3_access_key_id: None,
             s3_secret_access_key: None,
             s3_region: None,
             s3_endpoint: None,
+            disk_cache: None,
+            resilience: ResilienceOptions::default(),
+            missing_tile_cache_size: 100_000,
+            missing_tile_ttl: Duration::from_secs(3600),
         }
     }
 }
 
+/// Capacity of the in-memory tile cache of a layer.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CacheSize {
+    /// Number of tiles, regardless of their size
+    Tiles(u64),
+    /// Bytes of decoded tile data
+    Bytes(u64),
+}
+
+impl CacheSize {
+    /// Splits the budget in two halves, e.g. between whole tiles and range read blocks.
+    fn split(self) -> (Self, Self) {
+        match self {
+            CacheSize::Tiles(tiles) => (
+                CacheSize::Tiles(tiles - tiles / 2),
+                CacheSize::Tiles(tiles / 2),
+            ),
+            CacheSize::Bytes(bytes) => (
+                CacheSize::Bytes(bytes - bytes / 2),
+                CacheSize::Bytes(bytes / 2),
+            ),
+        }
+    }
+}
+
+impl FromStr for CacheSize {
+    type Err = std::io::Error;
+
+    /// Plain numbers are tile counts, numbers with a unit (e.g. `4GB`) are byte budgets.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        if let Ok(tiles) = s.parse::<u64>() {
+            return Ok(CacheSize::Tiles(tiles));
+        }
+        Byte::parse_str(s, true)
+            .map(|bytes| CacheSize::Bytes(bytes.as_u64()))
+            .map_err(|e| {
+                std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!("Invalid cache size '{}': {}", s, e),
+                )
+            })
+    }
+}
+
+impl std::fmt::Display for CacheSize {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CacheSize::Tiles(tiles) => write!(f, "{} tiles", tiles),
+            CacheSize::Bytes(bytes) => write!(
+                f,
+                "{:.2}",
+                Byte::from_u64(*bytes).get_appropriate_unit(byte_unit::UnitType::Binary)
+            ),
+        }
+    }
+}
+
+#[allow(clippy::upper_case_acronyms)]
 pub enum TileSet {
     File(FileTileSet),
     HTTP(HTTPTileSet),
@@ -59,136 +166,899 @@ impl TileSet {
                 String::new()
             };
 
-            Ok(TileSet::S3(S3TileSet::new(
-                bucket,
-                key_prefix,
-                options.gzip,
-                options.s3_access_key_id,
-                options.s3_secret_access_key,
-                options.s3_region,
-                options.s3_endpoint,
-            )?))
+            Ok(TileSet::S3(S3TileSet::new(bucket, key_prefix, options)?))
         } else if options.path.starts_with("http://") || options.path.starts_with("https://") {
             Ok(TileSet::HTTP(HTTPTileSet::new(
                 options.path.clone(),
-                options,
-            )))
+                options.resilience,
+            )?))
         } else {
-            Ok(TileSet::File(FileTileSet::new(
-                options.path.clone(),
-                options,
-            )))
+            Ok(TileSet::File(FileTileSet::new(options.path.clone())))
+        }
+    }
+
+    /// Fetches the bytes `start..=end` of a tile and returns them with the tile length.
+    async fn get_range(
+        &self,
+        tile_path: &str,
+        start: u64,
+        end: u64,
+    ) -> Result<(Vec<u8>, u64), std::io::Error> {
+        let started = Instant::now();
+        let result = match self {
+            TileSet::File(_) => Err(std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                "Range reads are only supported by remote tile sets",
+            )),
+            TileSet::HTTP(http_tileset) => http_tileset.get_range(tile_path, start, end).await,
+            TileSet::S3(s3_tileset) => s3_tileset.get_range(tile_path, start, end).await,
+        };
+        record_fetch(self, started, &result);
+        result
+    }
+
+    /// Short name of the backend type, used for reporting.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            TileSet::File(_) => "file",
+            TileSet::HTTP(_) => "http",
+            TileSet::S3(_) => "s3",
+        }
+    }
+
+    /// Returns false while requests to a remote backend fail and are cut short.
+    pub fn is_reachable(&self) -> bool {
+        match self {
+            TileSet::File(_) => true,
+            TileSet::HTTP(http_tileset) => http_tileset.is_reachable(),
+            TileSet::S3(s3_tileset) => s3_tileset.is_reachable(),
         }
     }
 }
 
-pub struct TileSetWithCache {
+/// Records the duration and outcome of a request to the backend of a tile set.
+fn record_fetch<T>(tileset: &TileSet, start: Instant, result: &Result<T, std::io::Error>) {
+    let backend = KeyValue::new("backend", tileset.kind());
+    METRICS.backend_duration.record(
+        start.elapsed().as_secs_f64(),
+        std::slice::from_ref(&backend),
+    );
+    if let Err(e) = result {
+        let kind = KeyValue::new("kind", format!("{:?}", e.kind()));
+        METRICS.backend_errors.add(1, &[backend, kind]);
+    }
+}
+
+/// Returns the complete length from a `Content-Range` header, e.g. `bytes 0-1/2884802`.
+fn parse_content_range(value: &str) -> Option<u64> {
+    value.rsplit_once('/')?.1.trim().parse().ok()
+}
+
+/// Decompresses gzip compressed tile data.
+fn decompress_tile(buffer: &[u8]) -> Result<Vec<u8>, std::io::Error> {
+    let start = Instant::now();
+    let mut decoder = GzDecoder::new(buffer);
+    let mut decompressed = Vec::new();
+    decoder.read_to_end(&mut decompressed).map_err(|e| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("Failed to decompress tile: {}", e),
+        )
+    })?;
+    METRICS
+        .decompression_duration
+        .record(start.elapsed().as_secs_f64(), &[]);
+    Ok(decompressed)
+}
+
+/// Decoded tile, ready for lookups.
+pub enum Tile {
+    Hgt(HGT),
+    Raster(RasterTile),
+}
+
+impl Tile {
+    /// Memory used by the decoded tile data.
+    fn size_bytes(&self) -> usize {
+        match self {
+            Tile::Hgt(hgt) => hgt.size_bytes(),
+            Tile::Raster(raster) => raster.size_bytes(),
+        }
+    }
+
+    fn get_elevation(&self, lat: f64, lng: f64) -> Result<i16, std::io::Error> {
+        match self {
+            Tile::Hgt(hgt) => hgt.get_elevation(lat, lng),
+            Tile::Raster(raster) => raster.get_elevation(lat, lng),
+        }
+    }
+}
+
+/// Cache key of a tile: the south-west corner of HGT tiles, `x`/`y` of raster tiles.
+type TileKey = (i32, i32);
+
+/// A single tile source together with its own tile cache.
+struct TileSetLayer {
+    options: TileSetOptions,
     tileset: TileSet,
-    hgt_cache: Cache<(i32, i32), Arc<HGT>>, // Cache HGT instances instead of raw tile data
+    hgt_cache: Cache<(i32, i32), Arc<Tile>>, // Cache decoded tiles instead of raw tile data
+    disk_cache: Option<(Arc<DiskCache>, String)>, // Cache and namespace of remote layers
+    range_reader: Option<RangeReader>,
+    missing_tiles: Option<Cache<TileKey, ()>>, // Tiles the backend reported as not existing
+    missing_tile_hits: AtomicU64,
 }
 
-impl TileSetWithCache {
-    pub fn new(options: TileSetOptions) -> Result<Self, Box<dyn std::error::Error>> {
+/// Points of a single tile from which the whole tile is loaded even with range reads.
+const WHOLE_TILE_MIN_POINTS: usize = 32;
+
+impl TileSetLayer {
+    fn new(options: TileSetOptions) -> Result<Self, Box<dyn std::error::Error>> {
+        if options.format.is_raster() != options.path_template.is_xyz() {
+            return Err(format!(
+                "Path template '{}' of layer '{}' does not match the {} tile format",
+                options.path_template, options.name, options.format
+            )
+            .into());
+        }
+        if options.format.is_raster() && options.zoom > 24 {
+            return Err(format!(
+                "Zoom {} of layer '{}' is too large",
+                options.zoom, options.name
+            )
+            .into());
+        }
+
+        if options.mmap && (options.gzip || options.format != TileFormat::Hgt) {
+            return Err(format!(
+                "Layer '{}' can only be memory-mapped with uncompressed HGT tiles",
+                options.name
+            )
+            .into());
+        }
+
+        if options.range_reads && (options.gzip || options.format != TileFormat::Hgt) {
+            return Err(format!(
+                "Layer '{}' can only use range reads with uncompressed HGT tiles",
+                options.name
+            )
+            .into());
+        }
+
+        let layer = KeyValue::new("layer", options.name.clone());
+        let builder = Cache::builder().eviction_listener(move |_, _, cause| {
+            if cause.was_evicted() {
+                METRICS.cache_evictions.add(1, std::slice::from_ref(&layer));
+            }
+        });
+        // Layers with range reads share their budget between whole tiles and blocks
+        let (tile_cache_size, block_cache_size) = match options.range_reads {
+            true => options.cache_size.split(),
+            false => (options.cache_size, CacheSize::Tiles(0)),
+        };
+        let hgt_cache = match tile_cache_size {
+            CacheSize::Tiles(tiles) => builder.max_capacity(tiles).build(),
+            CacheSize::Bytes(bytes) => builder
+                .max_capacity(bytes)
+                .weigher(|_, tile: &Arc<Tile>| u32::try_from(tile.size_bytes()).unwrap_or(u32::MAX))
+                .build(),
+        };
         let tileset = TileSet::new(options.clone())?;
-        let hgt_cache = Cache::new(options.cache_size);
-        Ok(Self { tileset, hgt_cache })
+        if options.mmap && !matches!(tileset, TileSet::File(_)) {
+            return Err(format!(
+                "Layer '{}' can only be memory-mapped from a local tile set",
+                options.name
+            )
+            .into());
+        }
+        if options.range_reads && matches!(tileset, TileSet::File(_)) {
+            return Err(format!(
+                "Layer '{}' can only use range reads with a remote tile set",
+                options.name
+            )
+            .into());
+        }
+        let range_reader = options
+            .range_reads
+            .then(|| RangeReader::new(block_cache_size));
+        let missing_tiles = (options.missing_tile_cache_size > 0
+            && !options.missing_tile_ttl.is_zero())
+        .then(|| {
+            Cache::builder()
+                .max_capacity(options.missing_tile_cache_size)
+                .time_to_live(options.missing_tile_ttl)
+                .build()
+        });
+        // Local tiles are already on disk
+        let disk_cache = match &tileset {
+            TileSet::File(_) => None,
+            _ => options
+                .disk_cache
+                .clone()
+                .map(|cache| (cache, disk_cache::namespace(&options.path))),
+        };
+        Ok(Self {
+            options,
+            tileset,
+            hgt_cache,
+            disk_cache,
+            range_reader,
+            missing_tiles,
+            missing_tile_hits: AtomicU64::new(0),
+        })
     }
 
-    pub fn get_file_path(lat: f64, lng: f64) -> Result<String, std::io::Error> {
-        let lat_prefix = if lat < 0.0 { "S" } else { "N" };
-        let lng_prefix = if lng < 0.0 { "W" } else { "E" };
-        let lat_file_name = format!("{}{:02}", lat_prefix, lat.abs() as i32);
-        let lng_file_name = format!("{}{:03}", lng_prefix, lng.abs() as i32);
-        Ok(format!(
-            "{}/{}{}.hgt.gz",
-            lat_file_name, lat_file_name, lng_file_name
-        ))
+    /// Returns the cache key and the path of the tile containing `lat`/`lng`, or
+    /// `NotFound` if the layer has no tile there.
+    fn get_tile_key(&self, lat: f64, lng: f64) -> Result<((i32, i32), String), tokio::io::Error> {
+        if self.options.format.is_raster() {
+            check_mercator_lat(lat)?;
+            let (x, y) = mercator_tile(lat, lng, self.options.zoom);
+            let (x, y) = (x.floor() as u32, y.floor() as u32);
+            let path = self
+                .options
+                .path_template
+                .render_xyz(self.options.zoom, x, y);
+            Ok(((x as i32, y as i32), path))
+        } else {
+            let lat_floor = lat.floor();
+            let lng_floor = lng.floor();
+            let path = self.options.path_template.render(lat_floor, lng_floor);
+            Ok(((lat_floor as i32, lng_floor as i32), path))
+        }
     }
 
-    #[instrument(level = "debug", skip_all, fields(coord = format!("{},{}", lat, lng)))]
-    pub async fn get_elevation(&self, lat: f64, lng: f64) -> Result<i16, tokio::io::Error> {
-        TileSetWithCache::validate_coordinates(lat, lng)?;
+    /// Returns the cache key and a point inside each tile intersecting `bbox`,
+    /// or an error if there are more than `limit` tiles.
+    fn tiles_in_bbox(
+        &self,
+        bbox: &BoundingBox,
+        limit: usize,
+    ) -> Result<Vec<(TileKey, f64, f64)>, tokio::io::Error> {
+        let (columns, rows) = if self.options.format.is_raster() {
+            let (west, north) = mercator_tile(bbox.north, bbox.west, self.options.zoom);
+            let (east, south) = mercator_tile(bbox.south, bbox.east, self.options.zoom);
+            (
+                west.floor() as i32..=east.floor() as i32,
+                north.floor() as i32..=south.floor() as i32,
+            )
+        } else {
+            // A box ending on a degree line does not reach into the next tile
+            let last = |min: f64, max: f64, limit: i32| {
+                ((max.ceil() as i32 - 1).max(min.floor() as i32)).min(limit)
+            };
+            (
+                bbox.west.floor() as i32..=last(bbox.west, bbox.east, 179),
+                bbox.south.floor() as i32..=last(bbox.south, bbox.north, 89),
+            )
+        };
 
-        let lat_floor = lat.floor();
-        let lng_floor = lng.floor();
-        let cache_key = (lat_floor as i32, lng_floor as i32);
+        let count = columns.clone().count() * rows.clone().count();
+        if count > limit {
+            return Err(tokio::io::Error::new(
+                tokio::io::ErrorKind::InvalidInput,
+                format!(
+                    "Region covers {} tiles of layer '{}', at most {} are allowed",
+                    count, self.options.name, limit
+                ),
+            ));
+        }
 
-        debug!(lat_floor, lng_floor, "Getting elevation for coordinates");
+        Ok(columns
+            .flat_map(|column| rows.clone().map(move |row| (column, row)))
+            .map(|(column, row)| {
+                if self.options.format.is_raster() {
+                    let (lat, lng) =
+                        mercator_tile_center(self.options.zoom, column as u32, row as u32);
+                    ((column, row), lat, lng)
+                } else {
+                    ((row, column), row as f64 + 0.5, column as f64 + 0.5)
+                }
+            })
+            .collect())
+    }
+
+    #[instrument(level = "debug", skip_all, fields(layer = %self.options.name))]
+    async fn get_elevation(&self, lat: f64, lng: f64) -> Result<i16, tokio::io::Error> {
+        let (cache_key, tile_path) = self.get_tile_key(lat, lng)?;
+
+        debug!(?cache_key, "Getting elevation for coordinates");
+        let elevation = self
+            .get_elevations_in_tile(cache_key, &tile_path, &[(lat, lng)])
+            .await?[0];
+        debug!(elevation, "Retrieved elevation");
+        Ok(elevation)
+    }
+
+    /// Evaluates points of the same tile. With range reads, a few points of a tile
+    /// that is not cached are read sample by sample, otherwise the whole tile is loaded.
+    async fn get_elevations_in_tile(
+        &self,
+        cache_key: TileKey,
+        tile_path: &str,
+        points: &[(f64, f64)],
+    ) -> Result<Vec<i16>, tokio::io::Error> {
+        if let Some(range_reader) = &self.range_reader
+            && points.len() < WHOLE_TILE_MIN_POINTS
+            && !self.hgt_cache.contains_key(&cache_key)
+        {
+            self.check_missing(cache_key, tile_path)?;
+            let mut elevations = Vec::with_capacity(points.len());
+            for &(lat, lng) in points {
+                let elevation = range_reader
+                    .get_elevation(&self.tileset, cache_key, tile_path, lat, lng)
+                    .await
+                    .map_err(|e| self.convert_fetch_error(tile_path, e));
+                elevations.push(self.remember_missing(cache_key, elevation).await?);
+            }
+            return Ok(elevations);
+        }
 
-        // Cache HGT instances instead of raw tile data for better performance
-        let hgt = self
+        let tile = self.load_tile(cache_key, tile_path).await?;
+        points
+            .iter()
+            .map(|&(lat, lng)| tile.get_elevation(lat, lng))
+            .collect()
+    }
+
+    /// Returns the decoded tile, loading it into the cache if needed.
+    async fn load_tile(
+        &self,
+        cache_key: TileKey,
+        tile_path: &str,
+    ) -> Result<Arc<Tile>, tokio::io::Error> {
+        self.check_missing(cache_key, tile_path)?;
+        // Cache decoded tiles instead of raw tile data for better performance
+        let labels = [KeyValue::new("layer", self.options.name.clone())];
+        let tile = self
             .hgt_cache
-            .try_get_with(cache_key, async {
+            .entry(cache_key)
+            .or_try_insert_with(async {
                 debug!("Loading tile data from cache or source");
-                // Load tile data and create HGT instance
-                let tile_data = self.get_tile_data(lat_floor, lng_floor).await?;
-                let hgt = HGT::new(tile_data, (lat_floor, lng_floor))?;
-                info!(lat_floor, lng_floor, "Loaded and cached HGT tile");
-                Ok::<Arc<HGT>, tokio::io::Error>(Arc::new(hgt))
+                let tile = match &self.tileset {
+                    TileSet::File(file_tileset) if self.options.mmap => {
+                        let mmap = file_tileset
+                            .map_tile(tile_path)
+                            .await
+                            .map_err(|e| self.convert_fetch_error(tile_path, e))?;
+                        Tile::Hgt(HGT::from_mmap(
+                            mmap,
+                            (cache_key.0 as f64, cache_key.1 as f64),
+                        )?)
+                    }
+                    _ => {
+                        // Load tile data and decode it
+                        self.get_tile(tile_path, cache_key).await?
+                    }
+                };
+                info!(tile_path, layer = %self.options.name, "Loaded and cached tile");
+                Ok::<Arc<Tile>, tokio::io::Error>(Arc::new(tile))
             })
             .await
-            .map_err(|e| tokio::io::Error::new(tokio::io::ErrorKind::Other, e))?;
+            .map(|entry| {
+                // Callers that waited for a concurrent load of the same tile count as hits
+                match entry.is_fresh() {
+                    true => METRICS.cache_misses.add(1, &labels),
+                    false => METRICS.cache_hits.add(1, &labels),
+                }
+                entry.into_value()
+            })
+            // Keep the error kind so that missing tiles can fall through to the next layer
+            .map_err(|e| {
+                METRICS.cache_misses.add(1, &labels);
+                tokio::io::Error::new(e.kind(), e)
+            });
+        self.remember_missing(cache_key, tile).await
+    }
 
-        let elevation = hgt.get_elevation(lat, lng)?;
-        debug!(elevation, "Retrieved elevation");
-        Ok(elevation)
+    /// Fails fast for tiles the backend recently reported as not existing.
+    fn check_missing(&self, cache_key: TileKey, tile_path: &str) -> Result<(), tokio::io::Error> {
+        match &self.missing_tiles {
+            Some(missing_tiles) if missing_tiles.contains_key(&cache_key) => {
+                self.missing_tile_hits.fetch_add(1, Ordering::Relaxed);
+                METRICS
+                    .missing_tile_cache_hits
+                    .add(1, &[KeyValue::new("layer", self.options.name.clone())]);
+                debug!(tile_path, layer = %self.options.name, missing_tile_cache_hit = true, "Tile is known to be missing");
+                Err(tokio::io::Error::new(
+                    tokio::io::ErrorKind::NotFound,
+                    format!("Tile not found at '{}' (cached)", tile_path),
+                ))
+            }
+            _ => Ok(()),
+        }
     }
 
-    fn validate_coordinates(lat: f64, lng: f64) -> Result<(), std::io::Error> {
-        if lat < -90.0 || lat > 90.0 || lng < -180.0 || lng > 180.0 {
-            return Err(std::io::Error::new(
-                std::io::ErrorKind::InvalidInput,
-                "Latitude must be between -90 and 90, and longitude must be between -180 and 180.",
-            ));
+    /// Remembers tiles that do not exist. Only genuine not-found answers are cached,
+    /// transient errors are retried by the next lookup.
+    async fn remember_missing<T>(
+        &self,
+        cache_key: TileKey,
+        result: Result<T, tokio::io::Error>,
+    ) -> Result<T, tokio::io::Error> {
+        if let (Some(missing_tiles), Err(e)) = (&self.missing_tiles, &result)
+            && e.kind() == tokio::io::ErrorKind::NotFound
+        {
+            missing_tiles.insert(cache_key, ()).await;
         }
-        Ok(())
+        result
     }
 
-    #[instrument(level = "debug", skip_all, fields(coord = format!("{},{}", lat_floor, lng_floor)))]
-    async fn get_tile_data(
+    /// Returns the number of cached tiles and the bytes they use.
+    fn cache_usage(&self) -> (u64, u64) {
+        let (tiles, bytes) = self
+            .hgt_cache
+            .iter()
+            .fold((0, 0), |(tiles, bytes), (_, tile)| {
+                (tiles + 1, bytes + tile.size_bytes() as u64)
+            });
+        // Blocks fetched by range reads count towards the bytes, not the tiles
+        let (_, block_bytes) = self
+            .range_reader
+            .as_ref()
+            .map_or((0, 0), RangeReader::cache_usage);
+        (tiles, bytes + block_bytes)
+    }
+
+    fn decode_tile(
         &self,
-        lat_floor: f64,
-        lng_floor: f64,
-    ) -> Result<Vec<u8>, tokio::io::Error> {
-        debug!("Fetching tile data for coordinates");
+        tile_data: Vec<u8>,
+        cache_key: (i32, i32),
+    ) -> Result<Tile, std::io::Error> {
+        match self.options.format {
+            TileFormat::Hgt => Ok(Tile::Hgt(HGT::new(
+                tile_data,
+                (cache_key.0 as f64, cache_key.1 as f64),
+            )?)),
+            format => Ok(Tile::Raster(RasterTile::new(
+                &tile_data,
+                format,
+                self.options.zoom,
+                cache_key.0 as u32,
+                cache_key.1 as u32,
+            )?)),
+        }
+    }
+
+    /// Loads a tile from the disk cache or the source and decodes it. Cached entries that
+    /// cannot be decoded are dropped and the tile is fetched again.
+    #[instrument(level = "debug", skip(self))]
+    async fn get_tile(
+        &self,
+        tile_path: &str,
+        cache_key: TileKey,
+    ) -> Result<Tile, tokio::io::Error> {
+        if let Some((cache, namespace)) = &self.disk_cache
+            && let Some(data) = cache.get(namespace, tile_path).await
+        {
+            let data = if cache.stores_decompressed() || !self.options.gzip {
+                Ok(data)
+            } else {
+                decompress_tile(&data)
+            };
+            match data.and_then(|data| self.decode_tile(data, cache_key)) {
+                Ok(tile) => return Ok(tile),
+                Err(e) => {
+                    warn!(tile_path, error = %e, "Dropping corrupt disk cache entry");
+                    cache.remove(namespace, tile_path).await;
+                }
+            }
+        }
+
+        let data = self.fetch_tile(tile_path).await?;
+        let (data, compressed) = if self.options.gzip {
+            (decompress_tile(&data)?, Some(data))
+        } else {
+            (data, None)
+        };
+        let entry = self.disk_cache.as_ref().map(|(cache, _)| match compressed {
+            Some(compressed) if !cache.stores_decompressed() => compressed,
+            _ => data.clone(),
+        });
+        let tile = self.decode_tile(data, cache_key)?;
+
+        // Only tiles that decode are cached, writing to disk must not delay the response
+        if let (Some((cache, namespace)), Some(entry)) = (&self.disk_cache, entry) {
+            tokio::spawn(
+                cache
+                    .clone()
+                    .put(namespace.clone(), tile_path.to_string(), entry),
+            );
+        }
+        Ok(tile)
+    }
+
+    async fn fetch_tile(&self, tile_path: &str) -> Result<Vec<u8>, tokio::io::Error> {
+        debug!("Fetching tile data");
+        let start = Instant::now();
         let tileset = match &self.tileset {
-            TileSet::File(file_tileset) => file_tileset.get_tile(lat_floor, lng_floor).await,
-            TileSet::HTTP(s3_tileset) => s3_tileset.get_tile(lat_floor, lng_floor).await,
-            TileSet::S3(s3_tileset) => s3_tileset.get_tile(lat_floor, lng_floor).await,
+            TileSet::File(file_tileset) => file_tileset.get_tile(tile_path).await,
+            TileSet::HTTP(http_tileset) => http_tileset.get_tile(tile_path).await,
+            TileSet::S3(s3_tileset) => s3_tileset.get_tile(tile_path).await,
         };
+        record_fetch(&self.tileset, start, &tileset);
+
+        tileset.map_err(|e| self.convert_fetch_error(tile_path, e))
+    }
 
-        match tileset {
-            Ok(data) => Ok(data),
-            Err(e) => Err(tokio::io::Error::new(
+    /// Keeps the error kind: a missing tile is not the same as an unreachable backend.
+    fn convert_fetch_error(&self, tile_path: &str, e: tokio::io::Error) -> tokio::io::Error {
+        match e.kind() {
+            tokio::io::ErrorKind::NotFound => tokio::io::Error::new(
                 tokio::io::ErrorKind::NotFound,
+                format!("Tile not found at '{}': {}", tile_path, e),
+            ),
+            kind => {
+                error!(tile_path, error = %e, layer = %self.options.name, "Failed to fetch tile");
+                tokio::io::Error::new(kind, format!("Failed to fetch tile '{}': {}", tile_path, e))
+            }
+        }
+    }
+}
+
+/// What to answer for points without any tile, e.g. over the open ocean.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MissingTilePolicy {
+    /// Fail the request with a 404
+    Error,
+    /// Report sea level
+    Zero,
+    /// Report `null`
+    Null,
+    /// Look the point up in the bathymetry dataset
+    Bathymetry,
+}
+
+impl FromStr for MissingTilePolicy {
+    type Err = std::io::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "error" => Ok(MissingTilePolicy::Error),
+            "zero" | "sea-level" => Ok(MissingTilePolicy::Zero),
+            "null" => Ok(MissingTilePolicy::Null),
+            "bathymetry" => Ok(MissingTilePolicy::Bathymetry),
+            _ => Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
                 format!(
-                    "Tile not found for coordinates ({}, {}): {}",
-                    lat_floor, lng_floor, e
+                    "Unknown missing tile policy '{}' (error, zero, null and bathymetry supported)",
+                    s
                 ),
             )),
         }
     }
 }
 
+/// A tile of one layer to load ahead of requests, identified by a point inside it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct WarmupTile {
+    layer: usize,
+    lat: f64,
+    lng: f64,
+}
+
+/// Ordered list of tile sources. Lookups try each layer in turn and fall back
+/// to the next one when the tile is missing or the sample is a void.
+pub struct TileSetWithCache {
+    layers: Vec<TileSetLayer>,
+    missing_tile_policy: MissingTilePolicy,
+    bathymetry: Option<Arc<TileSetWithCache>>,
+}
+
+impl TileSetWithCache {
+    pub fn new(layers: Vec<TileSetOptions>) -> Result<Self, Box<dyn std::error::Error>> {
+        if layers.is_empty() {
+            return Err("At least one tile set layer is required".into());
+        }
+
+        let layers = layers
+            .into_iter()
+            .map(TileSetLayer::new)
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(Self {
+            layers,
+            missing_tile_policy: MissingTilePolicy::Error,
+            bathymetry: None,
+        })
+    }
+
+    /// Sets how points without tiles are answered. The bathymetry policy needs
+    /// the tile set to consult.
+    pub fn with_missing_tile_policy(
+        mut self,
+        policy: MissingTilePolicy,
+        bathymetry: Option<Arc<TileSetWithCache>>,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        if policy == MissingTilePolicy::Bathymetry && bathymetry.is_none() {
+            return Err("The bathymetry missing tile policy requires a bathymetry dataset".into());
+        }
+        self.missing_tile_policy = policy;
+        self.bathymetry = bathymetry;
+        Ok(self)
+    }
+
+    pub fn missing_tile_policy(&self) -> MissingTilePolicy {
+        self.missing_tile_policy
+    }
+
+    /// Like `get_elevation`, but answers points without tiles according to
+    /// the missing tile policy. `None` is reported as `null`.
+    pub async fn get_elevation_with_policy(
+        &self,
+        lat: f64,
+        lng: f64,
+    ) -> Result<Option<i16>, tokio::io::Error> {
+        match self.get_elevation(lat, lng).await {
+            Ok(elevation) => Ok(Some(elevation)),
+            Err(e) if e.kind() == tokio::io::ErrorKind::NotFound => {
+                debug!(policy = ?self.missing_tile_policy, "No tile for coordinates");
+                match (self.missing_tile_policy, &self.bathymetry) {
+                    (MissingTilePolicy::Zero, _) => Ok(Some(0)),
+                    (MissingTilePolicy::Null, _) => Ok(None),
+                    (MissingTilePolicy::Bathymetry, Some(bathymetry)) => {
+                        bathymetry.get_elevation(lat, lng).await.map(Some)
+                    }
+                    _ => Err(e),
+                }
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Batch version of `get_elevation_with_policy`. Points are grouped by tile, so
+    /// each tile is loaded once and all of its points are evaluated together. At most
+    /// `concurrency` tiles are loaded at the same time. The results keep the input order.
+    #[instrument(level = "debug", skip_all, fields(points = points.len()))]
+    pub async fn get_elevations_with_policy(
+        &self,
+        points: &[(f64, f64)],
+        concurrency: usize,
+    ) -> Result<Vec<Option<i16>>, tokio::io::Error> {
+        let mut elevations = self.get_elevations(points, concurrency).await?;
+        let missing = (0..points.len())
+            .filter(|&index| elevations[index].is_none())
+            .collect::<Vec<_>>();
+        let Some(&first_missing) = missing.first() else {
+            return Ok(elevations);
+        };
+        debug!(policy = ?self.missing_tile_policy, missing = missing.len(), "No tiles for points");
+
+        let not_found = |index: usize| {
+            tokio::io::Error::new(
+                tokio::io::ErrorKind::NotFound,
+                format!(
+                    "No elevation data for coordinates ({}, {})",
+                    points[index].0, points[index].1
+                ),
+            )
+        };
+        match (self.missing_tile_policy, &self.bathymetry) {
+            (MissingTilePolicy::Zero, _) => {
+                for index in missing {
+                    elevations[index] = Some(0);
+                }
+            }
+            (MissingTilePolicy::Null, _) => {}
+            (MissingTilePolicy::Bathymetry, Some(bathymetry)) => {
+                let missing_points = missing
+                    .iter()
+                    .map(|&index| points[index])
+                    .collect::<Vec<_>>();
+                let depths = bathymetry
+                    .get_elevations(&missing_points, concurrency)
+                    .await?;
+                for (index, depth) in missing.into_iter().zip(depths) {
+                    elevations[index] = Some(depth.ok_or_else(|| not_found(index))?);
+                }
+            }
+            _ => return Err(not_found(first_missing)),
+        }
+        Ok(elevations)
+    }
+
+    /// Looks up a batch of points layer by layer. `None` marks points without
+    /// data in any layer.
+    async fn get_elevations(
+        &self,
+        points: &[(f64, f64)],
+        concurrency: usize,
+    ) -> Result<Vec<Option<i16>>, tokio::io::Error> {
+        for &(lat, lng) in points {
+            TileSetWithCache::validate_coordinates(lat, lng)?;
+        }
+
+        let mut elevations = vec![None; points.len()];
+        let mut pending = (0..points.len()).collect::<Vec<_>>();
+        for layer in &self.layers {
+            if pending.is_empty() {
+                break;
+            }
+
+            let mut groups: HashMap<TileKey, (String, Vec<usize>)> = HashMap::new();
+            let mut uncovered = Vec::new();
+            for index in pending.drain(..) {
+                match layer.get_tile_key(points[index].0, points[index].1) {
+                    Ok((cache_key, tile_path)) => groups
+                        .entry(cache_key)
+                        .or_insert_with(|| (tile_path, Vec::new()))
+                        .1
+                        .push(index),
+                    Err(_) => uncovered.push(index),
+                }
+            }
+            pending.extend(uncovered);
+            debug!(layer = %layer.options.name, tiles = groups.len(), "Grouped points by tile");
+
+            let mut tiles = futures::stream::iter(groups)
+                .map(|(cache_key, (tile_path, indexes))| async move {
+                    let tile_points = indexes
+                        .iter()
+                        .map(|&index| points[index])
+                        .collect::<Vec<_>>();
+                    let tile_elevations = layer
+                        .get_elevations_in_tile(cache_key, &tile_path, &tile_points)
+                        .await;
+                    (tile_elevations, indexes)
+                })
+                .buffer_unordered(concurrency.max(1));
+            while let Some((tile_elevations, indexes)) = tiles.next().await {
+                let tile_elevations = match tile_elevations {
+                    Ok(tile_elevations) => tile_elevations,
+                    Err(e) if e.kind() == tokio::io::ErrorKind::NotFound => {
+                        debug!(layer = %layer.options.name, error = %e, "Tile missing, trying next layer");
+                        pending.extend(indexes);
+                        continue;
+                    }
+                    Err(e) => return Err(e),
+                };
+                for (index, elevation) in indexes.into_iter().zip(tile_elevations) {
+                    match elevation {
+                        VOID_VALUE => pending.push(index),
+                        elevation => elevations[index] = Some(elevation),
+                    }
+                }
+            }
+        }
+        Ok(elevations)
+    }
+
+    #[instrument(level = "debug", skip_all, fields(coord = format!("{},{}", lat, lng)))]
+    pub async fn get_elevation(&self, lat: f64, lng: f64) -> Result<i16, tokio::io::Error> {
+        TileSetWithCache::validate_coordinates(lat, lng)?;
+
+        let mut last_error = None;
+        for layer in &self.layers {
+            match layer.get_elevation(lat, lng).await {
+                Ok(VOID_VALUE) => {
+                    debug!(layer = %layer.options.name, "Void sample, trying next layer");
+                }
+                Ok(elevation) => return Ok(elevation),
+                Err(e) if e.kind() == tokio::io::ErrorKind::NotFound => {
+                    debug!(layer = %layer.options.name, error = %e, "Tile missing, trying next layer");
+                    last_error = Some(e);
+                }
+                Err(e) => return Err(e),
+            }
+        }
+
+        Err(last_error.unwrap_or_else(|| {
+            tokio::io::Error::new(
+                tokio::io::ErrorKind::NotFound,
+                format!("No elevation data for coordinates ({}, {})", lat, lng),
+            )
+        }))
+    }
+
+    /// Returns the tiles of all layers intersecting the bounding boxes, each once.
+    pub fn tiles_in_bboxes(
+        &self,
+        bboxes: &[BoundingBox],
+        limit: usize,
+    ) -> Result<Vec<WarmupTile>, tokio::io::Error> {
+        let mut seen = HashSet::new();
+        let mut tiles = Vec::new();
+        for bbox in bboxes {
+            for (index, layer) in self.layers.iter().enumerate() {
+                for (key, lat, lng) in layer.tiles_in_bbox(bbox, limit)? {
+                    if seen.insert((index, key)) {
+                        tiles.push(WarmupTile {
+                            layer: index,
+                            lat,
+                            lng,
+                        });
+                    }
+                }
+                if tiles.len() > limit {
+                    return Err(tokio::io::Error::new(
+                        tokio::io::ErrorKind::InvalidInput,
+                        format!("Regions cover more than {} tiles", limit),
+                    ));
+                }
+            }
+        }
+        Ok(tiles)
+    }
+
+    /// Loads a tile into the caches of its layer.
+    pub async fn warm_up_tile(&self, tile: &WarmupTile) -> Result<(), tokio::io::Error> {
+        let layer = &self.layers[tile.layer];
+        let (cache_key, tile_path) = layer.get_tile_key(tile.lat, tile.lng)?;
+        layer.load_tile(cache_key, &tile_path).await.map(|_| ())
+    }
+
+    /// Describes the layers of this tile set in lookup order.
+    pub fn layers_info(&self) -> Vec<LayerInfo> {
+        self.layers
+            .iter()
+            .map(|layer| {
+                let (cached_tiles, cache_bytes) = layer.cache_usage();
+                LayerInfo {
+                    name: layer.options.name.clone(),
+                    backend: layer.tileset.kind(),
+                    backend_reachable: layer.tileset.is_reachable(),
+                    cache_size: layer.options.cache_size.to_string(),
+                    cached_tiles,
+                    cache_bytes,
+                    gzip: layer.options.gzip,
+                    path_template: layer.options.path_template.to_string(),
+                    disk_cache: layer.disk_cache.is_some(),
+                    mmap: layer.options.mmap,
+                    range_reads: layer.options.range_reads,
+                    missing_tiles: layer
+                        .missing_tiles
+                        .as_ref()
+                        .map_or(0, |missing_tiles| missing_tiles.entry_count()),
+                    missing_tile_hits: layer.missing_tile_hits.load(Ordering::Relaxed),
+                    format: layer.options.format.to_string(),
+                    zoom: layer
+                        .options
+                        .format
+                        .is_raster()
+                        .then_some(layer.options.zoom),
+                }
+            })
+            .collect()
+    }
+
+    pub fn validate_coordinates(lat: f64, lng: f64) -> Result<(), std::io::Error> {
+        if !(-90.0..=90.0).contains(&lat) || !(-180.0..=180.0).contains(&lng) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "Latitude must be between -90 and 90, and longitude must be between -180 and 180.",
+            ));
+        }
+        Ok(())
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use std::io::ErrorKind;
 
     use super::*;
-    use tokio;
+    use crate::test_util::TempDir;
 
     #[tokio::test]
     async fn test_get_elevation() {
         let options = TileSetOptions {
+            name: String::from("default"),
             path: String::from("test_files"),
-            cache_size: 128,
+            cache_size: CacheSize::Tiles(128),
             gzip: true,
+            path_template: PathTemplate::default(),
+            format: TileFormat::Hgt,
+            zoom: 12,
+            mmap: false,
+            range_reads: false,
             s3_access_key_id: None,
             s3_secret_access_key: None,
             s3_region: None,
             s3_endpoint: None,
+            disk_cache: None,
+            resilience: ResilienceOptions::default(),
+            missing_tile_cache_size: 100_000,
+            missing_tile_ttl: Duration::from_secs(3600),
         };
-        let tileset = TileSetWithCache::new(options).unwrap();
+        let tileset = TileSetWithCache::new(vec![options]).unwrap();
         let elevation = tileset.get_elevation(45.123, 9.456).await;
         assert!(elevation.is_ok());
         let elevation_value = elevation.unwrap();
@@ -198,29 +1068,547 @@ mod tests {
     #[tokio::test]
     async fn test_get_elevation_invalid_coordinates() {
         let options = TileSetOptions {
+            name: String::from("default"),
             path: String::from("test_files"),
-            cache_size: 128,
+            cache_size: CacheSize::Tiles(128),
             gzip: true,
+            path_template: PathTemplate::default(),
+            format: TileFormat::Hgt,
+            zoom: 12,
+            mmap: false,
+            range_reads: false,
             s3_access_key_id: None,
             s3_secret_access_key: None,
             s3_region: None,
             s3_endpoint: None,
+            disk_cache: None,
+            resilience: ResilienceOptions::default(),
+            missing_tile_cache_size: 100_000,
+            missing_tile_ttl: Duration::from_secs(3600),
         };
-        let tileset = TileSetWithCache::new(options).unwrap();
+        let tileset = TileSetWithCache::new(vec![options]).unwrap();
         let elevation = tileset.get_elevation(100.0, 200.0).await;
         assert!(elevation.is_err());
         let error = elevation.unwrap_err();
         assert_eq!(error.kind(), ErrorKind::InvalidInput);
     }
 
+    /// Writes a gzipped 3 arcsecond tile filled with `value` below `dir`.
+    fn write_tile(dir: &std::path::Path, lat: f64, lng: f64, value: i16) {
+        use flate2::{Compression, write::GzEncoder};
+        use std::io::Write;
+
+        let path = dir.join(PathTemplate::default().render(lat, lng));
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::fast());
+        encoder
+            .write_all(&value.to_be_bytes().repeat(1201 * 1201))
+            .unwrap();
+        std::fs::write(path, encoder.finish().unwrap()).unwrap();
+    }
+
+    fn layer_options(name: &str, path: &std::path::Path) -> TileSetOptions {
+        TileSetOptions {
+            name: String::from(name),
+            path: path.to_string_lossy().into_owned(),
+            ..TileSetOptions::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_layers_fall_back_on_missing_tile() {
+        let root = TempDir::new("layers");
+        let high_res = root.join("missing");
+        let low_res = root.join("low");
+        write_tile(&low_res, 45.5, 9.5, 100);
+
+        let tileset = TileSetWithCache::new(vec![
+            layer_options("high", &high_res),
+            layer_options("low", &low_res),
+        ])
+        .unwrap();
+        let elevation = tileset.get_elevation(45.5, 9.5).await;
+        assert_eq!(elevation.unwrap(), 100);
+    }
+
+    #[tokio::test]
+    async fn test_layers_fall_back_on_void() {
+        let root = TempDir::new("voids");
+        let high_res = root.join("high");
+        let low_res = root.join("low");
+        write_tile(&high_res, 45.5, 9.5, VOID_VALUE);
+        write_tile(&low_res, 45.5, 9.5, 100);
+
+        let tileset = TileSetWithCache::new(vec![
+            layer_options("high", &high_res),
+            layer_options("low", &low_res),
+        ])
+        .unwrap();
+        let elevation = tileset.get_elevation(45.5, 9.5).await;
+        let missing = tileset.get_elevation(10.5, 9.5).await;
+        assert_eq!(elevation.unwrap(), 100);
+        assert_eq!(missing.unwrap_err().kind(), ErrorKind::NotFound);
+    }
+
+    #[tokio::test]
+    async fn test_terrarium_layer() {
+        let root = TempDir::new("terrarium");
+        let path = root.join(
+            PathTemplate::new(XYZ_TEMPLATE, false)
+                .unwrap()
+                .render_xyz(1, 1, 0),
+        );
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        let mut buffer = Vec::new();
+        let mut encoder = png::Encoder::new(&mut buffer, 2, 2);
+        encoder.set_color(png::ColorType::Rgb);
+        let mut writer = encoder.write_header().unwrap();
+        writer.write_image_data(&[128, 100, 0].repeat(4)).unwrap();
+        writer.finish().unwrap();
+        std::fs::write(&path, buffer).unwrap();
+        write_tile(&root.join("hgt"), 88.5, 90.5, 7);
+
+        let tileset = TileSetWithCache::new(vec![
+            TileSetOptions {
+                gzip: false,
+                path_template: PathTemplate::new(XYZ_TEMPLATE, false).unwrap(),
+                format: TileFormat::Terrarium,
+                zoom: 1,
+                ..layer_options("terrarium", &root)
+            },
+            layer_options("hgt", &root.join("hgt")),
+        ])
+        .unwrap();
+        let elevation = tileset.get_elevation(45.0, 90.0).await;
+        // Beyond the Web Mercator projection the next layer answers
+        let polar = tileset.get_elevation(88.5, 90.5).await;
+        let batch = tileset
+            .get_elevations(&[(45.0, 90.0), (88.5, 90.5)], 4)
+            .await;
+        assert_eq!(elevation.unwrap(), 100);
+        assert_eq!(polar.unwrap(), 7);
+        assert_eq!(batch.unwrap(), vec![Some(100), Some(7)]);
+    }
+
+    #[test]
+    fn test_new_rejects_mismatched_template() {
+        let options = TileSetOptions {
+            format: TileFormat::Terrarium,
+            ..TileSetOptions::default()
+        };
+        assert!(TileSetWithCache::new(vec![options]).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_missing_tile_policies() {
+        let root = TempDir::new("policy");
+        let bathymetry = root.join("bathymetry");
+        write_tile(&bathymetry, 10.5, 9.5, -3000);
+        let new_tileset = || TileSetWithCache::new(vec![layer_options("land", &root.join("land"))]);
+
+        let error = new_tileset().unwrap();
+        let zero = new_tileset()
+            .unwrap()
+            .with_missing_tile_policy(MissingTilePolicy::Zero, None)
+            .unwrap();
+        let null = new_tileset()
+            .unwrap()
+            .with_missing_tile_policy(MissingTilePolicy::Null, None)
+            .unwrap();
+        let bathymetry = new_tileset()
+            .unwrap()
+            .with_missing_tile_policy(
+                MissingTilePolicy::Bathymetry,
+                Some(Arc::new(
+                    TileSetWithCache::new(vec![layer_options("bathymetry", &bathymetry)]).unwrap(),
+                )),
+            )
+            .unwrap();
+
+        let error = error.get_elevation_with_policy(10.5, 9.5).await;
+        let zero = zero.get_elevation_with_policy(10.5, 9.5).await;
+        let null = null.get_elevation_with_policy(10.5, 9.5).await;
+        let bathymetry = bathymetry.get_elevation_with_policy(10.5, 9.5).await;
+        assert_eq!(error.unwrap_err().kind(), ErrorKind::NotFound);
+        assert_eq!(zero.unwrap(), Some(0));
+        assert_eq!(null.unwrap(), None);
+        assert_eq!(bathymetry.unwrap(), Some(-3000));
+    }
+
+    #[tokio::test]
+    async fn test_corrupt_tile_is_not_missing() {
+        let root = TempDir::new("corrupt");
+        let path = root.join(PathTemplate::default().render(10.0, 9.0));
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(&path, b"not a gzip file").unwrap();
+
+        let tileset = TileSetWithCache::new(vec![layer_options("corrupt", &root)])
+            .unwrap()
+            .with_missing_tile_policy(MissingTilePolicy::Zero, None)
+            .unwrap();
+        let elevation = tileset.get_elevation_with_policy(10.5, 9.5).await;
+        assert_eq!(elevation.unwrap_err().kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_bathymetry_policy_requires_dataset() {
+        let tileset = TileSetWithCache::new(vec![TileSetOptions::default()]).unwrap();
+        assert!(
+            tileset
+                .with_missing_tile_policy(MissingTilePolicy::Bathymetry, None)
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_new_requires_a_layer() {
+        assert!(TileSetWithCache::new(Vec::new()).is_err());
+    }
+
+    #[test]
+    fn test_parse_cache_size() {
+        assert_eq!("128".parse::<CacheSize>().unwrap(), CacheSize::Tiles(128));
+        assert_eq!(
+            "4GB".parse::<CacheSize>().unwrap(),
+            CacheSize::Bytes(4_000_000_000)
+        );
+        assert_eq!(
+            "512 MiB".parse::<CacheSize>().unwrap(),
+            CacheSize::Bytes(512 * 1024 * 1024)
+        );
+        assert!("lots".parse::<CacheSize>().is_err());
+    }
+
+    #[test]
+    fn test_split_cache_size() {
+        assert_eq!(
+            CacheSize::Tiles(5).split(),
+            (CacheSize::Tiles(3), CacheSize::Tiles(2))
+        );
+        assert_eq!(
+            CacheSize::Bytes(1000).split(),
+            (CacheSize::Bytes(500), CacheSize::Bytes(500))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_byte_weighted_cache() {
+        let root = TempDir::new("weighted");
+        write_tile(&root, 45.5, 9.5, 100);
+        write_tile(&root, 46.5, 9.5, 200);
+        let tile_bytes = 1201 * 1201 * 2;
+
+        // Room for one 3 arcsecond tile only
+        let tileset = TileSetWithCache::new(vec![TileSetOptions {
+            cache_size: CacheSize::Bytes(tile_bytes + tile_bytes / 2),
+            ..layer_options("weighted", &root)
+        }])
+        .unwrap();
+        tileset.get_elevation(45.5, 9.5).await.unwrap();
+        tileset.get_elevation(46.5, 9.5).await.unwrap();
+        let layer = &tileset.layers[0];
+        layer.hgt_cache.run_pending_tasks().await;
+
+        assert_eq!(layer.cache_usage(), (1, tile_bytes));
+        let info = tileset.layers_info();
+        assert_eq!(info[0].cache_size, "4.13 MiB");
+    }
+
+    #[tokio::test]
+    async fn test_mmap_layer() {
+        let root = TempDir::new("mmap");
+        let template = PathTemplate::new("{NAME}.hgt", false).unwrap();
+        std::fs::write(
+            root.join(template.render(45.0, 9.0)),
+            250i16.to_be_bytes().repeat(1201 * 1201),
+        )
+        .unwrap();
+
+        let tileset = TileSetWithCache::new(vec![TileSetOptions {
+            gzip: false,
+            path_template: template,
+            mmap: true,
+            ..layer_options("mapped", &root)
+        }])
+        .unwrap();
+        let elevation = tileset.get_elevation(45.5, 9.5).await;
+        let missing = tileset.get_elevation(46.5, 9.5).await;
+        tileset.layers[0].hgt_cache.run_pending_tasks().await;
+
+        assert_eq!(elevation.unwrap(), 250);
+        assert_eq!(missing.unwrap_err().kind(), ErrorKind::NotFound);
+        // Mapped tiles live in the page cache, not on the heap
+        assert_eq!(tileset.layers[0].cache_usage(), (1, 0));
+    }
+
+    #[test]
+    fn test_mmap_requires_uncompressed_local_tiles() {
+        let compressed = TileSetOptions {
+            mmap: true,
+            ..TileSetOptions::default()
+        };
+        let remote = TileSetOptions {
+            path: String::from("https://example.com/tiles"),
+            gzip: false,
+            mmap: true,
+            ..TileSetOptions::default()
+        };
+        assert!(TileSetWithCache::new(vec![compressed]).is_err());
+        assert!(TileSetWithCache::new(vec![remote]).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_range_reads_match_whole_tiles() {
+        let root = TempDir::new("range");
+        let template = PathTemplate::new("{NAME}.hgt", false).unwrap();
+        // Every sample differs, so reading a wrong offset changes the result
+        let samples = (0..1201 * 1201)
+            .flat_map(|i: i32| ((i % 30011) as i16).to_be_bytes())
+            .collect::<Vec<_>>();
+        std::fs::write(root.join(template.render(45.0, 9.0)), samples).unwrap();
+        let (address, server) =
+            warp::serve(warp::fs::dir(root.to_path_buf())).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+
+        let whole = TileSetWithCache::new(vec![TileSetOptions {
+            gzip: false,
+            path_template: template.clone(),
+            ..layer_options("whole", &root)
+        }])
+        .unwrap();
+        let ranged = TileSetWithCache::new(vec![TileSetOptions {
+            path: format!("http://{}", address),
+            gzip: false,
+            path_template: template,
+            range_reads: true,
+            ..TileSetOptions::default()
+        }])
+        .unwrap();
+        let points = [(45.0004, 9.0004), (45.5, 9.37), (45.9999, 9.9999)];
+        let mut expected = Vec::new();
+        let mut actual = Vec::new();
+        for (lat, lng) in points {
+            expected.push(whole.get_elevation(lat, lng).await.unwrap());
+            actual.push(ranged.get_elevation(lat, lng).await.unwrap());
+        }
+        let missing = ranged.get_elevation(46.5, 9.5).await;
+        let layer = &ranged.layers[0];
+        layer.hgt_cache.run_pending_tasks().await;
+        let (tiles, _) = layer.cache_usage();
+
+        assert_eq!(actual, expected);
+        assert_eq!(missing.unwrap_err().kind(), ErrorKind::NotFound);
+        assert_eq!(tiles, 0);
+    }
+
+    #[tokio::test]
+    async fn test_corrupt_disk_cache_entry_is_refetched() {
+        let root = TempDir::new("dropped");
+        let tiles = root.join("tiles");
+        let template = PathTemplate::new("{NAME}.hgt", false).unwrap();
+        let tile_path = template.render(45.0, 9.0);
+        std::fs::create_dir_all(&tiles).unwrap();
+        std::fs::write(
+            tiles.join(&tile_path),
+            100i16.to_be_bytes().repeat(1201 * 1201),
+        )
+        .unwrap();
+        let (address, server) =
+            warp::serve(warp::fs::dir(tiles)).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+
+        let path = format!("http://{}", address);
+        let namespace = disk_cache::namespace(&path);
+        let cache = Arc::new(DiskCache::new(root.join("cache"), 1 << 30, false).unwrap());
+        // A truncated entry, e.g. left behind by a full disk
+        cache
+            .clone()
+            .put(namespace.clone(), tile_path.clone(), vec![0; 10])
+            .await;
+        let tileset = TileSetWithCache::new(vec![TileSetOptions {
+            path,
+            gzip: false,
+            path_template: template,
+            disk_cache: Some(cache.clone()),
+            ..TileSetOptions::default()
+        }])
+        .unwrap();
+
+        let elevation = tileset.get_elevation(45.5, 9.5).await;
+        let mut cached = None;
+        for _ in 0..50 {
+            cached = cache.get(&namespace, &tile_path).await;
+            if cached.as_ref().is_some_and(|data| data.len() > 10) {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        assert_eq!(elevation.unwrap(), 100);
+        assert_eq!(cached.map(|data| data.len()), Some(1201 * 1201 * 2));
+    }
+
+    #[test]
+    fn test_range_reads_require_uncompressed_remote_tiles() {
+        let compressed = TileSetOptions {
+            path: String::from("https://example.com/tiles"),
+            range_reads: true,
+            ..TileSetOptions::default()
+        };
+        let local = TileSetOptions {
+            gzip: false,
+            range_reads: true,
+            ..TileSetOptions::default()
+        };
+        assert!(TileSetWithCache::new(vec![compressed]).is_err());
+        assert!(TileSetWithCache::new(vec![local]).is_err());
+        assert_eq!(parse_content_range("bytes 0-1/2884802"), Some(2884802));
+        assert_eq!(parse_content_range("bytes */2884802"), Some(2884802));
+        assert_eq!(parse_content_range("bytes 0-1/*"), None);
+    }
+
+    #[tokio::test]
+    async fn test_missing_tiles_are_cached() {
+        use std::sync::atomic::AtomicUsize;
+        use warp::Filter;
+
+        // N45E009 does not exist, the backend of N46E009 is failing
+        let requests = Arc::new(AtomicUsize::new(0));
+        let counter = requests.clone();
+        let route = warp::path::tail().map(move |tail: warp::path::Tail| {
+            counter.fetch_add(1, Ordering::SeqCst);
+            let status = match tail.as_str().contains("N45") {
+                true => warp::http::StatusCode::NOT_FOUND,
+                false => warp::http::StatusCode::SERVICE_UNAVAILABLE,
+            };
+            warp::reply::with_status("", status)
+        });
+        let (address, server) = warp::serve(route).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+
+        let tileset = TileSetWithCache::new(vec![TileSetOptions {
+            name: String::from("remembered"),
+            path: format!("http://{}", address),
+            resilience: ResilienceOptions {
+                max_retries: 0,
+                breaker_threshold: 0,
+                ..ResilienceOptions::default()
+            },
+            ..TileSetOptions::default()
+        }])
+        .unwrap();
+        for _ in 0..3 {
+            let missing = tileset.get_elevation(45.5, 9.5).await.unwrap_err();
+            assert_eq!(missing.kind(), ErrorKind::NotFound);
+        }
+        assert_eq!(requests.load(Ordering::SeqCst), 1);
+        for _ in 0..2 {
+            assert!(tileset.get_elevation(46.5, 9.5).await.is_err());
+        }
+        assert_eq!(requests.load(Ordering::SeqCst), 3);
+
+        tileset.layers[0]
+            .missing_tiles
+            .as_ref()
+            .unwrap()
+            .run_pending_tasks()
+            .await;
+        let info = tileset.layers_info();
+        assert_eq!(info[0].missing_tiles, 1);
+        assert_eq!(info[0].missing_tile_hits, 2);
+        let metrics = METRICS.render().unwrap();
+        assert!(metrics.contains(r#"missing_tile_cache_hits_total{layer="remembered"} 2"#));
+    }
+
+    #[tokio::test]
+    async fn test_tiles_in_bboxes() {
+        let root = TempDir::new("warmup-bboxes");
+        write_tile(&root, 45.5, 9.5, 100);
+        let tileset = TileSetWithCache::new(vec![
+            layer_options("hgt", &root),
+            TileSetOptions {
+                gzip: false,
+                path_template: PathTemplate::new(XYZ_TEMPLATE, false).unwrap(),
+                format: TileFormat::Terrarium,
+                zoom: 1,
+                ..layer_options("terrarium", &root)
+            },
+        ])
+        .unwrap();
+
+        let bbox = BoundingBox {
+            west: 9.0,
+            south: 45.0,
+            east: 11.0,
+            north: 45.5,
+        };
+        // Two HGT tiles and one raster tile, the overlapping box adds nothing
+        let tiles = tileset.tiles_in_bboxes(&[bbox, bbox], 100).unwrap();
+        assert_eq!(tiles.len(), 3);
+        assert_eq!(
+            tiles[0],
+            WarmupTile {
+                layer: 0,
+                lat: 45.5,
+                lng: 9.5
+            }
+        );
+        assert!(tileset.tiles_in_bboxes(&[bbox], 2).is_err());
+
+        tileset.warm_up_tile(&tiles[0]).await.unwrap();
+        let missing = tileset.warm_up_tile(&tiles[1]).await;
+        tileset.layers[0].hgt_cache.run_pending_tasks().await;
+        assert_eq!(missing.unwrap_err().kind(), ErrorKind::NotFound);
+        assert_eq!(tileset.layers[0].cache_usage().0, 1);
+    }
+
+    #[tokio::test]
+    async fn test_batch_keeps_input_order() {
+        let root = TempDir::new("batch");
+        let high_res = root.join("high");
+        let low_res = root.join("low");
+        write_tile(&high_res, 45.5, 9.5, 100);
+        write_tile(&high_res, 46.5, 9.5, VOID_VALUE);
+        write_tile(&low_res, 46.5, 9.5, 200);
+        let new_tileset = || {
+            TileSetWithCache::new(vec![
+                layer_options("high", &high_res),
+                layer_options("low", &low_res),
+            ])
+            .unwrap()
+        };
+        let points = [
+            (46.2, 9.1),
+            (45.5, 9.5),
+            (10.5, 9.5),
+            (45.1, 9.9),
+            (46.8, 9.3),
+        ];
+
+        let null = new_tileset()
+            .with_missing_tile_policy(MissingTilePolicy::Null, None)
+            .unwrap();
+        let elevations = null.get_elevations_with_policy(&points, 2).await;
+        let error = new_tileset().get_elevations_with_policy(&points, 2).await;
+        let invalid = new_tileset()
+            .get_elevations_with_policy(&[(45.5, 9.5), (95.0, 9.5)], 2)
+            .await;
+
+        assert_eq!(
+            elevations.unwrap(),
+            vec![Some(200), Some(100), None, Some(100), Some(200)]
+        );
+        assert_eq!(error.unwrap_err().kind(), ErrorKind::NotFound);
+        assert_eq!(invalid.unwrap_err().kind(), ErrorKind::InvalidInput);
+    }
+
     #[test]
     fn test_get_file_path() {
         let lat = 45.123;
         let lng = 9.456;
         let expected_path = "N45/N45E009.hgt.gz";
-        let file_path = TileSetWithCache::get_file_path(lat, lng);
-        assert!(file_path.is_ok());
-        assert_eq!(file_path.unwrap(), expected_path);
+        let file_path = PathTemplate::default().render(lat, lng);
+        assert_eq!(file_path, expected_path);
     }
 
     #[test]
@@ -228,9 +1616,8 @@ mod tests {
         let lat = -45.123;
         let lng = -9.456;
         let expected_path = "S45/S45W009.hgt.gz";
-        let file_path = TileSetWithCache::get_file_path(lat, lng);
-        assert!(file_path.is_ok());
-        assert_eq!(file_path.unwrap(), expected_path);
+        let file_path = PathTemplate::default().render(lat, lng);
+        assert_eq!(file_path, expected_path);
     }
 
     #[test]
@@ -238,9 +1625,8 @@ mod tests {
         let lat = 0.0;
         let lng = 0.0;
         let expected_path = "N00/N00E000.hgt.gz";
-        let file_path = TileSetWithCache::get_file_path(lat, lng);
-        assert!(file_path.is_ok());
-        assert_eq!(file_path.unwrap(), expected_path);
+        let file_path = PathTemplate::default().render(lat, lng);
+        assert_eq!(file_path, expected_path);
     }
 
     #[test]
@@ -248,9 +1634,8 @@ mod tests {
         let lat = -0.0;
         let lng = -0.0;
         let expected_path = "N00/N00E000.hgt.gz";
-        let file_path = TileSetWithCache::get_file_path(lat, lng);
-        assert!(file_path.is_ok());
-        assert_eq!(file_path.unwrap(), expected_path);
+        let file_path = PathTemplate::default().render(lat, lng);
+        assert_eq!(file_path, expected_path);
     }
 
     #[test]
@@ -258,33 +1643,43 @@ mod tests {
         let lat = 90.0;
         let lng = 180.0;
         let expected_path = "N90/N90E180.hgt.gz";
-        let file_path = TileSetWithCache::get_file_path(lat, lng);
-        assert!(file_path.is_ok());
-        assert_eq!(file_path.unwrap(), expected_path);
+        let file_path = PathTemplate::default().render(lat, lng);
+        assert_eq!(file_path, expected_path);
     }
 
     #[test]
     fn test_tile_set_options_default() {
         let options = TileSetOptions::default();
         assert_eq!(options.path, "");
-        assert_eq!(options.cache_size, 128);
+        assert_eq!(options.cache_size, CacheSize::Tiles(128));
         assert!(options.gzip);
     }
 
     #[test]
     fn test_tile_set_options_custom() {
         let options = TileSetOptions {
+            name: String::from("custom"),
             path: String::from("custom_path"),
-            cache_size: 256,
+            cache_size: CacheSize::Tiles(256),
             gzip: false,
+            path_template: PathTemplate::new("{LAT}{LNG}.{ext}", false).unwrap(),
+            format: TileFormat::Hgt,
+            zoom: 12,
+            mmap: false,
+            range_reads: false,
             s3_access_key_id: None,
             s3_secret_access_key: None,
             s3_region: None,
             s3_endpoint: None,
+            disk_cache: None,
+            resilience: ResilienceOptions::default(),
+            missing_tile_cache_size: 100_000,
+            missing_tile_ttl: Duration::from_secs(3600),
         };
 
         assert_eq!(options.path, "custom_path");
-        assert_eq!(options.cache_size, 256);
+        assert_eq!(options.cache_size, CacheSize::Tiles(256));
         assert!(!options.gzip);
+        assert_eq!(options.path_template.render(45.0, 9.0), "N45E009.hgt");
     }
 }
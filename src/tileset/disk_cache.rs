@@ -0,0 +1,344 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{Error, ErrorKind, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::{Duration, SystemTime};
+use tracing::{debug, info, instrument, warn};
+
+/// Suffix of entries that are still being written. They are renamed into place once complete.
+const TEMP_SUFFIX: &str = ".tmp";
+/// Suffix of entries holding decompressed tile data.
+const DECOMPRESSED_SUFFIX: &str = ".raw";
+/// Temporary files older than this were left behind by a crashed process.
+const STALE_TEMP_AGE: Duration = Duration::from_secs(3600);
+/// Eviction frees space down to this share of the budget, so it does not run on every write.
+const EVICTION_TARGET: f64 = 0.9;
+
+static TEMP_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+/// Tile cache on local disk, shared by all remote layers and surviving restarts.
+///
+/// Entries are written to a temporary file and renamed into place, so readers never
+/// see partial tiles, and the least recently used entries (by modification time, which
+/// is refreshed on every hit) are evicted once the byte budget is exceeded. Several
+/// processes may share the directory; each one evicts based on a full scan.
+#[derive(Debug)]
+pub struct DiskCache {
+    root: PathBuf,
+    max_bytes: u64,
+    decompressed: bool,
+    usage: AtomicU64, // Estimate between scans, other processes' writes are only seen by a scan
+    evicting: AtomicBool,
+}
+
+impl DiskCache {
+    pub fn new(root: PathBuf, max_bytes: u64, decompressed: bool) -> Result<Self, Error> {
+        fs::create_dir_all(&root)?;
+        let cache = Self {
+            root,
+            max_bytes,
+            decompressed,
+            usage: AtomicU64::new(0),
+            evicting: AtomicBool::new(false),
+        };
+        cache.evict()?;
+        info!(
+            path = %cache.root.display(),
+            usage = cache.usage(),
+            max_bytes,
+            decompressed,
+            "Opened disk tile cache"
+        );
+        Ok(cache)
+    }
+
+    /// Returns true if entries hold decompressed instead of the original tile data.
+    pub fn stores_decompressed(&self) -> bool {
+        self.decompressed
+    }
+
+    /// Bytes used by the cache as of the last scan plus the writes of this process since.
+    pub fn usage(&self) -> u64 {
+        self.usage.load(Ordering::Relaxed)
+    }
+
+    /// Returns the cached tile, or `None` on a miss. Errors are logged and treated as misses.
+    #[instrument(level = "debug", skip(self))]
+    pub async fn get(&self, namespace: &str, tile_path: &str) -> Option<Vec<u8>> {
+        let path = self.entry_path(namespace, tile_path);
+        let result = tokio::task::spawn_blocking(move || read_and_touch(&path))
+            .await
+            .map_err(Error::other)
+            .and_then(|result| result);
+
+        match result {
+            Ok(data) => {
+                debug!(bytes = data.len(), "Disk cache hit");
+                Some(data)
+            }
+            Err(e) if e.kind() == ErrorKind::NotFound => {
+                debug!("Disk cache miss");
+                None
+            }
+            Err(e) => {
+                warn!(error = %e, "Failed to read from disk cache");
+                None
+            }
+        }
+    }
+
+    /// Stores a tile, evicting old entries if the budget is exceeded. Errors are logged.
+    #[instrument(level = "debug", skip(self, data), fields(bytes = data.len()))]
+    pub async fn put(
+        self: std::sync::Arc<Self>,
+        namespace: String,
+        tile_path: String,
+        data: Vec<u8>,
+    ) {
+        let result = tokio::task::spawn_blocking(move || {
+            let path = self.entry_path(&namespace, &tile_path);
+            write_atomically(&path, &data)?;
+            let usage =
+                self.usage.fetch_add(data.len() as u64, Ordering::Relaxed) + data.len() as u64;
+            if usage > self.max_bytes && !self.evicting.swap(true, Ordering::AcqRel) {
+                let result = self.evict();
+                self.evicting.store(false, Ordering::Release);
+                result?;
+            }
+            Ok::<(), Error>(())
+        })
+        .await
+        .map_err(Error::other)
+        .and_then(|result| result);
+
+        if let Err(e) = result {
+            warn!(error = %e, "Failed to write to disk cache");
+        }
+    }
+
+    /// Drops an entry, e.g. because it turned out to be corrupt.
+    pub async fn remove(&self, namespace: &str, tile_path: &str) {
+        let path = self.entry_path(namespace, tile_path);
+        if let Err(e) = tokio::fs::remove_file(&path).await
+            && e.kind() != ErrorKind::NotFound
+        {
+            warn!(path = %path.display(), error = %e, "Failed to remove disk cache entry");
+        }
+    }
+
+    /// Returns the file of an entry. The tile path is stripped of anything that could
+    /// leave the namespace directory.
+    fn entry_path(&self, namespace: &str, tile_path: &str) -> PathBuf {
+        let mut path = self.root.join(namespace);
+        for component in tile_path.split('/') {
+            if !matches!(component, "" | "." | "..") {
+                path.push(component);
+            }
+        }
+        if self.decompressed {
+            path.as_mut_os_string().push(DECOMPRESSED_SUFFIX);
+        }
+        path
+    }
+
+    /// Scans the cache directory, removes stale temporary files and deletes the least
+    /// recently used entries until the usage is below the eviction target.
+    fn evict(&self) -> Result<(), Error> {
+        let mut entries = Vec::new();
+        scan(&self.root, &mut entries)?;
+
+        let now = SystemTime::now();
+        let mut usage = 0;
+        let mut files = Vec::new();
+        for (path, len, modified) in entries {
+            let is_temp = path.to_string_lossy().ends_with(TEMP_SUFFIX);
+            let age = now.duration_since(modified).unwrap_or_default();
+            if is_temp && age > STALE_TEMP_AGE {
+                remove_entry(&path);
+            } else if is_temp {
+                usage += len;
+            } else {
+                usage += len;
+                files.push((path, len, modified));
+            }
+        }
+
+        if usage > self.max_bytes {
+            let target = (self.max_bytes as f64 * EVICTION_TARGET) as u64;
+            let before = usage;
+            files.sort_by_key(|(_, _, modified)| *modified);
+            for (path, len, _) in files {
+                if usage <= target {
+                    break;
+                }
+                remove_entry(&path);
+                usage -= len;
+            }
+            info!(before, after = usage, "Evicted tiles from disk cache");
+        }
+
+        self.usage.store(usage, Ordering::Relaxed);
+        Ok(())
+    }
+}
+
+/// Derives a directory name for a tile source from its path or URL.
+pub fn namespace(path: &str) -> String {
+    path.chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '-' || c == '.' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+fn read_and_touch(path: &Path) -> Result<Vec<u8>, Error> {
+    let mut file = OpenOptions::new().read(true).write(true).open(path)?;
+    let mut data = Vec::new();
+    file.read_to_end(&mut data)?;
+    // The modification time is the recency used for eviction
+    file.set_modified(SystemTime::now())?;
+    Ok(data)
+}
+
+fn write_atomically(path: &Path, data: &[u8]) -> Result<(), Error> {
+    let parent = path
+        .parent()
+        .ok_or_else(|| Error::new(ErrorKind::InvalidInput, "Cache entry has no parent"))?;
+    fs::create_dir_all(parent)?;
+
+    let mut temp_name = path.file_name().unwrap_or_default().to_os_string();
+    temp_name.push(format!(
+        ".{}.{}{}",
+        std::process::id(),
+        TEMP_COUNTER.fetch_add(1, Ordering::Relaxed),
+        TEMP_SUFFIX
+    ));
+    let temp_path = parent.join(temp_name);
+
+    let result = File::create(&temp_path).and_then(|mut file| {
+        file.write_all(data)?;
+        file.sync_data()
+    });
+    match result.and_then(|_| fs::rename(&temp_path, path)) {
+        Ok(()) => Ok(()),
+        Err(e) => {
+            remove_entry(&temp_path);
+            Err(e)
+        }
+    }
+}
+
+fn scan(dir: &Path, entries: &mut Vec<(PathBuf, u64, SystemTime)>) -> Result<(), Error> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        // Entries may vanish while scanning when another process evicts
+        let metadata = match entry.metadata() {
+            Ok(metadata) => metadata,
+            Err(e) if e.kind() == ErrorKind::NotFound => continue,
+            Err(e) => return Err(e),
+        };
+        if metadata.is_dir() {
+            match scan(&entry.path(), entries) {
+                Err(e) if e.kind() != ErrorKind::NotFound => return Err(e),
+                _ => {}
+            }
+        } else {
+            entries.push((entry.path(), metadata.len(), metadata.modified()?));
+        }
+    }
+    Ok(())
+}
+
+fn remove_entry(path: &Path) {
+    if let Err(e) = fs::remove_file(path)
+        && e.kind() != ErrorKind::NotFound
+    {
+        warn!(path = %path.display(), error = %e, "Failed to remove disk cache entry");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempDir;
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn test_put_and_get() {
+        let root = TempDir::new("disk-cache-roundtrip");
+        let cache = Arc::new(DiskCache::new(root.to_path_buf(), 1024, false).unwrap());
+        cache
+            .clone()
+            .put(
+                String::from("s3___tiles"),
+                String::from("N45/N45E009.hgt.gz"),
+                vec![1, 2, 3],
+            )
+            .await;
+
+        let hit = cache.get("s3___tiles", "N45/N45E009.hgt.gz").await;
+        let miss = cache.get("s3___tiles", "N46/N46E009.hgt.gz").await;
+        let reopened = DiskCache::new(root.to_path_buf(), 1024, false).unwrap();
+        let usage = reopened.usage();
+        assert_eq!(hit, Some(vec![1, 2, 3]));
+        assert_eq!(miss, None);
+        assert_eq!(usage, 3);
+    }
+
+    #[tokio::test]
+    async fn test_evicts_least_recently_used() {
+        let root = TempDir::new("disk-cache-eviction");
+        let cache = Arc::new(DiskCache::new(root.to_path_buf(), 250, false).unwrap());
+        for name in ["a", "b"] {
+            cache
+                .clone()
+                .put(String::from("tiles"), String::from(name), vec![0; 100])
+                .await;
+        }
+        // Make "a" the most recently used entry
+        let old = SystemTime::now() - Duration::from_secs(60);
+        File::options()
+            .write(true)
+            .open(cache.entry_path("tiles", "b"))
+            .unwrap()
+            .set_modified(old)
+            .unwrap();
+        assert!(cache.get("tiles", "a").await.is_some());
+        cache
+            .clone()
+            .put(String::from("tiles"), String::from("c"), vec![0; 100])
+            .await;
+
+        let a = cache.get("tiles", "a").await;
+        let b = cache.get("tiles", "b").await;
+        let c = cache.get("tiles", "c").await;
+        let usage = cache.usage();
+        assert!(a.is_some());
+        assert!(b.is_none());
+        assert!(c.is_some());
+        assert_eq!(usage, 200);
+    }
+
+    #[test]
+    fn test_entry_path_stays_in_namespace() {
+        let cache = DiskCache {
+            root: PathBuf::from("/cache"),
+            max_bytes: 0,
+            decompressed: true,
+            usage: AtomicU64::new(0),
+            evicting: AtomicBool::new(false),
+        };
+        assert_eq!(
+            cache.entry_path("tiles", "/../../etc/N45E009.hgt.gz"),
+            PathBuf::from("/cache/tiles/etc/N45E009.hgt.gz.raw")
+        );
+        assert_eq!(
+            namespace("s3://elevation-tiles-prod/skadi"),
+            "s3___elevation-tiles-prod_skadi"
+        );
+    }
+}
@@ -1,44 +1,45 @@
-use crate::tileset::{TileSetOptions, TileSetWithCache};
-use flate2::read::GzDecoder;
-use std::io::Read;
+use memmap2::Mmap;
+use std::io::Error;
 use std::path::PathBuf;
 use tokio::fs;
 use tracing::{debug, instrument};
 
 pub struct FileTileSet {
     folder: PathBuf,
-    options: TileSetOptions,
 }
 
 impl FileTileSet {
-    pub fn new(folder: String, options: TileSetOptions) -> Self {
+    pub fn new(folder: String) -> Self {
         Self {
             folder: PathBuf::from(folder),
-            options,
         }
     }
 
-    #[instrument(level="debug", name = "get_tile_file", skip_all, fields(coord = format!("{},{}", lat, lng)))]
-    pub async fn get_tile(
-        &self,
-        lat: f64,
-        lng: f64,
-    ) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
-        let tile_path: String =
-            TileSetWithCache::get_file_path(lat, lng).expect("Failed to get tile path");
+    #[instrument(level = "debug", name = "get_tile_file", skip(self))]
+    pub async fn get_tile(&self, tile_path: &str) -> Result<Vec<u8>, Error> {
         let file_path = self.folder.join(tile_path);
         debug!("Fetching tile from: {:?}", file_path);
 
-        let buffer = fs::read(file_path.as_path()).await?;
+        fs::read(file_path.as_path()).await
+    }
 
-        // Handle gzip decompression if needed
-        if self.options.gzip {
-            let mut decoder = GzDecoder::new(&buffer[..]);
-            let mut decompressed = Vec::new();
-            decoder.read_to_end(&mut decompressed)?;
-            Ok(decompressed)
-        } else {
-            Ok(buffer)
-        }
+    /// Maps an uncompressed tile into memory instead of reading it.
+    #[instrument(level = "debug", name = "map_tile_file", skip(self))]
+    pub async fn map_tile(&self, tile_path: &str) -> Result<Mmap, Error> {
+        let file_path = self.folder.join(tile_path);
+        debug!("Mapping tile: {:?}", file_path);
+
+        tokio::task::spawn_blocking(move || {
+            let file = std::fs::File::open(&file_path)?;
+            // SAFETY: tile files are treated as read-only. Truncating a tile while it
+            // is mapped would fault on access, as documented for the mmap mode.
+            let mmap = unsafe { Mmap::map(&file)? };
+            // Lookups touch a few samples each, read-ahead would only waste I/O
+            #[cfg(unix)]
+            mmap.advise(memmap2::Advice::Random)?;
+            Ok(mmap)
+        })
+        .await
+        .map_err(Error::other)?
     }
 }
@@ -1,39 +1,55 @@
+use memmap2::Mmap;
 use std::io::{Error, ErrorKind};
+use std::ops::Deref;
 use tracing::{debug, instrument};
 
-#[derive(Debug, Clone)]
+/// Sample value SRTM uses to mark voids (no data) in a tile.
+pub const VOID_VALUE: i16 = i16::MIN;
+
+/// Samples of a tile, either read into memory or mapped from an uncompressed file.
+#[derive(Debug)]
+enum TileBuffer {
+    Owned(Vec<u8>),
+    Mapped(Mmap),
+}
+
+impl Deref for TileBuffer {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self {
+            TileBuffer::Owned(buffer) => buffer,
+            TileBuffer::Mapped(mmap) => mmap,
+        }
+    }
+}
+
+#[derive(Debug)]
+#[allow(clippy::upper_case_acronyms)]
 pub struct HGT {
-    buffer: Vec<u8>,
+    buffer: TileBuffer,
     sw_lat_lng: (f64, f64),
     size: usize,
 }
 
 impl HGT {
-    #[instrument(level="debug", skip_all, fields(coord = format!("{},{}", sw_lat_lng.0, sw_lat_lng.1)))]
     pub fn new(buffer: Vec<u8>, sw_lat_lng: (f64, f64)) -> Result<Self, Error> {
-        let size;
-        let resolution;
-
-        match buffer.len() {
-            25934402 => {
-                resolution = 1;
-                size = 3601;
-            }
-            2884802 => {
-                resolution = 3;
-                size = 1201;
-            }
-            _ => {
-                return Err(Error::new(
-                    ErrorKind::InvalidData,
-                    "Unknown tile format (1 arcsecond and 3 arcsecond supported).",
-                ));
-            }
-        }
+        Self::from_buffer(TileBuffer::Owned(buffer), sw_lat_lng)
+    }
 
+    /// Reads samples straight from a mapped uncompressed tile, so only the pages
+    /// touched by lookups are loaded.
+    pub fn from_mmap(mmap: Mmap, sw_lat_lng: (f64, f64)) -> Result<Self, Error> {
+        Self::from_buffer(TileBuffer::Mapped(mmap), sw_lat_lng)
+    }
+
+    #[instrument(level="debug", skip_all, fields(coord = format!("{},{}", sw_lat_lng.0, sw_lat_lng.1)))]
+    fn from_buffer(buffer: TileBuffer, sw_lat_lng: (f64, f64)) -> Result<Self, Error> {
+        let size = tile_size(buffer.len() as u64)?;
         debug!(
             "HGT tile created with size: {}, resolution: {}",
-            size, resolution
+            size,
+            3600 / (size - 1)
         );
 
         Ok(Self {
@@ -43,69 +59,23 @@ impl HGT {
         })
     }
 
-    #[instrument(level = "debug", skip_all, fields(coord = format!("{},{}", lat, lng)))]
-    pub fn get_elevation(&self, lat: f64, lng: f64) -> Result<i16, Error> {
-        let size = self.size - 1;
-        let row = (lat - self.sw_lat_lng.0) * size as f64;
-        let col = (lng - self.sw_lat_lng.1) * size as f64;
-
-        if row < 0.0 || col < 0.0 || row > size as f64 || col > size as f64 {
-            return Err(Error::new(
-                ErrorKind::InvalidInput,
-                format!(
-                    "Latitude/longitude is outside tile bounds (row={}, col={}; size={})",
-                    row, col, size
-                ),
-            ));
+    /// Heap memory used by the tile. Mapped tiles live in the OS page cache and count as 0.
+    pub fn size_bytes(&self) -> usize {
+        match &self.buffer {
+            TileBuffer::Owned(buffer) => buffer.len(),
+            TileBuffer::Mapped(_) => 0,
         }
-
-        HGT::interpolation(self, row, col)
     }
 
-    #[instrument(skip_all, fields(row, col), level = "trace")]
-    fn interpolation(&self, row: f64, col: f64) -> Result<i16, Error> {
-        let row_low = row.floor();
-        let row_high = row_low + 1.0;
-        let row_frac = row - row_low;
-
-        let col_low = col.floor();
-        let col_high = col_low + 1.0;
-        let col_frac = col - col_low;
-
-        let value_low_low = HGT::get_row_col_value(self, row_low, col_low)?;
-        let value_low_high = HGT::get_row_col_value(self, row_low, col_high)?;
-        let value_high_low = HGT::get_row_col_value(self, row_high, col_low)?;
-        let value_high_high = HGT::get_row_col_value(self, row_high, col_high)?;
-        let value_low =
-            (value_low_low as f64 * (1.0 - col_frac) + value_low_high as f64 * col_frac) as i16;
-        let value_high =
-            (value_high_low as f64 * (1.0 - col_frac) + value_high_high as f64 * col_frac) as i16;
-
-        debug!("row = {}", row);
-        debug!("col = {}", col);
-        debug!("row_low = {}", row_low);
-        debug!("row_high = {}", row_high);
-        debug!("row_frac = {}", row_frac);
-        debug!("col_low = {}", col_low);
-        debug!("col_high = {}", col_high);
-        debug!("col_frac = {}", col_frac);
-
-        debug!("value_low_low = {}", value_low_low);
-        debug!("value_high_low = {}", value_high_low);
-        debug!("value_high_high = {}", value_high_high);
-        debug!("value_low_high = {}", value_low_high);
-
-        debug!("value_low = {}", value_low);
-        debug!("value_high = {}", value_high);
-
-        let value = (value_low as f64 * (1.0 - row_frac) + value_high as f64 * row_frac) as i16;
-        debug!("Final interpolated value: {}", value);
-        Ok(value)
+    #[instrument(level = "debug", skip_all, fields(coord = format!("{},{}", lat, lng)))]
+    pub fn get_elevation(&self, lat: f64, lng: f64) -> Result<i16, Error> {
+        let (row, col) = position(self.size, self.sw_lat_lng, lat, lng)?;
+        interpolate(row, col, |row, col| self.get_row_col_value(row, col))
     }
 
     #[instrument(skip_all, fields(row, col), level = "trace")]
     fn get_row_col_value(&self, row: f64, col: f64) -> Result<i16, Error> {
-        let offset = ((self.size - row as usize - 1) * self.size + col as usize) * 2;
+        let offset = sample_offset(self.size, row, col);
         if offset + 1 >= self.buffer.len() {
             return Err(Error::new(
                 ErrorKind::InvalidData,
@@ -118,6 +88,107 @@ impl HGT {
     }
 }
 
+/// Returns the samples per row and column of a tile file with `len` bytes.
+pub fn tile_size(len: u64) -> Result<usize, Error> {
+    match len {
+        25934402 => Ok(3601), // 1 arcsecond
+        2884802 => Ok(1201),  // 3 arcsecond
+        _ => Err(Error::new(
+            ErrorKind::InvalidData,
+            "Unknown tile format (1 arcsecond and 3 arcsecond supported).",
+        )),
+    }
+}
+
+/// Returns the fractional row (counted from the south) and column of `lat`/`lng`
+/// in a tile with `size` samples per row whose south-west corner is `sw_lat_lng`.
+pub fn position(
+    size: usize,
+    sw_lat_lng: (f64, f64),
+    lat: f64,
+    lng: f64,
+) -> Result<(f64, f64), Error> {
+    let size = size - 1;
+    let row = (lat - sw_lat_lng.0) * size as f64;
+    let col = (lng - sw_lat_lng.1) * size as f64;
+
+    if row < 0.0 || col < 0.0 || row > size as f64 || col > size as f64 {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!(
+                "Latitude/longitude is outside tile bounds (row={}, col={}; size={})",
+                row, col, size
+            ),
+        ));
+    }
+    Ok((row, col))
+}
+
+/// Returns the byte offset of a sample. Rows are stored from the north.
+pub fn sample_offset(size: usize, row: f64, col: f64) -> usize {
+    ((size - row as usize - 1) * size + col as usize) * 2
+}
+
+/// Interpolates bilinearly between the four samples around `row`/`col`.
+#[instrument(skip_all, fields(row, col), level = "trace")]
+pub fn interpolate(
+    row: f64,
+    col: f64,
+    sample: impl Fn(f64, f64) -> Result<i16, Error>,
+) -> Result<i16, Error> {
+    let row_low = row.floor();
+    let row_high = row_low + 1.0;
+    let row_frac = row - row_low;
+
+    let col_low = col.floor();
+    let col_high = col_low + 1.0;
+    let col_frac = col - col_low;
+
+    let value_low_low = sample(row_low, col_low)?;
+    let value_low_high = sample(row_low, col_high)?;
+    let value_high_low = sample(row_high, col_low)?;
+    let value_high_high = sample(row_high, col_high)?;
+
+    // Interpolating across a void would produce garbage, report the void instead
+    if [
+        value_low_low,
+        value_low_high,
+        value_high_low,
+        value_high_high,
+    ]
+    .contains(&VOID_VALUE)
+    {
+        debug!("Void sample in interpolation window");
+        return Ok(VOID_VALUE);
+    }
+
+    let value_low =
+        (value_low_low as f64 * (1.0 - col_frac) + value_low_high as f64 * col_frac) as i16;
+    let value_high =
+        (value_high_low as f64 * (1.0 - col_frac) + value_high_high as f64 * col_frac) as i16;
+
+    debug!("row = {}", row);
+    debug!("col = {}", col);
+    debug!("row_low = {}", row_low);
+    debug!("row_high = {}", row_high);
+    debug!("row_frac = {}", row_frac);
+    debug!("col_low = {}", col_low);
+    debug!("col_high = {}", col_high);
+    debug!("col_frac = {}", col_frac);
+
+    debug!("value_low_low = {}", value_low_low);
+    debug!("value_high_low = {}", value_high_low);
+    debug!("value_high_high = {}", value_high_high);
+    debug!("value_low_high = {}", value_low_high);
+
+    debug!("value_low = {}", value_low);
+    debug!("value_high = {}", value_high);
+
+    let value = (value_low as f64 * (1.0 - row_frac) + value_high as f64 * row_frac) as i16;
+    debug!("Final interpolated value: {}", value);
+    Ok(value)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -150,6 +221,15 @@ mod tests {
         assert_eq!(elevation.unwrap(), 0); // Default buffer values lead to elevation 0
     }
 
+    #[test]
+    fn test_get_elevation_void() {
+        let buffer = [0x80, 0x00].repeat(2884802 / 2); // Every sample is a void
+        let sw_lat_lng = (0.0, 0.0);
+        let hgt = HGT::new(buffer, sw_lat_lng).unwrap();
+        let elevation = hgt.get_elevation(0.5, 0.5);
+        assert_eq!(elevation.unwrap(), VOID_VALUE);
+    }
+
     #[test]
     fn test_get_elevation_out_of_bounds() {
         let buffer = vec![0; 25934402]; // Valid buffer size for 1 arcsecond resolution
@@ -1,40 +1,295 @@
-use crate::tileset::{TileSetOptions, TileSetWithCache};
-use flate2::read::GzDecoder;
-use reqwest::Client;
-use std::io::Read;
+use crate::telemetry::inject_context;
+use crate::tileset::parse_content_range;
+use crate::tileset::resilience::{Resilience, ResilienceOptions, status_error};
+use reqwest::{Client, RequestBuilder, StatusCode, header};
+use std::io::{Error, ErrorKind};
 use tracing::{debug, instrument};
 
 pub struct HTTPTileSet {
     base_url: String,
-    options: TileSetOptions,
+    client: Client, // Shared by all requests of the tile set, so connections are reused
+    resilience: Resilience,
 }
 
 impl HTTPTileSet {
-    pub fn new(base_url: String, options: TileSetOptions) -> Self {
-        Self { base_url, options }
+    pub fn new(base_url: String, options: ResilienceOptions) -> Result<Self, reqwest::Error> {
+        let client = Client::builder()
+            .connect_timeout(options.connect_timeout)
+            .read_timeout(options.read_timeout)
+            .build()?;
+        Ok(Self {
+            resilience: Resilience::new(&base_url, options),
+            base_url,
+            client,
+        })
     }
 
-    #[instrument(level="debug", name="get_tile_http", skip_all, fields(coord = format!("{},{}", lat, lng)))]
-    pub async fn get_tile(
-        &self,
-        lat: f64,
-        lng: f64,
-    ) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
-        let file_path = format!(
-            "{}/{}",
-            self.base_url,
-            TileSetWithCache::get_file_path(lat, lng)?
-        );
+    /// Starts a GET request carrying the trace context of the current span.
+    fn request(&self, url: &str) -> RequestBuilder {
+        inject_context()
+            .iter()
+            .fold(self.client.get(url), |request, (name, value)| {
+                request.header(name.as_str(), value.as_bytes())
+            })
+    }
+
+    /// Returns false while the circuit breaker of the backend is open.
+    pub fn is_reachable(&self) -> bool {
+        !self.resilience.is_open()
+    }
+
+    #[instrument(level = "debug", name = "get_tile_http", skip(self))]
+    pub async fn get_tile(&self, tile_path: &str) -> Result<Vec<u8>, Error> {
+        self.resilience.call(|| self.fetch_tile(tile_path)).await
+    }
+
+    async fn fetch_tile(&self, tile_path: &str) -> Result<Vec<u8>, Error> {
+        let file_path = format!("{}/{}", self.base_url, tile_path);
         debug!("Fetching tile from: {}", file_path);
-        let response = Client::new().get(&file_path).send().await?.bytes().await?;
-        // Handle gzip decompression if needed
-        if self.options.gzip {
-            let mut decoder = GzDecoder::new(&response[..]);
-            let mut decompressed = Vec::new();
-            decoder.read_to_end(&mut decompressed)?;
-            Ok(decompressed)
-        } else {
-            Ok(response.to_vec())
+        let response = self
+            .request(&file_path)
+            .send()
+            .await
+            .map_err(convert_reqwest_error)?;
+
+        match response.status() {
+            status if status.is_success() => {}
+            StatusCode::NOT_FOUND | StatusCode::GONE => {
+                return Err(Error::new(
+                    ErrorKind::NotFound,
+                    format!("Tile does not exist: {}", file_path),
+                ));
+            }
+            status => return Err(status_error(status.as_u16(), &file_path)),
+        }
+
+        let response = response.bytes().await.map_err(convert_reqwest_error)?;
+        Ok(response.to_vec())
+    }
+}
+
+impl HTTPTileSet {
+    /// Fetches the bytes `start..=end` of a tile and returns them with the tile length.
+    #[instrument(level = "debug", name = "get_tile_range_http", skip(self))]
+    pub async fn get_range(
+        &self,
+        tile_path: &str,
+        start: u64,
+        end: u64,
+    ) -> Result<(Vec<u8>, u64), Error> {
+        self.resilience
+            .call(|| self.fetch_range(tile_path, start, end))
+            .await
+    }
+
+    async fn fetch_range(
+        &self,
+        tile_path: &str,
+        start: u64,
+        end: u64,
+    ) -> Result<(Vec<u8>, u64), Error> {
+        let file_path = format!("{}/{}", self.base_url, tile_path);
+        debug!("Fetching tile range from: {}", file_path);
+        let response = self
+            .request(&file_path)
+            .header(header::RANGE, format!("bytes={}-{}", start, end))
+            .send()
+            .await
+            .map_err(convert_reqwest_error)?;
+
+        let status = response.status();
+        let content_range = response
+            .headers()
+            .get(header::CONTENT_RANGE)
+            .and_then(|value| value.to_str().ok())
+            .map(String::from);
+        match status {
+            StatusCode::PARTIAL_CONTENT => {
+                let len = content_range
+                    .as_deref()
+                    .and_then(parse_content_range)
+                    .ok_or_else(|| {
+                        Error::new(
+                            ErrorKind::InvalidData,
+                            format!("Missing Content-Range fetching {}", file_path),
+                        )
+                    })?;
+                let bytes = response.bytes().await.map_err(convert_reqwest_error)?;
+                Ok((bytes.to_vec(), len))
+            }
+            // Servers without range support send the whole tile
+            status if status.is_success() => {
+                let bytes = response.bytes().await.map_err(convert_reqwest_error)?;
+                let len = bytes.len() as u64;
+                let end = (end + 1).min(len) as usize;
+                Ok((
+                    bytes.get(start as usize..end).unwrap_or_default().to_vec(),
+                    len,
+                ))
+            }
+            StatusCode::NOT_FOUND | StatusCode::GONE => Err(Error::new(
+                ErrorKind::NotFound,
+                format!("Tile does not exist: {}", file_path),
+            )),
+            status => Err(status_error(status.as_u16(), &file_path)),
+        }
+    }
+}
+
+fn convert_reqwest_error(err: reqwest::Error) -> Error {
+    let kind = if err.is_timeout() {
+        ErrorKind::TimedOut
+    } else if err.is_connect() {
+        ErrorKind::ConnectionRefused
+    } else if err.is_request() || err.is_body() {
+        // The connection broke while sending the request or reading the response
+        ErrorKind::ConnectionReset
+    } else {
+        ErrorKind::Other
+    };
+    Error::new(kind, err)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+    use warp::Filter;
+    use warp::http::StatusCode as WarpStatus;
+
+    /// Serves `tile` after answering the first `failures` requests with `status`.
+    fn stub_server(status: u16, failures: usize) -> (String, Arc<AtomicUsize>) {
+        let requests = Arc::new(AtomicUsize::new(0));
+        let counter = requests.clone();
+        let route = warp::path!("tile").map(move || {
+            let status = match counter.fetch_add(1, Ordering::SeqCst) < failures {
+                true => WarpStatus::from_u16(status).unwrap(),
+                false => WarpStatus::OK,
+            };
+            warp::reply::with_status("tile", status)
+        });
+        let (address, server) = warp::serve(route).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+        (format!("http://{}", address), requests)
+    }
+
+    fn options() -> ResilienceOptions {
+        ResilienceOptions {
+            retry_base_delay: Duration::from_millis(1),
+            retry_max_delay: Duration::from_millis(5),
+            ..ResilienceOptions::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_retries_transient_errors() {
+        let (url, requests) = stub_server(503, 2);
+        let tileset = HTTPTileSet::new(url, options()).unwrap();
+        assert_eq!(tileset.get_tile("tile").await.unwrap(), b"tile");
+        assert_eq!(requests.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn test_does_not_retry_client_errors() {
+        let (url, requests) = stub_server(404, 1);
+        let tileset = HTTPTileSet::new(url, options()).unwrap();
+        let missing = tileset.get_tile("tile").await.unwrap_err();
+        assert_eq!(missing.kind(), ErrorKind::NotFound);
+
+        let (url, forbidden_requests) = stub_server(403, 1);
+        let tileset = HTTPTileSet::new(url, options()).unwrap();
+        let forbidden = tileset.get_tile("tile").await.unwrap_err();
+        assert_eq!(forbidden.kind(), ErrorKind::Other);
+        assert_eq!(requests.load(Ordering::SeqCst), 1);
+        assert_eq!(forbidden_requests.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_circuit_breaker_fails_fast() {
+        let (url, requests) = stub_server(500, usize::MAX);
+        let tileset = HTTPTileSet::new(
+            url,
+            ResilienceOptions {
+                max_retries: 1,
+                breaker_threshold: 2,
+                ..options()
+            },
+        )
+        .unwrap();
+        for _ in 0..2 {
+            assert!(tileset.get_tile("tile").await.is_err());
         }
+        let open = tileset.get_tile("tile").await.unwrap_err();
+        assert_eq!(open.kind(), ErrorKind::ConnectionRefused);
+        assert_eq!(requests.load(Ordering::SeqCst), 4);
+    }
+
+    #[tokio::test]
+    async fn test_read_timeout() {
+        let route = warp::path!("tile").then(|| async {
+            tokio::time::sleep(Duration::from_secs(5)).await;
+            "tile"
+        });
+        let (address, server) = warp::serve(route).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+        let tileset = HTTPTileSet::new(
+            format!("http://{}", address),
+            ResilienceOptions {
+                read_timeout: Duration::from_millis(50),
+                max_retries: 1,
+                ..options()
+            },
+        )
+        .unwrap();
+        let error = tileset.get_tile("tile").await.unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::TimedOut);
+    }
+
+    #[tokio::test]
+    async fn test_passes_trace_context_on() {
+        use crate::telemetry::extract_context;
+        use opentelemetry::trace::TracerProvider as _;
+        use std::sync::Mutex;
+        use tracing::Instrument;
+        use tracing_opentelemetry::OpenTelemetrySpanExt;
+        use tracing_subscriber::prelude::*;
+
+        let traceparents = Arc::new(Mutex::new(Vec::new()));
+        let recorded = traceparents.clone();
+        let route = warp::path!("tile")
+            .and(warp::header::optional::<String>("traceparent"))
+            .map(move |traceparent: Option<String>| {
+                recorded.lock().unwrap().push(traceparent);
+                "tile"
+            });
+        let (address, server) = warp::serve(route).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+
+        opentelemetry::global::set_text_map_propagator(
+            opentelemetry_sdk::propagation::TraceContextPropagator::new(),
+        );
+        let provider = opentelemetry_sdk::trace::TracerProvider::builder().build();
+        let subscriber = tracing_subscriber::registry()
+            .with(tracing_opentelemetry::layer().with_tracer(provider.tracer("test")));
+        let _guard = tracing::subscriber::set_default(subscriber);
+
+        let mut headers = warp::http::HeaderMap::new();
+        headers.insert(
+            "traceparent",
+            "00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01"
+                .parse()
+                .unwrap(),
+        );
+        let span = tracing::info_span!("request");
+        span.set_parent(extract_context(&headers));
+
+        let tileset = HTTPTileSet::new(format!("http://{}", address), options()).unwrap();
+        tileset.get_tile("tile").instrument(span).await.unwrap();
+
+        let traceparent = traceparents.lock().unwrap()[0].clone().unwrap();
+        assert!(traceparent.starts_with("00-0af7651916cd43dd8448eb211c80319c-"));
+        assert!(!traceparent.contains("b7ad6b7169203331"));
     }
 }
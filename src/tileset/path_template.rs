@@ -0,0 +1,191 @@
+use std::fmt;
+use std::io::{Error, ErrorKind};
+
+/// Skadi layout used by the AWS terrain tiles, e.g. `N45/N45E009.hgt.gz`, or
+/// `N45/N45E009.hgt` for uncompressed tile sets
+pub const SKADI_TEMPLATE: &str = "{LAT}/{LAT}{LNG}.{ext}";
+
+/// Slippy map layout used by raster tile sets, e.g. `12/2200/1365.png`
+pub const XYZ_TEMPLATE: &str = "{z}/{x}/{y}.png";
+
+#[derive(Debug, Clone, PartialEq)]
+enum Segment {
+    Literal(String),
+    Lat { upper: bool },
+    Lng { upper: bool },
+    Name { upper: bool },
+    Ext,
+    Zoom,
+    X,
+    Y,
+}
+
+/// Layout of the tile paths below the tile set root.
+///
+/// Supported placeholders:
+/// - `{LAT}` / `{lat}`: latitude part of the tile name, e.g. `N45` / `n45`
+/// - `{LNG}` / `{lng}`: longitude part of the tile name, e.g. `E009` / `e009`
+/// - `{NAME}` / `{name}`: full tile name, e.g. `N45E009` / `n45e009`
+/// - `{ext}`: `hgt.gz` for gzip compressed tile sets, `hgt` otherwise
+/// - `{z}`, `{x}`, `{y}`: Web Mercator tile coordinates of raster tile sets
+#[derive(Debug, Clone, PartialEq)]
+pub struct PathTemplate {
+    template: String,
+    segments: Vec<Segment>,
+    gzip: bool,
+}
+
+impl PathTemplate {
+    pub fn new(template: &str, gzip: bool) -> Result<Self, Error> {
+        let mut segments = Vec::new();
+        let mut rest = template;
+
+        while let Some(start) = rest.find('{') {
+            if start > 0 {
+                segments.push(Segment::Literal(rest[..start].to_string()));
+            }
+            let end = rest[start..].find('}').ok_or_else(|| {
+                Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("Unclosed placeholder in path template '{}'", template),
+                )
+            })? + start;
+
+            segments.push(match &rest[start + 1..end] {
+                "LAT" => Segment::Lat { upper: true },
+                "lat" => Segment::Lat { upper: false },
+                "LNG" => Segment::Lng { upper: true },
+                "lng" => Segment::Lng { upper: false },
+                "NAME" => Segment::Name { upper: true },
+                "name" => Segment::Name { upper: false },
+                "ext" => Segment::Ext,
+                "z" => Segment::Zoom,
+                "x" => Segment::X,
+                "y" => Segment::Y,
+                placeholder => {
+                    return Err(Error::new(
+                        ErrorKind::InvalidInput,
+                        format!(
+                            "Unknown placeholder '{{{}}}' in path template '{}'",
+                            placeholder, template
+                        ),
+                    ));
+                }
+            });
+            rest = &rest[end + 1..];
+        }
+        if !rest.is_empty() {
+            segments.push(Segment::Literal(rest.to_string()));
+        }
+
+        Ok(Self {
+            template: template.to_string(),
+            segments,
+            gzip,
+        })
+    }
+
+    /// Returns true if the template addresses Web Mercator tiles.
+    pub fn is_xyz(&self) -> bool {
+        self.segments
+            .iter()
+            .any(|segment| matches!(segment, Segment::Zoom | Segment::X | Segment::Y))
+    }
+
+    /// Returns the path of the Web Mercator tile `zoom`/`x`/`y`.
+    pub fn render_xyz(&self, zoom: u8, x: u32, y: u32) -> String {
+        self.segments
+            .iter()
+            .map(|segment| match segment {
+                Segment::Literal(literal) => literal.clone(),
+                Segment::Zoom => zoom.to_string(),
+                Segment::X => x.to_string(),
+                Segment::Y => y.to_string(),
+                _ => String::new(),
+            })
+            .collect()
+    }
+
+    /// Returns the path of the tile whose south-west corner is at `lat`/`lng`.
+    pub fn render(&self, lat: f64, lng: f64) -> String {
+        let lat_prefix = if lat < 0.0 { "S" } else { "N" };
+        let lng_prefix = if lng < 0.0 { "W" } else { "E" };
+        let lat_name = format!("{}{:02}", lat_prefix, lat.abs() as i32);
+        let lng_name = format!("{}{:03}", lng_prefix, lng.abs() as i32);
+        let case = |value: &str, upper: bool| {
+            if upper {
+                value.to_string()
+            } else {
+                value.to_lowercase()
+            }
+        };
+
+        self.segments
+            .iter()
+            .map(|segment| match segment {
+                Segment::Literal(literal) => literal.clone(),
+                Segment::Lat { upper } => case(&lat_name, *upper),
+                Segment::Lng { upper } => case(&lng_name, *upper),
+                Segment::Name { upper } => case(&format!("{}{}", lat_name, lng_name), *upper),
+                Segment::Ext => String::from(if self.gzip { "hgt.gz" } else { "hgt" }),
+                Segment::Zoom | Segment::X | Segment::Y => String::new(),
+            })
+            .collect()
+    }
+}
+
+impl Default for PathTemplate {
+    fn default() -> Self {
+        Self::new(SKADI_TEMPLATE, true).expect("Skadi template is valid")
+    }
+}
+
+impl fmt::Display for PathTemplate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.template)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_flat_layout() {
+        let template = PathTemplate::new("{LAT}{LNG}.hgt", false).unwrap();
+        assert_eq!(template.render(45.0, 9.0), "N45E009.hgt");
+    }
+
+    #[test]
+    fn test_lowercase_layout_with_extension() {
+        let template = PathTemplate::new("{lat}/{name}.{ext}", false).unwrap();
+        assert_eq!(template.render(-12.0, -77.0), "s12/s12w077.hgt");
+
+        let template = PathTemplate::new("{lat}/{name}.{ext}", true).unwrap();
+        assert_eq!(template.render(-12.0, -77.0), "s12/s12w077.hgt.gz");
+    }
+
+    #[test]
+    fn test_skadi_layout_follows_compression() {
+        let template = PathTemplate::new(SKADI_TEMPLATE, true).unwrap();
+        assert_eq!(template.render(45.0, 9.0), "N45/N45E009.hgt.gz");
+
+        let template = PathTemplate::new(SKADI_TEMPLATE, false).unwrap();
+        assert_eq!(template.render(45.0, 9.0), "N45/N45E009.hgt");
+    }
+
+    #[test]
+    fn test_xyz_layout() {
+        let template = PathTemplate::new(XYZ_TEMPLATE, false).unwrap();
+        assert!(template.is_xyz());
+        assert_eq!(template.render_xyz(12, 2200, 1365), "12/2200/1365.png");
+        assert!(!PathTemplate::default().is_xyz());
+    }
+
+    #[test]
+    fn test_invalid_templates() {
+        let error = PathTemplate::new("{LAT}/{tile}.hgt", true).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::InvalidInput);
+        assert!(PathTemplate::new("{LAT/{LNG}.hgt", true).is_err());
+    }
+}
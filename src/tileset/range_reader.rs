@@ -0,0 +1,112 @@
+use crate::tileset::hgt::{interpolate, position, sample_offset, tile_size};
+use crate::tileset::{CacheSize, TileKey, TileSet};
+use moka::future::Cache;
+use std::io::{Error, ErrorKind};
+use std::sync::Arc;
+use tracing::{debug, instrument};
+
+/// Rows of samples fetched with one range request.
+const BLOCK_ROWS: usize = 16;
+
+/// Bytes of a 3 arcsecond tile, used to convert tile count budgets to bytes.
+const TILE_BYTES: u64 = 2884802;
+
+/// Reads single samples of uncompressed remote HGT tiles with HTTP range requests,
+/// caching blocks of rows instead of whole tiles.
+pub struct RangeReader {
+    lengths: Cache<TileKey, u64>,
+    blocks: Cache<(TileKey, usize), Arc<Vec<u8>>>,
+}
+
+impl RangeReader {
+    pub fn new(cache_size: CacheSize) -> Self {
+        let capacity = match cache_size {
+            CacheSize::Tiles(tiles) => tiles * TILE_BYTES,
+            CacheSize::Bytes(bytes) => bytes,
+        };
+        Self {
+            lengths: Cache::new(100_000),
+            blocks: Cache::builder()
+                .max_capacity(capacity)
+                .weigher(|_, block: &Arc<Vec<u8>>| u32::try_from(block.len()).unwrap_or(u32::MAX))
+                .build(),
+        }
+    }
+
+    /// Returns the number of cached blocks and the bytes they use.
+    pub fn cache_usage(&self) -> (u64, u64) {
+        self.blocks
+            .iter()
+            .fold((0, 0), |(blocks, bytes), (_, block)| {
+                (blocks + 1, bytes + block.len() as u64)
+            })
+    }
+
+    #[instrument(level = "debug", skip(self, tileset))]
+    pub async fn get_elevation(
+        &self,
+        tileset: &TileSet,
+        cache_key: TileKey,
+        tile_path: &str,
+        lat: f64,
+        lng: f64,
+    ) -> Result<i16, Error> {
+        // The first request of a tile only learns its length, which gives the resolution
+        let len = self
+            .lengths
+            .try_get_with(cache_key, async {
+                tileset.get_range(tile_path, 0, 1).await.map(|(_, len)| len)
+            })
+            .await
+            .map_err(|e| Error::new(e.kind(), e))?;
+        let size = tile_size(len)?;
+        let (row, col) = position(size, (cache_key.0 as f64, cache_key.1 as f64), lat, lng)?;
+
+        // The interpolation reads this row and the one north of it, rows are stored from the north
+        let block_bytes = BLOCK_ROWS * size * 2;
+        let file_row = size - row as usize - 1;
+        let mut blocks = Vec::new();
+        for block in [
+            file_row.saturating_sub(1) / BLOCK_ROWS,
+            file_row / BLOCK_ROWS,
+        ] {
+            if blocks.iter().any(|(index, _)| *index == block) {
+                continue;
+            }
+            let data = self
+                .blocks
+                .try_get_with((cache_key, block), async {
+                    let start = (block * block_bytes) as u64;
+                    let end = (start + block_bytes as u64).min(len) - 1;
+                    debug!(block, start, end, "Fetching block");
+                    let (data, _) = tileset.get_range(tile_path, start, end).await?;
+                    if data.len() as u64 != end - start + 1 {
+                        return Err(Error::new(
+                            ErrorKind::InvalidData,
+                            format!("Short range read of '{}'", tile_path),
+                        ));
+                    }
+                    Ok(Arc::new(data))
+                })
+                .await
+                .map_err(|e| Error::new(e.kind(), e))?;
+            blocks.push((block, data));
+        }
+
+        interpolate(row, col, |row, col| {
+            let offset = sample_offset(size, row, col);
+            let (_, data) = blocks
+                .iter()
+                .find(|(index, _)| *index == offset / block_bytes)
+                .ok_or_else(|| Error::new(ErrorKind::InvalidData, "Sample outside fetched rows"))?;
+            let offset = offset % block_bytes;
+            match data.get(offset..offset + 2) {
+                Some(sample) => Ok(i16::from_be_bytes([sample[0], sample[1]])),
+                None => Err(Error::new(
+                    ErrorKind::InvalidData,
+                    "Offset exceeds buffer length.",
+                )),
+            }
+        })
+    }
+}
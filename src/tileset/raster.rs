@@ -0,0 +1,280 @@
+use std::f64::consts::PI;
+use std::io::{Error, ErrorKind};
+use std::str::FromStr;
+use tracing::{debug, instrument};
+
+/// Highest latitude covered by Web Mercator tiles.
+const MAX_MERCATOR_LAT: f64 = 85.051_128_779_806_59;
+
+/// Encoding of the tiles of a tile set.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TileFormat {
+    /// SRTM HGT tiles covering one degree each.
+    Hgt,
+    /// Terrarium PNG tiles: `(R * 256 + G + B / 256) - 32768`.
+    Terrarium,
+    /// Mapbox Terrain-RGB PNG tiles: `-10000 + (R * 65536 + G * 256 + B) * 0.1`.
+    TerrainRgb,
+}
+
+impl TileFormat {
+    pub fn is_raster(&self) -> bool {
+        *self != TileFormat::Hgt
+    }
+}
+
+impl FromStr for TileFormat {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "hgt" => Ok(TileFormat::Hgt),
+            "terrarium" => Ok(TileFormat::Terrarium),
+            "terrain-rgb" | "terrainrgb" => Ok(TileFormat::TerrainRgb),
+            _ => Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "Unknown tile format '{}' (hgt, terrarium and terrain-rgb supported)",
+                    s
+                ),
+            )),
+        }
+    }
+}
+
+impl std::fmt::Display for TileFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            TileFormat::Hgt => "hgt",
+            TileFormat::Terrarium => "terrarium",
+            TileFormat::TerrainRgb => "terrain-rgb",
+        })
+    }
+}
+
+/// Fails with `NotFound` for latitudes beyond the Web Mercator projection, which no
+/// raster tile covers.
+pub fn check_mercator_lat(lat: f64) -> Result<(), Error> {
+    if lat.abs() > MAX_MERCATOR_LAT {
+        return Err(Error::new(
+            ErrorKind::NotFound,
+            format!("Latitude {} is outside the Web Mercator projection", lat),
+        ));
+    }
+    Ok(())
+}
+
+/// Returns the fractional Web Mercator tile coordinates of `lat`/`lng` at `zoom`.
+/// Latitudes beyond the projection are clamped to its edge.
+pub fn mercator_tile(lat: f64, lng: f64, zoom: u8) -> (f64, f64) {
+    let n = (1u32 << zoom) as f64;
+    let lat_rad = lat.clamp(-MAX_MERCATOR_LAT, MAX_MERCATOR_LAT).to_radians();
+    let x = (lng + 180.0) / 360.0 * n;
+    let y = (1.0 - (lat_rad.tan() + 1.0 / lat_rad.cos()).ln() / PI) / 2.0 * n;
+    // The east and south edges belong to the last tile
+    (
+        x.min(n - f64::EPSILON * n),
+        y.clamp(0.0, n - f64::EPSILON * n),
+    )
+}
+
+/// Returns the latitude/longitude of the center of the Web Mercator tile `zoom`/`x`/`y`.
+pub fn mercator_tile_center(zoom: u8, x: u32, y: u32) -> (f64, f64) {
+    let n = (1u32 << zoom) as f64;
+    let lng = (x as f64 + 0.5) / n * 360.0 - 180.0;
+    let lat = (PI * (1.0 - 2.0 * (y as f64 + 0.5) / n))
+        .sinh()
+        .atan()
+        .to_degrees();
+    (lat, lng)
+}
+
+/// Decoded PNG elevation tile in the Web Mercator tiling scheme.
+#[derive(Debug, Clone)]
+pub struct RasterTile {
+    heights: Vec<f32>,
+    size: usize,
+    zoom: u8,
+    x: u32,
+    y: u32,
+}
+
+impl RasterTile {
+    #[instrument(level = "debug", skip(buffer))]
+    pub fn new(buffer: &[u8], format: TileFormat, zoom: u8, x: u32, y: u32) -> Result<Self, Error> {
+        let mut decoder = png::Decoder::new(buffer);
+        decoder.set_transformations(png::Transformations::EXPAND | png::Transformations::STRIP_16);
+        let mut reader = decoder
+            .read_info()
+            .map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+        let mut pixels = vec![0; reader.output_buffer_size()];
+        let info = reader
+            .next_frame(&mut pixels)
+            .map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+
+        if info.width != info.height {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "Raster tiles must be square.",
+            ));
+        }
+        let channels = match info.color_type {
+            png::ColorType::Rgb => 3,
+            png::ColorType::Rgba => 4,
+            _ => {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    "Raster tiles must be RGB or RGBA images.",
+                ));
+            }
+        };
+
+        let heights = pixels[..info.buffer_size()]
+            .chunks_exact(channels)
+            .map(|pixel| {
+                let (r, g, b) = (pixel[0] as f32, pixel[1] as f32, pixel[2] as f32);
+                match format {
+                    TileFormat::Terrarium => r * 256.0 + g + b / 256.0 - 32768.0,
+                    _ => -10000.0 + (r * 65536.0 + g * 256.0 + b) * 0.1,
+                }
+            })
+            .collect();
+
+        debug!("Raster tile created with size: {}", info.width);
+        Ok(Self {
+            heights,
+            size: info.width as usize,
+            zoom,
+            x,
+            y,
+        })
+    }
+
+    pub fn size_bytes(&self) -> usize {
+        self.heights.len() * std::mem::size_of::<f32>()
+    }
+
+    #[instrument(level = "debug", skip_all, fields(coord = format!("{},{}", lat, lng)))]
+    pub fn get_elevation(&self, lat: f64, lng: f64) -> Result<i16, Error> {
+        let (x, y) = mercator_tile(lat, lng, self.zoom);
+        if x.floor() as u32 != self.x || y.floor() as u32 != self.y {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "Latitude/longitude is outside tile bounds (tile={}/{}/{})",
+                    self.zoom, self.x, self.y
+                ),
+            ));
+        }
+
+        // Pixel values are sampled at the pixel centers
+        let max = (self.size - 1) as f64;
+        let col = ((x - x.floor()) * self.size as f64 - 0.5).clamp(0.0, max);
+        let row = ((y - y.floor()) * self.size as f64 - 0.5).clamp(0.0, max);
+        let (col_low, row_low) = (col.floor() as usize, row.floor() as usize);
+        let (col_high, row_high) = (
+            (col_low + 1).min(self.size - 1),
+            (row_low + 1).min(self.size - 1),
+        );
+        let (col_frac, row_frac) = (col - col_low as f64, row - row_low as f64);
+
+        let value = |row: usize, col: usize| self.heights[row * self.size + col] as f64;
+        let top = value(row_low, col_low) * (1.0 - col_frac) + value(row_low, col_high) * col_frac;
+        let bottom =
+            value(row_high, col_low) * (1.0 - col_frac) + value(row_high, col_high) * col_frac;
+        let elevation = top * (1.0 - row_frac) + bottom * row_frac;
+
+        debug!("Interpolated raster value: {}", elevation);
+        Ok(elevation.round() as i16)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Encodes a 2x2 RGB tile with the given pixels.
+    fn encode_tile(pixels: &[[u8; 3]; 4]) -> Vec<u8> {
+        let mut buffer = Vec::new();
+        let mut encoder = png::Encoder::new(&mut buffer, 2, 2);
+        encoder.set_color(png::ColorType::Rgb);
+        encoder.set_depth(png::BitDepth::Eight);
+        let mut writer = encoder.write_header().unwrap();
+        writer.write_image_data(pixels.as_flattened()).unwrap();
+        writer.finish().unwrap();
+        buffer
+    }
+
+    #[test]
+    fn test_mercator_tile() {
+        let (x, y) = mercator_tile(0.0, 0.0, 1);
+        assert_eq!((x, y), (1.0, 1.0));
+        let (x, y) = mercator_tile(51.3, 13.4, 12);
+        assert_eq!((x.floor(), y.floor()), (2200.0, 1365.0));
+    }
+
+    #[test]
+    fn test_check_mercator_lat() {
+        assert!(check_mercator_lat(85.05).is_ok());
+        assert!(check_mercator_lat(-85.05).is_ok());
+        assert_eq!(
+            check_mercator_lat(85.06).unwrap_err().kind(),
+            ErrorKind::NotFound
+        );
+        assert!(check_mercator_lat(-90.0).is_err());
+    }
+
+    #[test]
+    fn test_mercator_tile_center() {
+        let (lat, lng) = mercator_tile_center(12, 2200, 1365);
+        let (x, y) = mercator_tile(lat, lng, 12);
+        assert_eq!((x.floor(), y.floor()), (2200.0, 1365.0));
+        assert_eq!(mercator_tile_center(1, 1, 0).1, 90.0);
+    }
+
+    #[test]
+    fn test_terrarium_decoding() {
+        // 32868 = 128 * 256 + 100 -> 100 m
+        let tile = encode_tile(&[[128, 100, 0]; 4]);
+        let raster = RasterTile::new(&tile, TileFormat::Terrarium, 1, 1, 0).unwrap();
+        assert_eq!(raster.get_elevation(45.0, 90.0).unwrap(), 100);
+    }
+
+    #[test]
+    fn test_terrain_rgb_decoding() {
+        // (1 * 65536 + 173 * 256 + 176) * 0.1 - 10000 = 1000 m
+        let tile = encode_tile(&[[1, 173, 176]; 4]);
+        let raster = RasterTile::new(&tile, TileFormat::TerrainRgb, 1, 1, 0).unwrap();
+        assert_eq!(raster.get_elevation(45.0, 90.0).unwrap(), 1000);
+    }
+
+    #[test]
+    fn test_interpolation_between_pixels() {
+        let tile = encode_tile(&[[128, 0, 0], [128, 100, 0], [128, 0, 0], [128, 100, 0]]);
+        let raster = RasterTile::new(&tile, TileFormat::Terrarium, 0, 0, 0).unwrap();
+        assert_eq!(raster.get_elevation(0.0, 0.0).unwrap(), 50);
+        assert_eq!(raster.get_elevation(0.0, -170.0).unwrap(), 0);
+        assert_eq!(raster.get_elevation(0.0, 170.0).unwrap(), 100);
+    }
+
+    #[test]
+    fn test_outside_tile_bounds() {
+        let tile = encode_tile(&[[128, 0, 0]; 4]);
+        let raster = RasterTile::new(&tile, TileFormat::Terrarium, 1, 1, 0).unwrap();
+        let error = raster.get_elevation(-45.0, -90.0).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn test_parse_format() {
+        assert_eq!(
+            "Terrarium".parse::<TileFormat>().unwrap(),
+            TileFormat::Terrarium
+        );
+        assert_eq!(
+            "terrain-rgb".parse::<TileFormat>().unwrap(),
+            TileFormat::TerrainRgb
+        );
+        assert!("jpeg".parse::<TileFormat>().is_err());
+    }
+}
@@ -0,0 +1,263 @@
+use std::fmt;
+use std::future::Future;
+use std::io::{Error, ErrorKind};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tracing::{debug, warn};
+
+/// Timeouts, retries and circuit breaking of requests to remote tile backends.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ResilienceOptions {
+    /// Time allowed to establish a connection
+    pub connect_timeout: Duration,
+    /// Time allowed for each attempt to receive the complete response
+    pub read_timeout: Duration,
+    /// Retries of requests that failed with a transient error
+    pub max_retries: u32,
+    /// Delay before the first retry, doubled for every further retry
+    pub retry_base_delay: Duration,
+    /// Upper bound of the delay between retries
+    pub retry_max_delay: Duration,
+    /// Consecutive failed requests that open the circuit breaker, 0 disables it
+    pub breaker_threshold: u32,
+    /// Time the circuit breaker stays open before letting a probe request through
+    pub breaker_cooldown: Duration,
+}
+
+impl Default for ResilienceOptions {
+    fn default() -> Self {
+        Self {
+            connect_timeout: Duration::from_secs(2),
+            read_timeout: Duration::from_secs(10),
+            max_retries: 3,
+            retry_base_delay: Duration::from_millis(100),
+            retry_max_delay: Duration::from_secs(2),
+            breaker_threshold: 5,
+            breaker_cooldown: Duration::from_secs(30),
+        }
+    }
+}
+
+/// Error payload of unexpected status codes, so retries can tell transient ones apart.
+#[derive(Debug)]
+pub struct UnexpectedStatus {
+    pub status: u16,
+    pub location: String,
+}
+
+impl fmt::Display for UnexpectedStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Unexpected status {} fetching {}",
+            self.status, self.location
+        )
+    }
+}
+
+impl std::error::Error for UnexpectedStatus {}
+
+pub fn status_error(status: u16, location: &str) -> Error {
+    Error::other(UnexpectedStatus {
+        status,
+        location: location.to_string(),
+    })
+}
+
+/// Returns true for errors that may go away when the request is repeated.
+pub fn is_transient(e: &Error) -> bool {
+    match e.kind() {
+        ErrorKind::TimedOut
+        | ErrorKind::ConnectionRefused
+        | ErrorKind::ConnectionReset
+        | ErrorKind::ConnectionAborted
+        | ErrorKind::BrokenPipe
+        | ErrorKind::UnexpectedEof
+        | ErrorKind::Interrupted => true,
+        _ => e
+            .get_ref()
+            .and_then(|inner| inner.downcast_ref::<UnexpectedStatus>())
+            .is_some_and(|status| {
+                matches!(status.status, 408 | 429) || (status.status >= 500 && status.status != 501)
+            }),
+    }
+}
+
+/// Runs backend requests with a deadline per attempt, retries transient failures with
+/// exponential backoff and jitter, and fails fast while the backend is down.
+#[derive(Debug)]
+pub struct Resilience {
+    options: ResilienceOptions,
+    backend: String,
+    breaker: Mutex<BreakerState>,
+}
+
+#[derive(Debug, Default)]
+struct BreakerState {
+    failures: u32,
+    open_until: Option<Instant>,
+}
+
+impl Resilience {
+    pub fn new(backend: &str, options: ResilienceOptions) -> Self {
+        Self {
+            options,
+            backend: backend.to_string(),
+            breaker: Mutex::new(BreakerState::default()),
+        }
+    }
+
+    pub async fn call<T, F, Fut>(&self, request: F) -> Result<T, Error>
+    where
+        F: Fn() -> Fut,
+        Fut: Future<Output = Result<T, Error>>,
+    {
+        self.acquire()?;
+
+        let mut retries = 0;
+        loop {
+            let result = match tokio::time::timeout(self.options.read_timeout, request()).await {
+                Ok(result) => result,
+                Err(_) => Err(Error::new(
+                    ErrorKind::TimedOut,
+                    format!("Request to {} timed out", self.backend),
+                )),
+            };
+
+            match result {
+                Err(e) if is_transient(&e) && retries < self.options.max_retries => {
+                    let delay = self.backoff(retries);
+                    retries += 1;
+                    warn!(backend = %self.backend, error = %e, retries, ?delay, "Retrying backend request");
+                    tokio::time::sleep(delay).await;
+                }
+                result => {
+                    // Any answer of the backend, including "not found", shows it is up
+                    let failed = result.as_ref().is_err_and(is_transient);
+                    self.record(failed);
+                    return result;
+                }
+            }
+        }
+    }
+
+    /// Returns true while the breaker fails requests fast, i.e. the backend is considered down.
+    pub fn is_open(&self) -> bool {
+        let state = self.breaker.lock().unwrap_or_else(|e| e.into_inner());
+        state.open_until.is_some()
+    }
+
+    /// Delay before the given retry, jittered between half and the full backoff.
+    fn backoff(&self, retries: u32) -> Duration {
+        let delay = self
+            .options
+            .retry_base_delay
+            .saturating_mul(2u32.saturating_pow(retries))
+            .min(self.options.retry_max_delay);
+        delay.mul_f64(rand::random_range(0.5..=1.0))
+    }
+
+    /// Fails fast while the breaker is open. Once the cooldown has passed, one request
+    /// is let through as a probe and the breaker stays open for everyone else.
+    fn acquire(&self) -> Result<(), Error> {
+        if self.options.breaker_threshold == 0 {
+            return Ok(());
+        }
+        let mut state = self.breaker.lock().unwrap_or_else(|e| e.into_inner());
+        match state.open_until {
+            Some(until) if Instant::now() < until => Err(Error::new(
+                ErrorKind::ConnectionRefused,
+                format!(
+                    "Circuit breaker open after {} failed requests to {}",
+                    state.failures, self.backend
+                ),
+            )),
+            Some(_) => {
+                debug!(backend = %self.backend, "Probing backend");
+                state.open_until = Some(Instant::now() + self.options.breaker_cooldown);
+                Ok(())
+            }
+            None => Ok(()),
+        }
+    }
+
+    fn record(&self, failed: bool) {
+        if self.options.breaker_threshold == 0 {
+            return;
+        }
+        let mut state = self.breaker.lock().unwrap_or_else(|e| e.into_inner());
+        if !failed {
+            if state.open_until.is_some() {
+                warn!(backend = %self.backend, "Circuit breaker closed");
+            }
+            *state = BreakerState::default();
+            return;
+        }
+
+        state.failures += 1;
+        if state.failures >= self.options.breaker_threshold {
+            if state.failures == self.options.breaker_threshold {
+                warn!(backend = %self.backend, failures = state.failures, "Circuit breaker opened");
+            }
+            state.open_until = Some(Instant::now() + self.options.breaker_cooldown);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    fn options() -> ResilienceOptions {
+        ResilienceOptions {
+            retry_base_delay: Duration::from_millis(1),
+            retry_max_delay: Duration::from_millis(2),
+            breaker_threshold: 2,
+            breaker_cooldown: Duration::from_millis(50),
+            ..ResilienceOptions::default()
+        }
+    }
+
+    #[test]
+    fn test_is_transient() {
+        assert!(is_transient(&Error::from(ErrorKind::TimedOut)));
+        assert!(is_transient(&status_error(503, "tile")));
+        assert!(is_transient(&status_error(429, "tile")));
+        assert!(!is_transient(&status_error(403, "tile")));
+        assert!(!is_transient(&status_error(501, "tile")));
+        assert!(!is_transient(&Error::from(ErrorKind::NotFound)));
+    }
+
+    #[tokio::test]
+    async fn test_breaker_probes_after_cooldown() {
+        let resilience = Resilience::new(
+            "test",
+            ResilienceOptions {
+                max_retries: 0,
+                ..options()
+            },
+        );
+        let calls = AtomicU32::new(0);
+        let failing = || async {
+            calls.fetch_add(1, Ordering::Relaxed);
+            Err::<(), _>(status_error(500, "tile"))
+        };
+
+        for _ in 0..2 {
+            assert_eq!(
+                resilience.call(failing).await.unwrap_err().kind(),
+                ErrorKind::Other
+            );
+        }
+        let open = resilience.call(failing).await.unwrap_err();
+        assert_eq!(open.kind(), ErrorKind::ConnectionRefused);
+        assert_eq!(calls.load(Ordering::Relaxed), 2);
+        assert!(resilience.is_open());
+
+        tokio::time::sleep(Duration::from_millis(60)).await;
+        assert!(resilience.call(|| async { Ok(()) }).await.is_ok());
+        assert!(resilience.call(|| async { Ok(()) }).await.is_ok());
+        assert!(!resilience.is_open());
+    }
+}
@@ -1,43 +1,42 @@
-use crate::tileset::TileSetWithCache;
-use flate2::read::GzDecoder;
-use s3::{Bucket, Region, creds::Credentials};
-use std::io::Read;
+use crate::telemetry::inject_context;
+use crate::tileset::resilience::{Resilience, status_error};
+use crate::tileset::{TileSetOptions, parse_content_range};
+use s3::{Bucket, Region, creds::Credentials, error::S3Error};
+use std::borrow::Cow;
+use std::io::{Error, ErrorKind};
 use tracing::{debug, instrument};
 
 pub struct S3TileSet {
     bucket: Box<Bucket>,
     key_prefix: String,
-    gzip: bool,
+    resilience: Resilience,
 }
 
 impl S3TileSet {
     pub fn new(
         bucket_name: String,
         key_prefix: String,
-        gzip: bool,
-        access_key_id: Option<String>,
-        secret_access_key: Option<String>,
-        region: Option<String>,
-        endpoint: Option<String>,
+        options: TileSetOptions,
     ) -> Result<Self, Box<dyn std::error::Error>> {
         // Set up credentials
-        let credentials =
-            if let (Some(access_key), Some(secret_key)) = (access_key_id, secret_access_key) {
-                Credentials::new(
-                    Some(&access_key),
-                    Some(&secret_key),
-                    None, // security_token
-                    None, // session_token
-                    None, // expiration
-                )?
-            } else {
-                // Try to use default credentials (environment variables, IAM roles, etc.)
-                Credentials::default()?
-            };
+        let credentials = if let (Some(access_key), Some(secret_key)) =
+            (&options.s3_access_key_id, &options.s3_secret_access_key)
+        {
+            Credentials::new(
+                Some(access_key),
+                Some(secret_key),
+                None, // security_token
+                None, // session_token
+                None, // expiration
+            )?
+        } else {
+            // Try to use default credentials (environment variables, IAM roles, etc.)
+            Credentials::default()?
+        };
 
         // Set up region
-        let region = if let Some(region_str) = region {
-            if let Some(endpoint_url) = endpoint {
+        let region = if let Some(region_str) = options.s3_region.clone() {
+            if let Some(endpoint_url) = options.s3_endpoint.clone() {
                 // Custom endpoint (e.g., MinIO, DigitalOcean Spaces)
                 Region::Custom {
                     region: region_str,
@@ -47,7 +46,7 @@ impl S3TileSet {
                 // Standard AWS region
                 region_str.parse().unwrap_or(Region::UsEast1)
             }
-        } else if let Some(endpoint_url) = endpoint {
+        } else if let Some(endpoint_url) = options.s3_endpoint.clone() {
             // Custom endpoint with default region
             Region::Custom {
                 region: "us-east-1".to_string(),
@@ -58,42 +57,155 @@ impl S3TileSet {
             Region::UsEast1
         };
 
-        // Create bucket instance
-        let bucket = Bucket::new(&bucket_name, region, credentials)?;
+        // Create bucket instance, the request timeout of rust-s3 limits connecting
+        let bucket = Bucket::new(&bucket_name, region, credentials)?
+            .with_request_timeout(options.resilience.connect_timeout)?;
+        let resilience = Resilience::new(&format!("s3://{}", bucket_name), options.resilience);
 
         Ok(Self {
             bucket,
             key_prefix,
-            gzip,
+            resilience,
         })
     }
 
-    #[instrument(level="debug", name="get_tile_s3", skip_all, fields(coord = format!("{},{}", lat, lng)))]
-    pub async fn get_tile(
-        &self,
-        lat: f64,
-        lng: f64,
-    ) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
-        let file_path = TileSetWithCache::get_file_path(lat, lng)?;
+    /// Returns the bucket sending the trace context of the current span with its requests.
+    fn traced_bucket(&self) -> Result<Cow<'_, Bucket>, Error> {
+        let headers = inject_context();
+        if headers.is_empty() {
+            return Ok(Cow::Borrowed(&self.bucket));
+        }
+        self.bucket
+            .with_extra_headers(headers)
+            .map(Cow::Owned)
+            .map_err(convert_s3_error)
+    }
+
+    /// Returns false while the circuit breaker of the backend is open.
+    pub fn is_reachable(&self) -> bool {
+        !self.resilience.is_open()
+    }
+
+    #[instrument(level = "debug", name = "get_tile_s3", skip(self))]
+    pub async fn get_tile(&self, tile_path: &str) -> Result<Vec<u8>, Error> {
+        self.resilience.call(|| self.fetch_tile(tile_path)).await
+    }
+
+    async fn fetch_tile(&self, tile_path: &str) -> Result<Vec<u8>, Error> {
         let key = if self.key_prefix.is_empty() {
-            file_path
+            tile_path.to_string()
         } else {
-            format!("{}/{}", self.key_prefix, file_path)
+            format!("{}/{}", self.key_prefix, tile_path)
         };
 
         debug!("Fetching tile from S3: s3://{}/{}", self.bucket.name, key);
 
-        let response = self.bucket.get_object(&key).await?;
-        let bytes = response.bytes().to_vec();
+        let response = self
+            .traced_bucket()?
+            .get_object(&key)
+            .await
+            .map_err(convert_s3_error)?;
+        match response.status_code() {
+            200..=299 => {}
+            404 => {
+                return Err(Error::new(
+                    ErrorKind::NotFound,
+                    format!("Tile does not exist: s3://{}/{}", self.bucket.name, key),
+                ));
+            }
+            status => {
+                return Err(status_error(
+                    status,
+                    &format!("s3://{}/{}", self.bucket.name, key),
+                ));
+            }
+        }
+        Ok(response.bytes().to_vec())
+    }
+
+    /// Fetches the bytes `start..=end` of a tile and returns them with the tile length.
+    #[instrument(level = "debug", name = "get_tile_range_s3", skip(self))]
+    pub async fn get_range(
+        &self,
+        tile_path: &str,
+        start: u64,
+        end: u64,
+    ) -> Result<(Vec<u8>, u64), Error> {
+        self.resilience
+            .call(|| self.fetch_range(tile_path, start, end))
+            .await
+    }
 
-        // Handle gzip decompression if needed
-        if self.gzip {
-            let mut decoder = GzDecoder::new(&bytes[..]);
-            let mut decompressed = Vec::new();
-            decoder.read_to_end(&mut decompressed)?;
-            Ok(decompressed)
+    async fn fetch_range(
+        &self,
+        tile_path: &str,
+        start: u64,
+        end: u64,
+    ) -> Result<(Vec<u8>, u64), Error> {
+        let key = if self.key_prefix.is_empty() {
+            tile_path.to_string()
         } else {
-            Ok(bytes)
+            format!("{}/{}", self.key_prefix, tile_path)
+        };
+
+        debug!(
+            "Fetching tile range from S3: s3://{}/{}",
+            self.bucket.name, key
+        );
+
+        let response = self
+            .traced_bucket()?
+            .get_object_range(&key, start, Some(end.max(start + 1)))
+            .await
+            .map_err(convert_s3_error)?;
+        match response.status_code() {
+            206 => {
+                let len = response
+                    .headers()
+                    .iter()
+                    .find(|(name, _)| name.eq_ignore_ascii_case("content-range"))
+                    .and_then(|(_, value)| parse_content_range(value))
+                    .ok_or_else(|| {
+                        Error::new(
+                            ErrorKind::InvalidData,
+                            format!(
+                                "Missing Content-Range fetching s3://{}/{}",
+                                self.bucket.name, key
+                            ),
+                        )
+                    })?;
+                let bytes = response.bytes();
+                let end = ((end - start + 1) as usize).min(bytes.len());
+                Ok((bytes[..end].to_vec(), len))
+            }
+            200..=299 => {
+                let bytes = response.bytes();
+                let len = bytes.len() as u64;
+                let end = (end + 1).min(len) as usize;
+                Ok((
+                    bytes.get(start as usize..end).unwrap_or_default().to_vec(),
+                    len,
+                ))
+            }
+            404 => Err(Error::new(
+                ErrorKind::NotFound,
+                format!("Tile does not exist: s3://{}/{}", self.bucket.name, key),
+            )),
+            status => Err(status_error(
+                status,
+                &format!("s3://{}/{}", self.bucket.name, key),
+            )),
         }
     }
 }
+
+/// Keeps transport failures apart from other errors, so they are retried.
+fn convert_s3_error(err: S3Error) -> Error {
+    match err {
+        S3Error::Io(e) => e,
+        S3Error::Hyper(e) if e.is_timeout() => Error::new(ErrorKind::TimedOut, e),
+        S3Error::Hyper(e) if e.is_connect() => Error::new(ErrorKind::ConnectionRefused, e),
+        S3Error::Hyper(e) => Error::new(ErrorKind::ConnectionReset, e),
+        e => Error::other(e),
+    }
+}
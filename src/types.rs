@@ -4,6 +4,32 @@ use serde::{Deserialize, Serialize};
 pub struct LatLng {
     pub lat: f64,
     pub lng: f64,
+    pub dataset: Option<String>,
+    #[serde(default)]
+    pub height: HeightReference,
+}
+
+#[derive(Deserialize)]
+pub struct ElevationQuery {
+    pub dataset: Option<String>,
+    #[serde(default)]
+    pub height: HeightReference,
+}
+
+/// Vertical reference of the returned heights. Tiles store orthometric heights
+/// (above the geoid), ellipsoidal heights add the geoid undulation.
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum HeightReference {
+    #[default]
+    Orthometric,
+    Ellipsoidal,
+}
+
+#[derive(Deserialize)]
+pub struct GeoidQuery {
+    pub lat: f64,
+    pub lng: f64,
 }
 
 #[derive(Deserialize)]
@@ -21,8 +47,91 @@ impl IntoIterator for LatLngs {
     }
 }
 
+/// Area given as `[west, south, east, north]`, like a GeoJSON bbox.
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(from = "[f64; 4]")]
+pub struct BoundingBox {
+    pub west: f64,
+    pub south: f64,
+    pub east: f64,
+    pub north: f64,
+}
+
+impl From<[f64; 4]> for BoundingBox {
+    fn from([west, south, east, north]: [f64; 4]) -> Self {
+        Self {
+            west,
+            south,
+            east,
+            north,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct WarmupRequest {
+    pub dataset: Option<String>,
+    #[serde(default)]
+    pub bboxes: Vec<BoundingBox>,
+    #[serde(default)]
+    pub tiles: Vec<String>,
+    pub geojson: Option<serde_json::Value>,
+}
+
+#[derive(Serialize)]
+pub struct WarmupResponse {
+    pub dataset: String,
+    pub tiles: usize,
+}
+
 #[derive(Serialize)]
 #[serde(transparent)]
 pub struct ElevationResponse {
-    pub elevations: Vec<i16>,
+    pub elevations: Vec<Option<i16>>,
+}
+
+#[derive(Serialize)]
+pub struct DatasetInfo {
+    pub name: String,
+    pub default: bool,
+    pub missing_tile_policy: String,
+    pub layers: Vec<LayerInfo>,
+}
+
+#[derive(Serialize)]
+pub struct LayerInfo {
+    pub name: String,
+    pub backend: &'static str,
+    pub backend_reachable: bool,
+    pub cache_size: String,
+    pub cached_tiles: u64,
+    pub cache_bytes: u64,
+    pub gzip: bool,
+    pub path_template: String,
+    pub disk_cache: bool,
+    pub mmap: bool,
+    pub range_reads: bool,
+    pub missing_tiles: u64,
+    pub missing_tile_hits: u64,
+    pub format: String,
+    pub zoom: Option<u8>,
+}
+
+#[derive(Serialize)]
+pub struct ProbeResult {
+    pub dataset: String,
+    pub lat: f64,
+    pub lng: f64,
+    pub ok: bool,
+    pub error: Option<String>,
+    pub duration_ms: f64,
+}
+
+#[derive(Serialize)]
+pub struct StatusResponse {
+    pub status: &'static str,
+    pub version: &'static str,
+    pub warmup: &'static str,
+    pub probes: Vec<ProbeResult>,
+    pub datasets: Vec<DatasetInfo>,
 }
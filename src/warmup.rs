@@ -0,0 +1,423 @@
+use futures::stream::StreamExt;
+use serde_json::Value;
+use std::{
+    io::{Error, ErrorKind},
+    sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+    },
+    time::Instant,
+};
+use tracing::{error, info, warn};
+
+use crate::{
+    config::Config,
+    datasets::Datasets,
+    tileset::{TileSetWithCache, WarmupTile},
+    types::BoundingBox,
+};
+
+/// Upper bound for the tiles of a single warmup, to catch regions given by mistake.
+const MAX_WARMUP_TILES: usize = 100_000;
+
+/// Tiles of one dataset to load into its caches.
+struct WarmupPlan {
+    dataset: String,
+    tileset: Arc<TileSetWithCache>,
+    tiles: Vec<WarmupTile>,
+}
+
+/// Loads the tiles of regions of interest into the tile caches ahead of requests.
+pub struct Warmup {
+    datasets: Arc<Datasets>,
+    concurrency: usize,
+    ready: AtomicBool,
+}
+
+impl Warmup {
+    /// A concurrency of 0 is treated as 1, `buffer_unordered(0)` would never make progress.
+    pub fn new(datasets: Arc<Datasets>, concurrency: usize) -> Self {
+        Self {
+            datasets,
+            concurrency: concurrency.max(1),
+            ready: AtomicBool::new(false),
+        }
+    }
+
+    /// Returns true once the startup warmup has finished.
+    pub fn is_ready(&self) -> bool {
+        self.ready.load(Ordering::Acquire)
+    }
+
+    /// Starts warming up the regions configured at startup. The service reports
+    /// ready once all of them are loaded. Invalid regions fail immediately.
+    pub fn start(self: &Arc<Self>, config: &Config) -> Result<(), Error> {
+        let regions = regions_from_config(config)?;
+        if regions.is_empty() {
+            self.ready.store(true, Ordering::Release);
+            return Ok(());
+        }
+
+        let plans = config
+            .warmup_datasets
+            .iter()
+            .map(|dataset| self.plan(Some(dataset), &regions))
+            .collect::<Result<Vec<_>, _>>()?;
+        let warmup = self.clone();
+        tokio::spawn(async move {
+            for plan in plans {
+                warmup.run(plan).await;
+            }
+            warmup.ready.store(true, Ordering::Release);
+            info!("Startup warmup finished");
+        });
+        Ok(())
+    }
+
+    /// Starts warming up `regions` of a dataset in the background and returns
+    /// the number of tiles to load.
+    pub fn trigger(
+        self: &Arc<Self>,
+        dataset: Option<&str>,
+        regions: &[BoundingBox],
+    ) -> Result<(String, usize), Error> {
+        let plan = self.plan(dataset, regions)?;
+        let summary = (plan.dataset.clone(), plan.tiles.len());
+        let warmup = self.clone();
+        tokio::spawn(async move { warmup.run(plan).await });
+        Ok(summary)
+    }
+
+    fn plan(&self, dataset: Option<&str>, regions: &[BoundingBox]) -> Result<WarmupPlan, Error> {
+        for region in regions {
+            validate_region(region)?;
+        }
+        let tileset = self.datasets.get(dataset)?;
+        let tiles = tileset.tiles_in_bboxes(regions, MAX_WARMUP_TILES)?;
+        Ok(WarmupPlan {
+            dataset: dataset
+                .map(String::from)
+                .unwrap_or_else(|| self.datasets.default_name().to_string()),
+            tileset,
+            tiles,
+        })
+    }
+
+    async fn run(&self, plan: WarmupPlan) {
+        let total = plan.tiles.len();
+        let started = Instant::now();
+        info!(dataset = %plan.dataset, tiles = total, "Starting warmup");
+
+        let tileset = plan.tileset;
+        let mut results = futures::stream::iter(plan.tiles)
+            .map(|tile| {
+                let tileset = tileset.clone();
+                async move { tileset.warm_up_tile(&tile).await }
+            })
+            .buffer_unordered(self.concurrency);
+
+        let (mut done, mut loaded, mut missing, mut failed) = (0, 0, 0, 0);
+        while let Some(result) = results.next().await {
+            match result {
+                Ok(()) => loaded += 1,
+                Err(e) if e.kind() == ErrorKind::NotFound => missing += 1,
+                Err(e) => {
+                    warn!(dataset = %plan.dataset, error = %e, "Failed to warm up tile");
+                    failed += 1;
+                }
+            }
+            done += 1;
+            // Report every 10%
+            if done * 10 / total != (done - 1) * 10 / total {
+                info!(
+                    dataset = %plan.dataset,
+                    progress = format!("{}/{}", done, total),
+                    "Warmup progress"
+                );
+            }
+        }
+
+        if failed > 0 {
+            error!(dataset = %plan.dataset, failed, "Warmup finished with failures");
+        }
+        info!(
+            dataset = %plan.dataset,
+            loaded,
+            missing,
+            failed,
+            elapsed_ms = started.elapsed().as_millis() as u64,
+            "Warmup finished"
+        );
+    }
+}
+
+/// Collects the regions from `WARMUP_BBOXES`, `WARMUP_GEOJSON` and `WARMUP_TILES`.
+fn regions_from_config(config: &Config) -> Result<Vec<BoundingBox>, Error> {
+    let mut regions = Vec::new();
+    if let Some(bboxes) = &config.warmup_bboxes {
+        regions.extend(parse_bboxes(bboxes)?);
+    }
+    if let Some(path) = &config.warmup_geojson {
+        let buffer = std::fs::read(path)?;
+        let geojson = serde_json::from_slice(&buffer).map_err(|e| {
+            Error::new(
+                ErrorKind::InvalidData,
+                format!("Invalid GeoJSON in '{}': {}", path, e),
+            )
+        })?;
+        regions.extend(geojson_regions(&geojson)?);
+    }
+    if let Some(tiles) = &config.warmup_tiles {
+        for name in tiles.split(|c: char| c == ',' || c.is_whitespace()) {
+            if !name.is_empty() {
+                regions.push(tile_region(name)?);
+            }
+        }
+    }
+    Ok(regions)
+}
+
+/// Collects the regions of a warmup request.
+pub fn regions_from_request(
+    bboxes: &[BoundingBox],
+    tiles: &[String],
+    geojson: Option<&Value>,
+) -> Result<Vec<BoundingBox>, Error> {
+    let mut regions = bboxes.to_vec();
+    if let Some(geojson) = geojson {
+        regions.extend(geojson_regions(geojson)?);
+    }
+    for name in tiles {
+        regions.push(tile_region(name)?);
+    }
+    Ok(regions)
+}
+
+/// Parses `west,south,east,north` boxes separated by `;`.
+fn parse_bboxes(value: &str) -> Result<Vec<BoundingBox>, Error> {
+    value
+        .split(';')
+        .map(str::trim)
+        .filter(|bbox| !bbox.is_empty())
+        .map(|bbox| {
+            let values = bbox
+                .split(',')
+                .map(|value| value.trim().parse::<f64>())
+                .collect::<Result<Vec<_>, _>>()
+                .ok()
+                .and_then(|values| <[f64; 4]>::try_from(values).ok())
+                .ok_or_else(|| {
+                    Error::new(
+                        ErrorKind::InvalidInput,
+                        format!(
+                            "Invalid bounding box '{}', expected west,south,east,north",
+                            bbox
+                        ),
+                    )
+                })?;
+            Ok(BoundingBox::from(values))
+        })
+        .collect()
+}
+
+/// Returns the area of an HGT tile given by name, e.g. `N45E009`.
+fn tile_region(name: &str) -> Result<BoundingBox, Error> {
+    let invalid = || {
+        Error::new(
+            ErrorKind::InvalidInput,
+            format!("Invalid tile name '{}', expected e.g. N45E009", name),
+        )
+    };
+    let name = name.to_uppercase();
+    let lng_start = name.find(['E', 'W']).ok_or_else(invalid)?;
+    let (lat, lng) = name.split_at(lng_start);
+    let parse = |part: &str, positive: char| -> Result<f64, Error> {
+        let mut chars = part.chars();
+        let sign = match chars.next() {
+            Some(c) if c == positive => 1.0,
+            Some('S') | Some('W') => -1.0,
+            _ => return Err(invalid()),
+        };
+        let value = chars.as_str().parse::<u8>().map_err(|_| invalid())?;
+        Ok(sign * value as f64)
+    };
+    let south = parse(lat, 'N')?;
+    let west = parse(lng, 'E')?;
+    Ok(BoundingBox {
+        west,
+        south,
+        east: west + 1.0,
+        north: south + 1.0,
+    })
+}
+
+/// Returns the bounding box of each geometry of a GeoJSON object.
+fn geojson_regions(geojson: &Value) -> Result<Vec<BoundingBox>, Error> {
+    let invalid = |message: &str| Error::new(ErrorKind::InvalidInput, message.to_string());
+    let items = |key: &str| {
+        geojson
+            .get(key)
+            .and_then(Value::as_array)
+            .ok_or_else(|| invalid(&format!("GeoJSON object is missing '{}'", key)))
+    };
+
+    match geojson.get("type").and_then(Value::as_str) {
+        Some("FeatureCollection") => items("features")?
+            .iter()
+            .map(geojson_regions)
+            .collect::<Result<Vec<_>, _>>()
+            .map(|regions| regions.concat()),
+        Some("GeometryCollection") => items("geometries")?
+            .iter()
+            .map(geojson_regions)
+            .collect::<Result<Vec<_>, _>>()
+            .map(|regions| regions.concat()),
+        Some("Feature") => match geojson.get("geometry") {
+            Some(Value::Null) | None => Ok(Vec::new()),
+            Some(geometry) => geojson_regions(geometry),
+        },
+        Some(_) => {
+            let mut bbox: Option<BoundingBox> = None;
+            extend_bbox(
+                geojson
+                    .get("coordinates")
+                    .ok_or_else(|| invalid("GeoJSON geometry is missing 'coordinates'"))?,
+                &mut bbox,
+            )?;
+            Ok(bbox.into_iter().collect())
+        }
+        None => Err(invalid("GeoJSON object is missing 'type'")),
+    }
+}
+
+/// Grows `bbox` by the positions in nested GeoJSON coordinate arrays.
+fn extend_bbox(coordinates: &Value, bbox: &mut Option<BoundingBox>) -> Result<(), Error> {
+    let invalid = || Error::new(ErrorKind::InvalidInput, "Invalid GeoJSON coordinates");
+    let values = coordinates.as_array().ok_or_else(invalid)?;
+    if values.first().is_some_and(Value::is_number) {
+        let (lng, lat) = match (values.first(), values.get(1)) {
+            (Some(lng), Some(lat)) => (
+                lng.as_f64().ok_or_else(invalid)?,
+                lat.as_f64().ok_or_else(invalid)?,
+            ),
+            _ => return Err(invalid()),
+        };
+        *bbox = Some(match *bbox {
+            Some(bbox) => BoundingBox {
+                west: bbox.west.min(lng),
+                south: bbox.south.min(lat),
+                east: bbox.east.max(lng),
+                north: bbox.north.max(lat),
+            },
+            None => BoundingBox::from([lng, lat, lng, lat]),
+        });
+        return Ok(());
+    }
+    for value in values {
+        extend_bbox(value, bbox)?;
+    }
+    Ok(())
+}
+
+fn validate_region(region: &BoundingBox) -> Result<(), Error> {
+    TileSetWithCache::validate_coordinates(region.south, region.west)?;
+    TileSetWithCache::validate_coordinates(region.north, region.east)?;
+    if region.west > region.east || region.south > region.north {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!(
+                "Invalid bounding box {:?}, west must not exceed east and south must not exceed north",
+                region
+            ),
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempDir;
+    use serde_json::json;
+
+    #[test]
+    fn test_parse_bboxes() {
+        let bboxes = parse_bboxes("9,45,10.5,46; -1,50,1,51").unwrap();
+        assert_eq!(bboxes.len(), 2);
+        assert_eq!(bboxes[0], BoundingBox::from([9.0, 45.0, 10.5, 46.0]));
+        assert!(parse_bboxes("9,45,10.5").is_err());
+    }
+
+    #[test]
+    fn test_tile_region() {
+        assert_eq!(
+            tile_region("N45E009").unwrap(),
+            BoundingBox::from([9.0, 45.0, 10.0, 46.0])
+        );
+        assert_eq!(
+            tile_region("s12w077").unwrap(),
+            BoundingBox::from([-77.0, -12.0, -76.0, -11.0])
+        );
+        assert!(tile_region("X45E009").is_err());
+        assert!(tile_region("N45").is_err());
+    }
+
+    #[test]
+    fn test_geojson_regions() {
+        let geojson = json!({
+            "type": "FeatureCollection",
+            "features": [
+                {
+                    "type": "Feature",
+                    "properties": {},
+                    "geometry": {
+                        "type": "Polygon",
+                        "coordinates": [[[9.1, 45.2], [10.3, 45.2], [10.3, 46.4], [9.1, 45.2]]]
+                    }
+                },
+                { "type": "Feature", "properties": {}, "geometry": null },
+                {
+                    "type": "Feature",
+                    "properties": {},
+                    "geometry": { "type": "Point", "coordinates": [-3.5, 51.0] }
+                }
+            ]
+        });
+        let regions = geojson_regions(&geojson).unwrap();
+        assert_eq!(
+            regions,
+            vec![
+                BoundingBox::from([9.1, 45.2, 10.3, 46.4]),
+                BoundingBox::from([-3.5, 51.0, -3.5, 51.0]),
+            ]
+        );
+        assert!(geojson_regions(&json!({ "type": "Polygon" })).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_zero_concurrency_still_runs() {
+        let root = TempDir::new("warmup-zero");
+        let config = Config::from_vars(&[
+            ("TILE_SET_PATH", &root.to_string_lossy()),
+            ("WARMUP_TILES", "N45E009"),
+        ])
+        .unwrap();
+        let datasets = Arc::new(Datasets::new(&config).unwrap());
+        let warmup = Arc::new(Warmup::new(datasets, 0));
+        warmup.start(&config).unwrap();
+        let finished = tokio::time::timeout(std::time::Duration::from_secs(5), async {
+            while !warmup.is_ready() {
+                tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+            }
+        })
+        .await;
+        assert!(finished.is_ok());
+    }
+
+    #[test]
+    fn test_validate_region() {
+        assert!(validate_region(&BoundingBox::from([9.0, 45.0, 10.0, 46.0])).is_ok());
+        assert!(validate_region(&BoundingBox::from([10.0, 45.0, 9.0, 46.0])).is_err());
+        assert!(validate_region(&BoundingBox::from([9.0, 45.0, 190.0, 46.0])).is_err());
+    }
+}